@@ -1,4 +1,5 @@
 use udp2p_discovery::kad::Kademlia;
+use udp2p_protocol::event::EventBus;
 use udp2p_protocol::protocol::{Message};
 use udp2p_transport::transport::Transport;
 use udp2p_transport::handler::MessageHandler;
@@ -8,7 +9,7 @@ use udp2p_node::peer_info::PeerInfo;
 use udp2p_node::peer_key::Key;
 use udp2p_protocol::protocol::AckMessage;
 use rand::{thread_rng, Rng};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::env::args;
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::mpsc::{channel, Receiver, Sender};
@@ -16,6 +17,7 @@ use udp2p_utils::utils::ByteRep;
 use std::thread;
 use std::time::{Duration, Instant};
 
+#[allow(clippy::type_complexity)]
 fn main() {
     // Bind a UDP Socket to a Socket Address with a random port between
     // 9292 and 19292 on the localhost address.
@@ -37,14 +39,25 @@ fn main() {
     // Initialize local peer information
     let key: Key = Key::rand();
     let id: PeerId = PeerId::from_key(&key);
-    let info: PeerInfo = PeerInfo::new(id, key, addr.clone());
+    let info: PeerInfo = PeerInfo::new(id, key, addr);
 
     // initialize a kademlia, transport and message handler instance
     let routing_table = RoutingTable::new(info.clone());
     let interval = Duration::from_secs(20);
     let ping_pong = Instant::now();
-    let mut kad = Kademlia::new(routing_table, to_transport_tx.clone(), to_kad_rx, HashSet::new(), interval, ping_pong);
-    let mut transport = Transport::new(addr.clone(), incoming_ack_rx, to_transport_rx);
+    let mut kad = Kademlia::new(routing_table, to_transport_tx.clone(), to_kad_rx, HashMap::new(), interval, ping_pong);
+    let mut transport = Transport::new(addr, incoming_ack_rx, to_transport_rx);
+
+    // Share one event bus between kademlia and the transport and print its events
+    let events = EventBus::new();
+    kad.set_event_bus(events.clone());
+    transport.set_event_bus(events.clone());
+    let event_rx = events.subscribe();
+    thread::spawn(move || {
+        for event in event_rx {
+            println!("{:?}", event);
+        }
+    });
     let mut message_handler = MessageHandler::new(
         to_transport_tx.clone(),
         incoming_ack_tx.clone(),
@@ -66,15 +79,19 @@ fn main() {
         });
 
         loop {
-            let local = addr.clone();
+            let local = addr;
             let mut buf = [0u8; 65536];
             message_handler.recv_msg(&thread_sock, &mut buf, local);
         }
     });
 
-    if let Some(to_dial) = args().nth(1) {
-        let bootstrap: SocketAddr = to_dial.parse().expect("Unable to parse address");
-        kad.bootstrap(&bootstrap);
+    // Any arguments are the addresses of seed nodes to bootstrap from
+    let seeds: Vec<SocketAddr> = args()
+        .skip(1)
+        .map(|seed| seed.parse().expect("Unable to parse address"))
+        .collect();
+    if !seeds.is_empty() {
+        kad.bootstrap(&seeds);
     } else {
         kad.add_peer(info.as_bytes().unwrap())
     }
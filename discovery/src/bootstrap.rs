@@ -0,0 +1,145 @@
+use crate::{BOOTSTRAP_BACKOFF, BOOTSTRAP_RETRIES};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The progress of joining the network through the seed nodes.
+/// A bootstrap is complete as soon as any one seed answers, and
+/// only fails once every seed has run out of retries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapStatus {
+    Idle,
+    InProgress,
+    Complete,
+    Failed,
+}
+
+/// A seed node that the local node is bootstrapping from. Contains the
+/// address of the seed, the number of times it has been queried, the
+/// instant it should next be queried if its last query went unanswered,
+/// and whether its final retry has gone unanswered.
+#[derive(Clone, Debug)]
+pub struct Seed {
+    pub address: SocketAddr,
+    pub attempts: usize,
+    pub retry_at: Option<Instant>,
+    pub exhausted: bool,
+}
+
+/// The state of a bootstrap. Maintains the seeds being queried and the
+/// overall status. Seeds that don't answer are retried with an exponential
+/// backoff, starting at BOOTSTRAP_BACKOFF and doubling after each attempt,
+/// until they have been retried BOOTSTRAP_RETRIES times.
+#[derive(Clone, Debug)]
+pub struct Bootstrap {
+    pub seeds: Vec<Seed>,
+    pub status: BootstrapStatus,
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Bootstrap {
+            seeds: vec![],
+            status: BootstrapStatus::Idle,
+        }
+    }
+}
+
+impl Bootstrap {
+    /// Creates a new bootstrap from a list of seed addresses, each of which
+    /// is counted as having been queried once.
+    ///
+    /// # Arguments
+    ///
+    /// * seeds - the addresses of the seed nodes
+    pub fn new(seeds: &[SocketAddr]) -> Bootstrap {
+        let mut addresses: Vec<SocketAddr> = vec![];
+        seeds.iter().for_each(|seed| {
+            if !addresses.contains(seed) {
+                addresses.push(*seed);
+            }
+        });
+
+        let status = if addresses.is_empty() {
+            BootstrapStatus::Failed
+        } else {
+            BootstrapStatus::InProgress
+        };
+
+        Bootstrap {
+            seeds: addresses
+                .into_iter()
+                .map(|address| Seed {
+                    address,
+                    attempts: 1,
+                    retry_at: None,
+                    exhausted: false,
+                })
+                .collect(),
+            status,
+        }
+    }
+
+    /// Marks the bootstrap as complete. Returns true if this is the first seed
+    /// to answer. A seed that answers its final retry after every other seed
+    /// has given up still completes the bootstrap.
+    pub fn succeeded(&mut self) -> bool {
+        let first = self.status != BootstrapStatus::Complete;
+        self.status = BootstrapStatus::Complete;
+        self.seeds.iter_mut().for_each(|seed| seed.retry_at = None);
+        first
+    }
+
+    /// Records that a query to a seed went unanswered, scheduling a retry if
+    /// the seed has retries left. If every seed has run out of retries the
+    /// bootstrap is marked as failed.
+    ///
+    /// # Arguments
+    ///
+    /// * address - the address of the seed that didn't answer
+    /// * now - the instant the query timed out
+    pub fn failed(&mut self, address: &SocketAddr, now: Instant) {
+        if self.status != BootstrapStatus::InProgress {
+            return;
+        }
+
+        if let Some(seed) = self.seeds.iter_mut().find(|seed| seed.address == *address) {
+            if seed.attempts <= BOOTSTRAP_RETRIES {
+                let backoff =
+                    Duration::from_nanos(BOOTSTRAP_BACKOFF) * 2u32.pow(seed.attempts as u32 - 1);
+                seed.retry_at = Some(now + backoff);
+            } else {
+                seed.exhausted = true;
+            }
+        }
+
+        if self.is_exhausted() {
+            self.status = BootstrapStatus::Failed;
+        }
+    }
+
+    /// Returns the seeds whose retry is due and counts the new attempt against them.
+    ///
+    /// # Arguments
+    ///
+    /// * now - the current instant
+    pub fn due(&mut self, now: Instant) -> Vec<SocketAddr> {
+        if self.status != BootstrapStatus::InProgress {
+            return vec![];
+        }
+
+        self.seeds
+            .iter_mut()
+            .filter(|seed| seed.retry_at.is_some_and(|at| at <= now))
+            .map(|seed| {
+                seed.retry_at = None;
+                seed.attempts += 1;
+                seed.address
+            })
+            .collect()
+    }
+
+    /// Checks if every seed has failed to answer its final retry and returns true or false
+    fn is_exhausted(&self) -> bool {
+        self.seeds.iter().all(|seed| seed.exhausted)
+    }
+}
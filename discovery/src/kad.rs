@@ -1,37 +1,83 @@
+use crate::bootstrap::{Bootstrap, BootstrapStatus};
+use crate::lookup::{Lookup, LookupKind};
+use crate::pending::{PendingRequest, Purpose};
 use crate::protocol::{Req, Resp, RPC};
 use crate::routing::RoutingTable;
-use crate::{DEFAULT_N_PEERS};
+use crate::score::{PeerScores, ScoreConfig};
+use crate::{
+    DEFAULT_N_PEERS, DEFAULT_PROTOCOL_ID, MAX_ACTIVE_RPCS, PROVIDER_REPUBLISH_INTERVAL, RECORD_REPUBLISH_INTERVAL,
+    VALUE_QUORUM,
+};
+use udp2p_node::peer_id::PeerId;
 use udp2p_node::peer_info::PeerInfo;
 use udp2p_node::peer_key::Key;
+use udp2p_protocol::event::{Event, EventBus};
 use udp2p_protocol::protocol::{
-    Header, KadMessage, Message, MessageKey, Nodes, Peer, RequestBytes, ResponseBytes, Value,
+    Header, KadMessage, Message, MessageKey, Nodes, Peer, RequestBytes, ResponseBytes, StoreKey,
+    Value,
 };
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::{Duration, Instant};
+use udp2p_record::memory::MemoryStore;
+use udp2p_record::record::{DhtRecord, ProviderRecord};
+use udp2p_record::store::RecordStore;
+use udp2p_record::validator::{Validator, Validators};
 use udp2p_traits::routable::Routable;
 use udp2p_utils::utils::timestamp_now;
 use udp2p_utils::utils::ByteRep;
 use udp2p_utils::utils::Distance;
+use log::info;
 
-/// The kademlia is the basic struct used for Peer Discovery in this crate
-/// Kademlia has a RoutingTable, a to_transport sender and from transport receiver
-/// that is used to communicate incoming and outgoing data from the
-/// transport layer. It also maintains a set of keys from pending messages
-/// to ensure that any responses are in relation to a message the local node
-/// sent. Lastly, an interval used to maintain the amount of time between
-/// ping-pong events, and a ping pong timer that is used to check whether
-/// or not the amount of time since the last ping pong event has exceeded
-/// the interval or not. If so then it is time to check on the health of
-/// the peers and clean up the routing table to get rid of any unresponsive peers.
+/// The kademlia is the basic struct used for Peer Discovery in this crate.
+/// It finds peers and stores records in the DHT, scores the peers it talks
+/// to, and checks on their health at each interval. Once the instance is shut
+/// down it stops handling messages.
 #[derive(Debug)]
 pub struct Kademlia {
+    /// The kbuckets of the peers the local node knows about
     pub routing_table: RoutingTable,
+    /// Sends outgoing messages to the transport layer
     pub to_transport: Sender<(SocketAddr, Message)>,
+    /// Receives incoming messages from the transport layer
     pub from_transport: Receiver<(SocketAddr, KadMessage)>,
-    pub pending: HashSet<MessageKey>,
+    /// The requests sent by the local node that are waiting on a response, by message key
+    pub pending: HashMap<MessageKey, PendingRequest>,
+    /// The number of consecutive requests each peer has failed to answer
+    failures: HashMap<SocketAddr, usize>,
+    /// The iterative lookups in progress
+    lookups: HashMap<MessageKey, Lookup>,
+    /// The state of the bootstrap from the seed nodes
+    bootstrapping: Bootstrap,
+    /// Holds records and their providers, an in memory store unless another is set
+    store: Box<dyn RecordStore>,
+    /// Checks records and picks between versions of them
+    validators: Validators,
+    /// The keys of the records the local node published, which it republishes
+    published: HashSet<Key>,
+    /// Peers discovered or evicted and lookups completed are published here
+    events: EventBus,
+    /// Receives the deliveries and delivery failures reported on the event bus
+    deliveries: Receiver<Event>,
+    /// The scores of peers, used to order lookups and to ban misbehaving peers
+    scores: PeerScores,
+    /// The id of the network, requests and responses from other networks are refused
+    protocol: String,
+    /// When the keys the local node provides were last republished
+    providers_republished: Instant,
+    /// When the records the local node published were last republished
+    records_republished: Instant,
+    /// The file the routing table is written to at each interval, if set
+    snapshot: Option<PathBuf>,
+    /// Whether the instance has been shut down
+    stopped: bool,
+    /// The time between checks on the health of peers and refreshes of stale kbuckets
     interval: Duration,
+    /// When peers were last checked on
     ping_pong: Instant,
 }
 
@@ -42,47 +88,255 @@ impl Kademlia {
     /// * routing_table - the routing table for this instance
     /// * to_transport - an mpsc sender to the transport layer
     /// * from_transport - an mpsc receiver from the transport layer
-    /// * pending - a hashset of message keys of pending outgoing messages
+    /// * pending - a hashmap of message keys of pending outgoing requests to the request they belong to
     /// * interval - a fixed duration used to check if it is time to send ping pong events
     /// * ping_pong - an instant that is checked against the interval to determine if its time to send ping pong events
     pub fn new(
         routing_table: RoutingTable,
         to_transport: Sender<(SocketAddr, Message)>,
         from_transport: Receiver<(SocketAddr, KadMessage)>,
-        pending: HashSet<MessageKey>,
+        pending: HashMap<MessageKey, PendingRequest>,
         interval: Duration,
         ping_pong: Instant,
     ) -> Kademlia {
+        let store = Box::new(MemoryStore::new(routing_table.local_info.id.clone()));
+        let events = EventBus::new();
+        let deliveries = events.subscribe();
         Kademlia {
             routing_table,
             to_transport,
             from_transport,
             pending,
+            failures: HashMap::new(),
+            lookups: HashMap::new(),
+            bootstrapping: Bootstrap::default(),
+            store,
+            validators: Validators::new(),
+            published: HashSet::new(),
+            events,
+            deliveries,
+            scores: PeerScores::default(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+            providers_republished: Instant::now(),
+            records_republished: Instant::now(),
+            snapshot: None,
+            stopped: false,
             interval,
             ping_pong,
         }
     }
 
-    /// A method to receive data from the transport layer and determine if
-    /// it is time to send ping-pong events.
+    /// A method to receive data from the transport layer, expire unanswered
+    /// requests, retry seeds that haven't answered, score deliveries, ban peers
+    /// and determine if it is time to send ping-pong events, refresh stale kbuckets,
+    /// decay scores, drop expired records and republish provided keys.
+    /// Does nothing once the instance has been shut down.
     pub fn recv(&mut self) {
+        if self.stopped {
+            return;
+        }
+
         let res = self.from_transport.try_recv();
-        match res {
-            Ok((_src, msg)) => {
-                self.handle_message(&msg);
-            }
-            Err(_) => {}
+        if let Ok((src, msg)) = res {
+            self.handle_message(&src, &msg);
         }
 
-        // TODO: check if its time to send pings out
+        self.expire_requests();
+        self.expire_lookups();
+        self.retry_bootstrap();
+        self.score_deliveries();
+        self.ban_peers();
+
         let now = Instant::now();
         if now.duration_since(self.ping_pong) > self.interval {
-            // Send ping messages to peers.
+            self.ping_lru_peers();
+            self.refresh_buckets();
+            self.scores.decay();
+            self.save_snapshot();
+            self.store.remove_expired();
+            self.ping_pong = now;
+        }
+
+        if now.duration_since(self.providers_republished) > Duration::from_nanos(PROVIDER_REPUBLISH_INTERVAL as u64) {
+            self.republish_providers();
+            self.providers_republished = now;
+        }
+
+        if now.duration_since(self.records_republished) > Duration::from_nanos(RECORD_REPUBLISH_INTERVAL as u64) {
+            self.republish_records();
+            self.records_republished = now;
+        }
+    }
+
+    /// Replaces the record store used to hold records stored in the DHT
+    /// 
+    /// # Arguments
+    /// 
+    /// * store - the record store to use
+    pub fn set_store(&mut self, store: Box<dyn RecordStore>) {
+        self.store = store;
+    }
+
+    /// Registers the validator used to check records in a namespace before they are
+    /// stored or returned from a lookup, and to pick the best of several versions.
+    /// 
+    /// # Arguments
+    /// 
+    /// * namespace - the namespace to validate
+    /// * validator - the validator for records in the namespace
+    pub fn add_validator(&mut self, namespace: &str, validator: Box<dyn Validator>) {
+        self.validators.register(namespace, validator);
+    }
+
+    /// Returns the record store used to hold records stored in the DHT
+    pub fn store(&self) -> &dyn RecordStore {
+        self.store.as_ref()
+    }
+
+    /// Replaces the event bus that discovery events are published on, so that
+    /// they can share a bus with the transport layer
+    /// 
+    /// # Arguments
+    /// 
+    /// * events - the event bus to publish to
+    pub fn set_event_bus(&mut self, events: EventBus) {
+        self.deliveries = events.subscribe();
+        self.events = events;
+    }
+
+    /// Sets the protocol id of the network the local node belongs to. Peers that
+    /// send a different protocol id are refused.
+    /// 
+    /// # Arguments
+    /// 
+    /// * protocol - the protocol id
+    pub fn set_protocol_id(&mut self, protocol: &str) {
+        self.protocol = protocol.to_string();
+    }
+
+    /// Returns the protocol id of the network the local node belongs to
+    pub fn protocol_id(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Checks that a message came from a peer on the local node's network, publishing
+    /// a protocol mismatch if it didn't, and returns true or false
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the address of the peer that sent the message
+    /// * protocol - the protocol id the peer sent
+    fn same_network(&self, peer: Option<SocketAddr>, protocol: &str) -> bool {
+        if protocol == self.protocol {
+            return true;
+        }
+        if let Some(peer) = peer {
+            info!("Refused {:?} from the {:?} network", peer, protocol);
+            self.events.publish(Event::ProtocolMismatch(peer, protocol.to_string()));
+        }
+        false
+    }
+
+    /// Replaces the weights and thresholds peers are scored with, forgetting
+    /// the scores so far
+    /// 
+    /// # Arguments
+    /// 
+    /// * config - the score configuration
+    pub fn set_score_config(&mut self, config: ScoreConfig) {
+        self.scores = PeerScores::new(config);
+    }
+
+    /// Returns the scores of the peers the local node has interacted with
+    pub fn scores(&self) -> &PeerScores {
+        &self.scores
+    }
+
+    /// Returns the peer scores so that other layers can record how peers behave
+    pub fn scores_mut(&mut self) -> &mut PeerScores {
+        &mut self.scores
+    }
+
+    /// Records each message that the transport layer reports was delivered to,
+    /// or failed to be delivered to, a peer in the peer's score
+    fn score_deliveries(&mut self) {
+        while let Ok(event) = self.deliveries.try_recv() {
+            match event {
+                Event::MessageDelivered(peer) => self.scores.delivered(peer),
+                Event::DeliveryFailed(peer) => self.scores.failed(peer),
+                _ => {}
+            }
+        }
+    }
+
+    /// Bans each peer whose score has fallen below the ban threshold. Banned peers
+    /// are removed from the routing table, any requests waiting on them are failed,
+    /// and they aren't added back or answered until the ban is lifted.
+    fn ban_peers(&mut self) {
+        self.scores.below_ban_threshold().into_iter().for_each(|address| {
+            info!("Banned peer {:?}", address);
+            let peer = self
+                .routing_table
+                .get_all_peers()
+                .into_iter()
+                .find(|peer| peer.address == address);
+            if let Some(peer) = peer {
+                self.disconnect(peer);
+            }
+            self.scores.ban(address);
+            self.events.publish(Event::PeerBanned(address));
+        });
+    }
+
+    /// Returns the event bus that discovery events are published on
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Sets the file that the routing table is snapshotted to at each interval
+    /// 
+    /// # Arguments
+    /// 
+    /// * path - the file to write routing table snapshots to
+    pub fn set_snapshot_path(&mut self, path: PathBuf) {
+        self.snapshot = Some(path);
+    }
+
+    /// Writes the routing table to the snapshot path, if one is set.
+    pub fn save_snapshot(&self) {
+        if let Some(path) = &self.snapshot {
+            if let Err(e) = self.routing_table.save(path) {
+                info!("Error saving routing table snapshot to {:?}: {:?}", path, e);
+            }
         }
     }
 
+    /// Reloads the peers from a routing table snapshot and pings each of them.
+    /// Peers are only added back to the routing table once they answer the ping,
+    /// so peers that went offline while the local node was down are never trusted.
+    /// Returns the number of peers pinged.
+    /// 
+    /// # Arguments
+    /// 
+    /// * path - the snapshot file written by a previous run
+    pub fn warm_start(&mut self, path: &Path) -> io::Result<usize> {
+        let local_id = self.routing_table.local_info.id.clone();
+        let peers: Vec<PeerInfo> = RoutingTable::load(path)?
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .filter(|peer| peer.id != local_id)
+            .collect();
+        let count = peers.len();
+        peers.into_iter().for_each(|peer| self.ping_node(peer));
+        Ok(count)
+    }
+
     /// Adds a peer to the routing table if they don't exist
-    /// Update's a peer if they do exist
+    /// Update's a peer if they do exist. If the peer's kbucket is
+    /// full the bucket's least recently seen peer is pinged, and the
+    /// new peer is only admitted if that ping goes unanswered. Peers
+    /// that are newly admitted are sent the records they should hold
+    /// and published as discovered.
     ///
     /// # Arguments
     ///
@@ -90,25 +344,170 @@ impl Kademlia {
     /// 
     pub fn add_peer(&mut self, peer: Peer) {
         let peer = PeerInfo::from_bytes(&peer).unwrap();
-        self.routing_table.update_peer(&peer, 0);
+        if self.scores.is_banned(&peer.address) {
+            return;
+        }
+        let new = self.routing_table.is_new(&peer);
+        if let Some(lru) = self.routing_table.update_peer(&peer) {
+            self.ping_node(lru);
+        }
+        if new && !self.routing_table.is_new(&peer) {
+            self.events.publish(Event::PeerDiscovered(peer.clone()));
+            self.replicate_to(&peer);
+        }
     }
 
-    /// Requests nodes from the bootstrap node provided at the start
-    /// and then subsequently adds and requests more nodes from each
-    /// node returned by the bootstrap node, etc. etc.
+    /// Requests nodes from each of the seed nodes provided at the start in parallel.
+    /// Seeds that don't answer are retried with an exponential backoff. Once the first
+    /// seed answers the bootstrap is complete and a lookup for the local key is started
+    /// to fill the routing table with the local node's closest peers. If every seed
+    /// runs out of retries the bootstrap is marked as failed. The progress can be
+    /// checked with bootstrap_status.
     /// 
     /// # Arguments
     /// 
-    /// * bootstrap - The socket address of the bootstrap node
+    /// * seeds - The socket addresses of the seed nodes
+    /// 
+    pub fn bootstrap(&mut self, seeds: &[SocketAddr]) {
+        self.bootstrapping = Bootstrap::new(seeds);
+        let addresses: Vec<SocketAddr> = self
+            .bootstrapping
+            .seeds
+            .iter()
+            .map(|seed| seed.address)
+            .collect();
+        addresses.iter().for_each(|seed| self.query_seed(*seed));
+        self.add_peer(self.routing_table.local_info.clone().as_bytes().unwrap());
+    }
+
+    /// Returns the status of the bootstrap from the seed nodes
+    pub fn bootstrap_status(&self) -> BootstrapStatus {
+        self.bootstrapping.status
+    }
+
+    /// Sends a find node request for the local node to a seed node
+    /// 
+    /// # Arguments
     /// 
-    pub fn bootstrap(&mut self, bootstrap: &SocketAddr) {
-        // Structure Message
+    /// * seed - the socket address of the seed node
+    fn query_seed(&mut self, seed: SocketAddr) {
         let local_info = self.routing_table.local_info.clone();
-        let (id, message) = self.prepare_find_node_message(local_info, None);
-        if let Err(e) = self.to_transport.send((bootstrap.clone(), message)) {
+        let target = local_info.get_key();
+        let request = self.prepare_find_node_message(local_info, None);
+        self.send_request(seed, target, Purpose::Bootstrap, request);
+    }
+
+    /// Queries the seed nodes whose backoff has elapsed since their last query timed out.
+    fn retry_bootstrap(&mut self) {
+        self.bootstrapping
+            .due(Instant::now())
+            .into_iter()
+            .for_each(|seed| {
+                info!("Retrying bootstrap from {:?}", seed);
+                self.query_seed(seed);
+            });
+    }
+
+    /// Sends a request to the transport layer and tracks it as pending so that
+    /// the response can be matched to it, or the request can time out.
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address to send the request to
+    /// * target - the key the request is about
+    /// * purpose - the reason the request is being sent
+    /// * request - the message key and message returned by one of the prepare functions
+    ///
+    fn send_request(
+        &mut self,
+        peer: SocketAddr,
+        target: Key,
+        purpose: Purpose,
+        request: (MessageKey, Message),
+    ) {
+        let (id, message) = request;
+        self.pending.insert(id, PendingRequest::new(peer, target, purpose));
+        if let Err(e) = self.to_transport.send((peer, message)) {
             println!("Error sending to transport: {:?}", e);
         }
-        self.add_peer(self.routing_table.local_info.clone().as_bytes().unwrap());
+    }
+
+    /// Removes pending requests that have gone unanswered for longer than REQ_TIMEOUT
+    /// and hands each of them to handle_timeout.
+    pub fn expire_requests(&mut self) {
+        let expired: Vec<MessageKey> = self
+            .pending
+            .iter()
+            .filter(|(_, req)| req.is_expired())
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired.iter().for_each(|id| {
+            if let Some(req) = self.pending.remove(id) {
+                self.handle_timeout(req);
+            }
+        });
+    }
+
+    /// Finishes the lookups that have been running for longer than LOOKUP_TIMEOUT
+    /// with the peers they have found so far, so that a lookup can't run forever.
+    pub fn expire_lookups(&mut self) {
+        let expired: Vec<MessageKey> = self
+            .lookups
+            .iter()
+            .filter(|(_, lookup)| lookup.is_expired())
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired.into_iter().for_each(|id| {
+            info!("Lookup {:?} ran past its deadline", id);
+            self.step_lookup(id);
+        });
+    }
+
+    /// Records a timed out request against the peer it was sent to, this count
+    /// of consecutive timeouts is reset whenever the peer responds to a request.
+    /// Peers that fail to answer a ping are evicted from the routing table.
+    ///
+    /// # Arguments
+    ///
+    /// * req - the request that timed out
+    ///
+    fn handle_timeout(&mut self, req: PendingRequest) {
+        info!("{:?} request to {:?} timed out", req.purpose, req.peer);
+        *self.failures.entry(req.peer).or_insert(0) += 1;
+        self.scores.failed(req.peer);
+        match req.purpose {
+            Purpose::Ping => {
+                if let Some(peer) = self.routing_table.evict(&req.target) {
+                    info!("Evicted unresponsive peer {:?}", peer.address);
+                    self.events.publish(Event::PeerEvicted(peer));
+                }
+            }
+            Purpose::Lookup(id) => {
+                if let Some(lookup) = self.lookups.get_mut(&id) {
+                    lookup.query_failed(&req.peer);
+                }
+                self.step_lookup(id);
+            }
+            Purpose::Bootstrap => {
+                self.bootstrapping.failed(&req.peer, Instant::now());
+                if self.bootstrapping.status == BootstrapStatus::Failed {
+                    info!("Bootstrap failed, none of the seed nodes responded");
+                }
+            }
+            Purpose::Store(_) => {}
+        }
+    }
+
+    /// Returns the number of consecutive requests to a peer that have timed out
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    ///
+    pub fn failures(&self, peer: &SocketAddr) -> usize {
+        self.failures.get(peer).copied().unwrap_or(0)
     }
 
     /// Structures and returns a nodes response message, i.e. a response to a find nodes request
@@ -130,14 +529,13 @@ impl Kademlia {
             request: req.as_bytes().unwrap(),
             receiver: local_info.as_bytes().unwrap(),
             payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
         };
 
-        let msg = Message {
+        Message {
             head: Header::Response,
             msg: KadMessage::Response(resp.as_bytes().unwrap()).as_bytes().unwrap(),
-        };
-
-        msg
+        }
     }
 
     /// Prepares a find node request message to be sent to a peer
@@ -167,6 +565,7 @@ impl Kademlia {
             id: MessageKey::rand().inner(),
             sender: local_info.as_bytes().unwrap(),
             payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
         };
 
         let msg = Message {
@@ -192,6 +591,7 @@ impl Kademlia {
             id: MessageKey::rand().inner(),
             sender: local_info.as_bytes().unwrap(),
             payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
         };
 
         let msg = Message {
@@ -209,6 +609,25 @@ impl Kademlia {
             id: MessageKey::rand().inner(),
             sender: local_info.as_bytes().unwrap(),
             payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
+
+        let msg = Message {
+            head: Header::Request,
+            msg: KadMessage::Request(req.as_bytes().unwrap()).as_bytes().unwrap(),
+        };
+        (MessageKey::from_inner(req.id), msg)
+    }
+
+    /// Structures the message used to tell a peer that the local node is leaving
+    pub fn prepare_leave_message(&self) -> (MessageKey, Message) {
+        let local_info = self.routing_table.local_info.clone();
+        let rpc: RPC = RPC::Leave;
+        let req: Req = Req {
+            id: MessageKey::rand().inner(),
+            sender: local_info.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
         };
 
         let msg = Message {
@@ -231,199 +650,803 @@ impl Kademlia {
             request: req.as_bytes().unwrap(),
             receiver: peer.as_bytes().unwrap(),
             payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
         };
 
-        let msg = Message {
+        Message {
             head: Header::Response,
             msg: KadMessage::Response(resp.as_bytes().unwrap()).as_bytes().unwrap(),
-        };
-
-        msg
+        }
     }
 
-    pub fn prepare_store_message(&self, peer: &PeerInfo, value: Value) {}
-    pub fn prepare_saved_response(&self, peer: &PeerInfo, req: Req) {}
-    pub fn prepare_find_value_message(&self, value: Value) {}
-    pub fn prepare_value_response(&self, peer: &PeerInfo, value: Value, req: Req) {}
-
-    /// The base request handler. This function does alot of the "heavy lifting"
-    /// for the kademlia structure by routing different RPCs to the correct function
+    /// Prepares a store request message asking a peer to hold a record
     /// 
     /// # Arguments
     /// 
-    /// * req - a byte representation of an incoming Req struct
+    /// * record - the record to store
     /// 
-    fn handle_request(&mut self, req: &RequestBytes) {
-        let req_msg = Req::from_bytes(&req);
-        if let Some(request) = req_msg {
-            let (id, sender, rpc) = request.to_components();
-            self.add_peer(sender.clone().unwrap().as_bytes().unwrap());
-            match rpc.unwrap() {
-                RPC::FindNode(node) => {
-                    let peer = PeerInfo::from_bytes(&node);
-                    if let Some(peer) = peer {
-                        self.lookup_node(peer, request.clone());
-                    }
-                }
-                RPC::NewPeer(peer) => {
-                    self.add_peer(peer);
-                }
-                RPC::FindValue(value) => {
-                    // TODO:
-                    //
-                    // Check if you are a provider
-                    // if so respond with the value
-                    // if not, check if you know a provider
-                    // if so, request the value from the provider
-                    // if not then find the closest peers to the key
-                    // and respond with a nodes message.
-                    // Change response handler to be able to handle the
-                    // case of a nodes response to a FindValue request.
-                }
-                RPC::Store(key, value) => {
-                    // TODO:
-                    //
-                    // Add to record store, respond with
-                    // Saved response with the key for this record
-                    // so that the receiving node can add you as a
-                    // provider of the value.
-                }
-                RPC::Ping => {
-                    // Send pong response
-                    let resp_msg = self.prepare_pong_response(&sender.clone().unwrap(), request);
-                    if let Err(e) = self
-                        .to_transport
-                        .send((sender.unwrap().address.clone(), resp_msg.clone()))
-                    {
-                        println!("Error sending to transport: {:?}", e);
-                    }
-                }
-                _ => {
-                    self.handle_response(req);
-                }
-            }
-        }
+    pub fn prepare_store_message(&self, record: &DhtRecord) -> (MessageKey, Message) {
+        let local_info = self.routing_table.local_info.clone();
+        let rpc: RPC = RPC::Store(record.key.get_key(), record.as_bytes().unwrap());
+        let req: Req = Req {
+            id: MessageKey::rand().inner(),
+            sender: local_info.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
 
+        let msg = Message {
+            head: Header::Request,
+            msg: KadMessage::Request(req.as_bytes().unwrap()).as_bytes().unwrap(),
+        };
+        (MessageKey::from_inner(req.id), msg)
     }
 
-    /// The mirror image of the handle request function, this function does the "heavy lifting"
-    /// for incoming response RPCs, by routing them to the correct function
+    /// Structures the message used to respond to a store request once the record is stored
     /// 
-    /// # Arguments 
+    /// # Arguments
     /// 
-    /// * resp - a byte representation of a Resp struct
+    /// * peer - the peer that sent the store request
+    /// * key - the key the record was stored under
+    /// * req - the original store request
     /// 
-    fn handle_response(&mut self, resp: &ResponseBytes) {
-        let resp_msg = Resp::from_bytes(&resp);
-        if let Some(rm) = resp_msg {
-            let (req, receiver, rpc) = rm.to_components();
-            if let Some(request) = req {
-                let (id, sender, req_rpc) = request.to_components();
-                let mut complete = false;
-                match rpc.unwrap() {
-                    RPC::Nodes(nodes) => {
-                        // TOOD:
-                        //
-                        // match req.
-                        // IF req is a FindNode
-                        // then proceed with the below functionality
-                        nodes.iter().for_each(|peer| {
-                            let peer_info = PeerInfo::from_bytes(&peer);
-                            if let Some(info) = peer_info {
-                                let new = self.routing_table.is_new(&info);
-                                self.add_peer(peer.clone());
-                                if new {
-                                    self.bootstrap(&info.get_address());
-                                }
-                            }
-                        })
-                        // TODO:
-                        //
-                        // IF the req is a FindValue then
-                        // attempt to find the value from the new
-                        // nodes received. This means that the
-                        // peer you requested the value from
-                        // didn't have it and didn't know any
-                        // providers of it, so they sent you
-                        // the closest nodes they know of to
-                        // the value.
-                        // If
-                    }
-                    RPC::Value(value) => {}
-                    RPC::Saved(key) => {}
-                    RPC::Pong(peer) => {
-                        // TODO:
-                        // 
-                        // Check pending requests to ensure
-                        // the Ping request isn't expired
-                        // If it hadn't expired then
-                        // The peer is still alive
-                        // keep them in the routing table and update
-                        // the routing table to move them to the back
-                        // as the LRU
-                        self.add_peer(peer)
-                    }
-                    _ => {
-                        self.handle_request(resp);
-                    }
-                }
-
-            }
+    pub fn prepare_saved_response(&self, peer: &PeerInfo, key: StoreKey, req: Req) -> Message {
+        let rpc = RPC::Saved(key);
+        let resp = Resp {
+            request: req.as_bytes().unwrap(),
+            receiver: peer.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
 
+        Message {
+            head: Header::Response,
+            msg: KadMessage::Response(resp.as_bytes().unwrap()).as_bytes().unwrap(),
         }
     }
 
-    /// Decodes and determines which type of message the
-    /// incoming message is. If it's a Request variant, then it calls handle_request()
-    /// if it's a Response variant then it calls handle_response.
+    /// Prepares a find value request message asking a peer for the record stored under a key
     /// 
     /// # Arguments
     /// 
-    /// * message - a KadMessage enum that contains a response or request and the relevant RPC.
-    pub fn handle_message(&mut self, message: &KadMessage) {
-        match message {
-            KadMessage::Request(req) => {
-                self.handle_request(req);
-            }
-            KadMessage::Response(resp) => {
-                self.handle_response(&resp);
-            }
-            KadMessage::Kill => {}
-        }
+    /// * key - the key of the record
+    /// 
+    pub fn prepare_find_value_message(&self, key: Key) -> (MessageKey, Message) {
+        let local_info = self.routing_table.local_info.clone();
+        let rpc: RPC = RPC::FindValue(key.get_key());
+        let req: Req = Req {
+            id: MessageKey::rand().inner(),
+            sender: local_info.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
+
+        let msg = Message {
+            head: Header::Request,
+            msg: KadMessage::Request(req.as_bytes().unwrap()).as_bytes().unwrap(),
+        };
+        (MessageKey::from_inner(req.id), msg)
     }
 
-    pub fn ping_node(&mut self, node: PeerInfo) {}
-    pub fn pong_response(&mut self, node: PeerInfo, req: Req) {}
+    /// Structures the message used to respond to a find value request with the record requested
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer that sent the find value request
+    /// * record - the record stored under the requested key
+    /// * req - the original find value request
+    /// 
+    pub fn prepare_value_response(&self, peer: &PeerInfo, record: &DhtRecord, req: Req) -> Message {
+        let rpc = RPC::Value(record.as_bytes().unwrap());
+        let resp = Resp {
+            request: req.as_bytes().unwrap(),
+            receiver: peer.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
 
+        Message {
+            head: Header::Response,
+            msg: KadMessage::Response(resp.as_bytes().unwrap()).as_bytes().unwrap(),
+        }
+    }
 
-    /// The core function of the kademlia DHT. This function takes in a peer (and the request that contained said peer)
-    /// and traverses the Routing table retuning DEFAULT_N_PEERS closest peers. It then responds to the requestor
-    /// with the closest peers to the requested peer, and if the peer is a new peer subsequently sends all the closest 
-    /// peers a new peer message to inform them that we have discovered a new peer.
+    /// Prepares an add provider message announcing the local node as a provider of a key
     /// 
     /// # Arguments
     /// 
-    /// * node - the node to lookup and/or find it's closest peers
-    /// * req - the original request. 
-    pub fn lookup_node(&mut self, node: PeerInfo, req: Req) {
-        let mut closest_peers = self
-            .routing_table
-            .get_closest_peers(node.clone(), DEFAULT_N_PEERS);
-        let (_, sender, rpc) = req.to_components();
-        // TODO: check if the peer is a new peer, if so then send a new peer message
-        // if not then don't worry about it.
-        if let Some(bytes) = node.as_bytes() {
-            self.add_peer(bytes);
-        }
-        let (id, msg) = self.prepare_new_peer_message(node.clone());
+    /// * key - the key the local node provides
+    /// 
+    pub fn prepare_add_provider_message(&self, key: Key) -> (MessageKey, Message) {
+        let local_info = self.routing_table.local_info.clone();
+        let rpc: RPC = RPC::AddProvider(key.get_key(), local_info.as_bytes().unwrap());
+        let req: Req = Req {
+            id: MessageKey::rand().inner(),
+            sender: local_info.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
+
+        let msg = Message {
+            head: Header::Request,
+            msg: KadMessage::Request(req.as_bytes().unwrap()).as_bytes().unwrap(),
+        };
+        (MessageKey::from_inner(req.id), msg)
+    }
+
+    /// Prepares a get providers request message asking a peer for the providers of a key
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key to find the providers of
+    /// 
+    pub fn prepare_get_providers_message(&self, key: Key) -> (MessageKey, Message) {
+        let local_info = self.routing_table.local_info.clone();
+        let rpc: RPC = RPC::GetProviders(key.get_key());
+        let req: Req = Req {
+            id: MessageKey::rand().inner(),
+            sender: local_info.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
+
+        let msg = Message {
+            head: Header::Request,
+            msg: KadMessage::Request(req.as_bytes().unwrap()).as_bytes().unwrap(),
+        };
+        (MessageKey::from_inner(req.id), msg)
+    }
+
+    /// Structures the message used to respond to a get providers request
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer that sent the get providers request
+    /// * key - the key the providers were requested for
+    /// * providers - the known providers of the key
+    /// * nodes - the closest peers to the key
+    /// * req - the original get providers request
+    /// 
+    pub fn prepare_providers_response(
+        &self,
+        peer: &PeerInfo,
+        key: StoreKey,
+        providers: Vec<PeerInfo>,
+        nodes: Vec<PeerInfo>,
+        req: Req,
+    ) -> Message {
+        let providers: Nodes = providers.iter().map(|peer| peer.as_bytes().unwrap()).collect();
+        let nodes: Nodes = nodes.iter().map(|peer| peer.as_bytes().unwrap()).collect();
+        let rpc = RPC::Providers(key, providers, nodes);
+        let resp = Resp {
+            request: req.as_bytes().unwrap(),
+            receiver: peer.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: self.protocol.clone(),
+        };
+
+        Message {
+            head: Header::Response,
+            msg: KadMessage::Response(resp.as_bytes().unwrap()).as_bytes().unwrap(),
+        }
+    }
+
+    /// The base request handler. This function does alot of the "heavy lifting"
+    /// for the kademlia structure by routing different RPCs to the correct function
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the address the request was received from
+    /// * req - a byte representation of an incoming Req struct
+    /// 
+    fn handle_request(&mut self, src: &SocketAddr, req: &RequestBytes) {
+        let req_msg = Req::from_bytes(req);
+        if let Some(request) = req_msg {
+            let (id, sender, rpc) = request.to_components();
+            if !self.same_network(sender.as_ref().map(|peer| peer.address), &request.protocol) {
+                return;
+            }
+            if sender.as_ref().is_some_and(|peer| self.scores.is_banned(&peer.address)) {
+                return;
+            }
+            // Only the leaving peer itself can announce that it is leaving,
+            // so a LEAVE that claims to come from anywhere else is ignored.
+            if let (Some(RPC::Leave), Some(peer)) = (&rpc, &sender) {
+                if peer.address == *src {
+                    self.leave_request(peer.clone());
+                } else {
+                    info!("Ignored LEAVE for {:?} sent from {:?}", peer.address, src);
+                }
+                return;
+            }
+            self.add_peer(sender.clone().unwrap().as_bytes().unwrap());
+            match rpc.unwrap() {
+                RPC::FindNode(node) => {
+                    let peer = PeerInfo::from_bytes(&node);
+                    if let Some(peer) = peer {
+                        self.lookup_node(peer, request.clone());
+                    }
+                }
+                RPC::NewPeer(peer) => {
+                    self.add_peer(peer);
+                }
+                RPC::FindValue(key) => {
+                    self.lookup_value_request(Key::new(key), request.clone());
+                }
+                RPC::Store(key, value) => {
+                    self.store_request(key, value, request.clone());
+                }
+                RPC::AddProvider(key, provider) => {
+                    self.add_provider_request(key, provider, sender.unwrap());
+                }
+                RPC::GetProviders(key) => {
+                    self.get_providers_request(key, request.clone());
+                }
+                RPC::Ping => {
+                    self.pong_response(sender.unwrap(), request);
+                }
+                _ => {
+                    self.handle_response(src, req);
+                }
+            }
+        }
+
+    }
+
+    /// The mirror image of the handle request function, this function does the "heavy lifting"
+    /// for incoming response RPCs, by routing them to the correct function
+    /// 
+    /// # Arguments 
+    /// 
+    /// * src - the address the response was received from
+    /// * resp - a byte representation of a Resp struct
+    /// 
+    fn handle_response(&mut self, src: &SocketAddr, resp: &ResponseBytes) {
+        let resp_msg = Resp::from_bytes(resp);
+        if let Some(rm) = resp_msg {
+            let (req, receiver, rpc) = rm.to_components();
+            if let Some(request) = req {
+                let (id, sender, req_rpc) = request.to_components();
+
+                // Only accept responses to requests the local node sent, from the
+                // peer they were sent to, that haven't already been answered or
+                // timed out. Answers from anyone else leave the request pending.
+                match self.pending.get(&id) {
+                    None => {
+                        info!("Rejected unsolicited response to request {:?}", id);
+                        return;
+                    }
+                    Some(pending) if pending.peer != *src => {
+                        info!("Rejected response from {:?} to request {:?} sent to {:?}", src, id, pending.peer);
+                        return;
+                    }
+                    Some(_) => {}
+                }
+                let Some(pending) = self.pending.remove(&id) else {
+                    return;
+                };
+
+                // A response without an rpc is treated as if the peer never answered
+                let Some(rpc) = rpc else {
+                    self.handle_timeout(pending);
+                    return;
+                };
+
+                // Lookup queries are only answered by nodes, a value or providers,
+                // any other answer fails the query so that the lookup moves on.
+                if let Purpose::Lookup(_) = pending.purpose {
+                    if !matches!(rpc, RPC::Nodes(_) | RPC::Value(_) | RPC::Providers(..)) {
+                        info!("Rejected {:?} in answer to a lookup query to {:?}", rpc, pending.peer);
+                        self.handle_timeout(pending);
+                        return;
+                    }
+                }
+
+                // Peers from other networks are treated as if they never answered,
+                // so they aren't added and lookups move on without them.
+                if !self.same_network(Some(pending.peer), &rm.protocol) {
+                    self.handle_timeout(pending);
+                    return;
+                }
+                self.failures.remove(&pending.peer);
+                self.scores.latency(pending.peer, pending.sent.elapsed());
+
+                let mut complete = false;
+                match rpc {
+                    RPC::Nodes(nodes) => {
+                        if let Purpose::Lookup(lookup_id) = pending.purpose {
+                            self.handle_lookup_response(lookup_id, nodes);
+                            return;
+                        }
+
+                        nodes.iter().for_each(|peer| {
+                            if PeerInfo::from_bytes(peer).is_some() {
+                                self.add_peer(peer.clone());
+                            }
+                        });
+
+                        if pending.purpose == Purpose::Bootstrap && self.bootstrapping.succeeded() {
+                            info!("Bootstrapped from {:?}", pending.peer);
+                            let local_key = self.routing_table.local_info.get_key();
+                            self.lookup(local_key);
+                        }
+                    }
+                    RPC::Value(value) => {
+                        if let Purpose::Lookup(lookup_id) = pending.purpose {
+                            self.handle_value_response(lookup_id, pending.peer, value);
+                        }
+                    }
+                    RPC::Providers(key, providers, nodes) => {
+                        if let Purpose::Lookup(lookup_id) = pending.purpose {
+                            self.handle_providers_response(lookup_id, providers, nodes);
+                        }
+                    }
+                    RPC::Saved(key) => {
+                        info!("{:?} stored record {:?}", pending.peer, Key::new(key));
+                    }
+                    RPC::Pong(peer) => {
+                        // The ping was still pending so the peer is alive,
+                        // moving them to the back of their kbucket as the
+                        // most recently seen peer. A pong claiming to be from
+                        // another peer is treated as if the ping went unanswered.
+                        let pinged = PeerInfo::from_bytes(&peer)
+                            .is_some_and(|info| info.get_key() == pending.target && info.address == pending.peer);
+                        if pinged {
+                            self.add_peer(peer)
+                        } else {
+                            info!("Rejected pong from {:?} for another peer", pending.peer);
+                            self.handle_timeout(pending);
+                        }
+                    }
+                    _ => {
+                        self.handle_request(src, resp);
+                    }
+                }
+
+            }
+
+        }
+    }
+
+    /// Decodes and determines which type of message the
+    /// incoming message is. If it's a Request variant, then it calls handle_request()
+    /// if it's a Response variant then it calls handle_response, and if it's a Kill
+    /// variant it shuts the instance down.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the address the message was received from
+    /// * message - a KadMessage enum that contains a response or request and the relevant RPC.
+    pub fn handle_message(&mut self, src: &SocketAddr, message: &KadMessage) {
+        match message {
+            KadMessage::Request(req) => {
+                self.handle_request(src, req);
+            }
+            KadMessage::Response(resp) => {
+                self.handle_response(src, resp);
+            }
+            KadMessage::Kill => self.shutdown(),
+        }
+    }
+
+    /// Shuts the instance down. Every peer in the routing table is told the local
+    /// node is leaving, pending requests and lookups are abandoned, the routing
+    /// table is written to the snapshot path if one is set, and no further
+    /// messages are handled.
+    pub fn shutdown(&mut self) {
+        if self.stopped {
+            return;
+        }
+
+        info!("Shutting down kademlia instance");
+        self.leave();
+        self.pending.clear();
+        self.lookups.clear();
+        self.save_snapshot();
+        self.stopped = true;
+    }
+
+    /// Checks if the instance has been shut down and returns true or false
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Sends a ping request to a peer to check whether it is still alive, unless
+    /// a ping to the same peer is already pending. If the ping times out the peer
+    /// is evicted from the routing table.
+    /// 
+    /// # Arguments
+    /// 
+    /// * node - the peer to ping
+    pub fn ping_node(&mut self, node: PeerInfo) {
+        let pinging = self
+            .pending
+            .values()
+            .any(|req| req.purpose == Purpose::Ping && req.peer == node.address);
+        if !pinging {
+            let request = self.prepare_ping_message();
+            self.send_request(node.address, node.get_key(), Purpose::Ping, request);
+        }
+    }
+
+    /// Responds to a ping request with a pong
+    /// 
+    /// # Arguments
+    /// 
+    /// * node - the peer that sent the ping request
+    /// * req - the original ping request
+    pub fn pong_response(&mut self, node: PeerInfo, req: Req) {
+        let resp_msg = self.prepare_pong_response(&node, req);
+        if let Err(e) = self.to_transport.send((node.address, resp_msg)) {
+            println!("Error sending to transport: {:?}", e);
+        }
+    }
+
+    /// Tells every peer in the routing table that the local node is leaving the network
+    pub fn leave(&mut self) {
+        let local_id = self.routing_table.local_info.id.clone();
+        self.routing_table
+            .get_all_peers()
+            .into_iter()
+            .filter(|peer| peer.id != local_id)
+            .for_each(|peer| {
+                let (_, message) = self.prepare_leave_message();
+                if let Err(e) = self.to_transport.send((peer.address, message)) {
+                    println!("Error sending to transport: {:?}", e);
+                }
+            });
+    }
+
+    /// Removes a peer that is leaving the network from the routing table, so it
+    /// is no longer returned in lookups or sampled for gossip, and fails any
+    /// requests still waiting on it so lookups move on without waiting for
+    /// them to time out.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer that is leaving
+    pub fn leave_request(&mut self, peer: PeerInfo) {
+        info!("Peer {:?} left the network", peer.address);
+        self.disconnect(peer);
+    }
+
+    /// Removes a peer from the routing table and its replacement cache, fails any
+    /// requests still waiting on it and forgets its timeouts.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer to disconnect from
+    fn disconnect(&mut self, peer: PeerInfo) {
+        if let Some(peer) = self.routing_table.remove_departed(&peer) {
+            self.events.publish(Event::PeerEvicted(peer));
+        }
+
+        let waiting: Vec<MessageKey> = self
+            .pending
+            .iter()
+            .filter(|(_, req)| req.peer == peer.address)
+            .map(|(id, _)| *id)
+            .collect();
+        waiting.iter().for_each(|id| {
+            if let Some(req) = self.pending.remove(id) {
+                self.handle_timeout(req);
+            }
+        });
+        self.failures.remove(&peer.address);
+    }
+
+    /// Returns the closest peers to a target from the routing table. Peers scoring
+    /// below zero are moved behind the others, so they are only returned when
+    /// there aren't enough well behaved peers nearby.
+    /// 
+    /// # Arguments
+    /// 
+    /// * target - the peer to find the closest peers to
+    /// * count - the number of peers to return
+    fn closest_peers(&self, target: PeerInfo, count: usize) -> Vec<PeerInfo> {
+        let mut closest = self.routing_table.get_closest_peers(target, count * 2);
+        closest.sort_by_key(|peer| self.scores.score(&peer.address) < 0.0);
+        closest.truncate(count);
+        closest
+    }
+
+    /// Evicts a peer that another layer found to be unresponsive from the routing
+    /// table, in the same way as a peer that fails to answer a ping
+    /// 
+    /// # Arguments
+    /// 
+    /// * address - the address of the unresponsive peer
+    pub fn report_unresponsive(&mut self, address: &SocketAddr) {
+        *self.failures.entry(*address).or_insert(0) += 1;
+        self.scores.failed(*address);
+        let key = self
+            .routing_table
+            .get_all_peers()
+            .into_iter()
+            .find(|peer| peer.address == *address)
+            .map(|peer| peer.get_key());
+        if let Some(peer) = key.and_then(|key| self.routing_table.evict(&key)) {
+            info!("Evicted unresponsive peer {:?}", peer.address);
+            self.events.publish(Event::PeerEvicted(peer));
+        }
+    }
+
+    /// Pings the least recently seen peer in each kbucket
+    pub fn ping_lru_peers(&mut self) {
+        self.routing_table
+            .get_lru_peers()
+            .into_iter()
+            .for_each(|peer| self.ping_node(peer));
+    }
+
+
+    /// Starts an iterative lookup for the DEFAULT_N_PEERS closest peers to a key.
+    /// The closest known peers are queried MAX_ACTIVE_RPCS at a time, and every
+    /// closer peer they return is queried in turn until the closest peers have
+    /// all responded. Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * target - the key to find the closest peers to
+    pub fn lookup(&mut self, target: Key) -> MessageKey {
+        self.start_lookup(target, LookupKind::Nodes)
+    }
+
+    /// Seeds a new lookup with the closest peers in the routing table and sends
+    /// its first round of queries. Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * target - the key being looked up
+    /// * kind - what the lookup is looking for
+    fn start_lookup(&mut self, target: Key, kind: LookupKind) -> MessageKey {
+        let id = MessageKey::rand();
+        let local_id = self.routing_table.local_info.id.clone();
+        let seeds = self
+            .closest_peers(self.target_info(target), DEFAULT_N_PEERS * 2 + 1)
+            .into_iter()
+            .filter(|peer| peer.id != local_id)
+            .collect();
+        self.lookups.insert(id, Lookup::new(target, seeds, kind));
+        self.step_lookup(id);
+        id
+    }
+
+    /// Returns the state of a lookup that is still in progress
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id returned when the lookup was started
+    pub fn get_lookup(&self, id: &MessageKey) -> Option<&Lookup> {
+        self.lookups.get(id)
+    }
+
+    /// Sends the next round of queries for a lookup, or removes the lookup
+    /// if it is complete.
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id of the lookup
+    fn step_lookup(&mut self, id: MessageKey) {
+        let (target, next, complete, kind) = match self.lookups.get_mut(&id) {
+            Some(lookup) => {
+                let quorum = lookup.kind == LookupKind::Value && lookup.records.len() >= VALUE_QUORUM;
+                let finished = quorum || lookup.is_expired();
+                let next = if finished { vec![] } else { lookup.next_peers(MAX_ACTIVE_RPCS) };
+                (lookup.target, next, finished || lookup.is_complete(), lookup.kind)
+            }
+            None => return,
+        };
+
+        if complete {
+            if let Some(lookup) = self.lookups.remove(&id) {
+                info!(
+                    "Lookup for {:?} completed with {} peers",
+                    lookup.target,
+                    lookup.closest.len()
+                );
+                let (target, closest) = (lookup.target, lookup.closest.clone());
+                match lookup.kind {
+                    LookupKind::AddProvider => self.announce_provider(lookup.target, &lookup.closest),
+                    LookupKind::Store => {
+                        if let Some(record) = self.store.get(&lookup.target) {
+                            self.store_value(record, &lookup.closest);
+                        }
+                    }
+                    LookupKind::Value => self.finish_value_lookup(lookup),
+                    _ => {}
+                }
+                self.events.publish(Event::LookupCompleted(id, target, closest));
+            }
+            return;
+        }
+
+        next.into_iter().for_each(|peer| {
+            let request = match kind {
+                LookupKind::Value => self.prepare_find_value_message(target),
+                LookupKind::Providers => self.prepare_get_providers_message(target),
+                _ => self.prepare_find_node_message(self.target_info(target), None),
+            };
+            self.send_request(peer.address, target, Purpose::Lookup(id), request);
+        });
+    }
+
+    /// Adds the peers returned in response to a lookup query to the routing table
+    /// and the lookup, then continues the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id of the lookup
+    /// * nodes - the byte representation of the peers returned
+    fn handle_lookup_response(&mut self, id: MessageKey, nodes: Nodes) {
+        let local_id = self.routing_table.local_info.id.clone();
+        let peers: Vec<PeerInfo> = nodes
+            .iter()
+            .filter_map(|peer| PeerInfo::from_bytes(peer))
+            .filter(|peer| peer.id != local_id && !self.scores.is_banned(&peer.address))
+            .collect();
+        peers.iter().for_each(|peer| {
+            if let Some(bytes) = peer.as_bytes() {
+                self.add_peer(bytes);
+            }
+        });
+
+        if let Some(lookup) = self.lookups.get_mut(&id) {
+            lookup.query_finished();
+            lookup.insert(peers);
+        }
+        self.step_lookup(id);
+    }
+
+    /// Adds the version of a record returned in response to a value lookup to the
+    /// lookup, then continues the lookup. Records that aren't stored under the lookup's
+    /// target, or that fail validation, are ignored.
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id of the lookup
+    /// * peer - the address of the peer that returned the record
+    /// * value - the byte representation of the record returned
+    fn handle_value_response(&mut self, id: MessageKey, peer: SocketAddr, value: Value) {
+        let target = match self.lookups.get_mut(&id) {
+            Some(lookup) => {
+                lookup.query_finished();
+                lookup.target
+            }
+            None => return,
+        };
+
+        match DhtRecord::from_bytes(&value) {
+            Some(record) if record.key == target => match self.validators.validate(&record) {
+                Ok(()) => {
+                    if let Some(lookup) = self.lookups.get_mut(&id) {
+                        lookup.records.push((peer, record));
+                    }
+                }
+                Err(e) => info!("Rejected invalid record {:?} from {:?}: {}", target, peer, e),
+            },
+            _ => info!("Rejected record that doesn't match lookup target {:?}", target),
+        }
+        self.step_lookup(id);
+    }
+
+    /// Picks the best of the versions of a record found by a value lookup and adds it
+    /// to the local record store. Peers that returned any other version are sent the best
+    /// version, so that outdated versions are corrected.
+    /// 
+    /// # Arguments
+    /// 
+    /// * lookup - the completed lookup
+    fn finish_value_lookup(&mut self, lookup: Lookup) {
+        let versions: Vec<DhtRecord> = lookup.records.iter().map(|(_, record)| record.clone()).collect();
+        let best = match self.validators.select(&versions) {
+            Some(best) => best,
+            None => {
+                info!("Lookup for record {:?} didn't find the record", lookup.target);
+                return;
+            }
+        };
+
+        info!("Lookup for record {:?} found {} versions", lookup.target, versions.len());
+        let outdated: Vec<PeerInfo> = lookup
+            .records
+            .iter()
+            .filter(|(_, record)| record.value != best.value)
+            .filter_map(|(address, _)| lookup.closest.iter().find(|peer| peer.address == *address).cloned())
+            .collect();
+        if let Err(e) = self.merge_record(best.clone()) {
+            info!("Unable to store record {:?}: {}", lookup.target, e);
+        }
+        self.store_value(best, &outdated);
+    }
+
+    /// Adds a record to the local record store unless the store already holds a better
+    /// version of it, as picked by the validator for the record's namespace. The stored
+    /// record's namespace is authoritative, so a version in another namespace is refused
+    /// rather than being allowed to skip the stored namespace's validator. The publisher
+    /// named by a remote record can't be checked, so it is cleared before the record is
+    /// stored. Returns an error if the record is refused.
+    /// 
+    /// # Arguments
+    /// 
+    /// * record - the record to add
+    fn merge_record(&mut self, mut record: DhtRecord) -> Result<(), Box<dyn Error>> {
+        record.publisher = None;
+        let best = match self.store.get(&record.key) {
+            Some(existing) if existing.namespace != record.namespace => {
+                return Err(format!(
+                    "record is in namespace {:?} but the stored record is in {:?}",
+                    record.namespace, existing.namespace
+                )
+                .into());
+            }
+            Some(existing) => self.validators.select(&[record, existing]),
+            None => Some(record),
+        };
+        match best {
+            Some(best) => self.store.put(best),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts a lookup for a random key in the range of every kbucket that
+    /// hasn't been updated in REFRESH_INTEVAL, so that quiet regions of the
+    /// keyspace are kept up to date.
+    pub fn refresh_buckets(&mut self) {
+        let local_key = self.routing_table.local_info.get_key();
+        self.routing_table.get_stale_indices().into_iter().for_each(|index| {
+            self.routing_table.buckets[index].touch();
+            let target = local_key.xor(Key::rand_in_range(index));
+            self.lookup(target);
+        });
+    }
+
+    /// Returns a PeerInfo for a key that is being looked up. Find node requests carry
+    /// a PeerInfo, so lookups for keys that don't belong to a known peer use the local
+    /// address alongside the target key.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key being looked up
+    fn target_info(&self, key: Key) -> PeerInfo {
+        PeerInfo::new(PeerId::from_key(&key), key, self.routing_table.local_info.address)
+    }
+
+    /// The core function of the kademlia DHT. This function takes in a peer (and the request that contained said peer)
+    /// and traverses the Routing table retuning DEFAULT_N_PEERS closest peers. It then responds to the requestor
+    /// with the closest peers to the requested peer. If the requestor is looking up itself, i.e. it is joining
+    /// the network, it subsequently sends all the closest peers a new peer message to inform them that we have
+    /// discovered a new peer. Lookups for any other key only return the closest peers, since the key doesn't
+    /// necessarily belong to a peer.
+    /// 
+    /// # Arguments
+    /// 
+    /// * node - the node to lookup and/or find it's closest peers
+    /// * req - the original request. 
+    pub fn lookup_node(&mut self, node: PeerInfo, req: Req) {
+        let mut closest_peers = self.closest_peers(node.clone(), DEFAULT_N_PEERS);
+        let (_, sender, rpc) = req.to_components();
+        let sender = match sender {
+            Some(sender) => sender,
+            None => return,
+        };
         let resp_msg =
             self.prepare_nodes_response_message(req.clone(), closest_peers.clone());
         if let Err(e) = self
             .to_transport
-            .send((node.address.clone(), resp_msg.clone()))
+            .send((sender.address, resp_msg.clone()))
         {
             println!("Error sending to transport: {:?}", e);
         }
+
+        if node != sender {
+            return;
+        }
+
+        if let Some(bytes) = node.as_bytes() {
+            self.add_peer(bytes);
+        }
+        let (id, msg) = self.prepare_new_peer_message(node.clone());
         closest_peers.iter().for_each(|peer| {
             if let Err(e) = self.to_transport.send((peer.get_address(), msg.clone())) {
                 println!("Error sending to transport: {:?}", e);
@@ -431,6 +1454,318 @@ impl Kademlia {
         });
     }
 
-    pub fn lookup_value(&mut self, value: Value) {}
-    pub fn store_value(&mut self, value: Value) {}
+    /// Responds to a find value request with the record stored under the key if the
+    /// local node holds it, or with the closest peers to the key if it doesn't.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key of the record requested
+    /// * req - the original request
+    pub fn lookup_value_request(&mut self, key: Key, req: Req) {
+        let record = match self.store.get(&key) {
+            Some(record) => record,
+            None => {
+                self.lookup_node(self.target_info(key), req);
+                return;
+            }
+        };
+
+        let (_, sender, _) = req.to_components();
+        if let Some(sender) = sender {
+            let resp_msg = self.prepare_value_response(&sender, &record, req);
+            if let Err(e) = self.to_transport.send((sender.address, resp_msg)) {
+                println!("Error sending to transport: {:?}", e);
+            }
+        }
+    }
+
+    /// Adds a record sent in a store request to the record store and responds
+    /// with a saved response. Records that don't match the key they were sent
+    /// under, that fail validation, or that the store refuses, are dropped without
+    /// a response. If the store already holds a better version of the record, the
+    /// request is still answered but the stored version is kept.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key the record was sent under
+    /// * value - the byte representation of the record
+    /// * req - the original request
+    pub fn store_request(&mut self, key: StoreKey, value: Value, req: Req) {
+        let record = match DhtRecord::from_bytes(&value) {
+            Some(record) if record.key == Key::new(key) => record,
+            _ => {
+                info!("Rejected malformed record for key {:?}", Key::new(key));
+                return;
+            }
+        };
+
+        if let Err(e) = self.validators.validate(&record) {
+            info!("Rejected invalid record {:?}: {}", Key::new(key), e);
+            return;
+        }
+
+        if let Err(e) = self.merge_record(record) {
+            info!("Unable to store record {:?}: {}", Key::new(key), e);
+            return;
+        }
+
+        let (_, sender, _) = req.to_components();
+        if let Some(sender) = sender {
+            let resp_msg = self.prepare_saved_response(&sender, key, req);
+            if let Err(e) = self.to_transport.send((sender.address, resp_msg)) {
+                println!("Error sending to transport: {:?}", e);
+            }
+        }
+    }
+
+    /// Stores a record in the local record store, then starts a lookup for its key
+    /// and sends the record to the closest peers the lookup finds. The local node is
+    /// the record's publisher and republishes it every RECORD_REPUBLISH_INTERVAL, so
+    /// it stays on the closest peers as they change. Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key to store the record under
+    /// * value - the value of the record
+    pub fn put_record(&mut self, key: Key, value: Value) -> Result<MessageKey, Box<dyn Error>> {
+        self.publish_record(DhtRecord::new(key, value, None))
+    }
+
+    /// Validates a record, stores it in the local record store with the local node as
+    /// its publisher, then sends it to the closest peers to its key like put_record.
+    /// Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * record - the record to publish
+    pub fn publish_record(&mut self, mut record: DhtRecord) -> Result<MessageKey, Box<dyn Error>> {
+        self.validators.validate(&record)?;
+        record.publisher = Some(self.routing_table.local_info.id.clone());
+        let key = record.key;
+        self.store.put(record)?;
+        self.published.insert(key);
+        Ok(self.start_lookup(key, LookupKind::Store))
+    }
+
+    /// Republishes every record the local node published with put_record or
+    /// publish_record. Each record is given a new expiry and sent to the closest
+    /// peers to its key again, while records stored for other peers are left to
+    /// expire unless the peer that published them republishes them.
+    pub fn republish_records(&mut self) {
+        let published: Vec<DhtRecord> = self.published.iter().filter_map(|key| self.store.get(key)).collect();
+        self.published.retain(|key| published.iter().any(|record| record.key == *key));
+        published.into_iter().for_each(|mut record| {
+            record.expires = None;
+            if let Err(e) = self.publish_record(record.clone()) {
+                info!("Unable to republish record {:?}: {}", record.key, e);
+            }
+        });
+    }
+
+    /// Sends a newly added peer every record held by the local node that the peer
+    /// is now one of the closest peers to, so records move to the closest peers
+    /// as they join.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer that was added to the routing table
+    fn replicate_to(&mut self, peer: &PeerInfo) {
+        let records: Vec<DhtRecord> = self
+            .store
+            .records()
+            .filter(|record| {
+                self.routing_table
+                    .get_closest_peers(self.target_info(record.key), DEFAULT_N_PEERS)
+                    .contains(peer)
+            })
+            .collect();
+        records.into_iter().for_each(|record| {
+            self.store_value(record, std::slice::from_ref(peer));
+        });
+    }
+
+    /// Returns the record stored under a key, if the local record store holds it.
+    /// Records found by value lookups are added to the local record store.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key of the record
+    pub fn get_record(&self, key: &Key) -> Option<DhtRecord> {
+        self.store.get(key)
+    }
+
+    /// Starts an iterative lookup for the record stored under a key. The lookup
+    /// ends once VALUE_QUORUM peers have returned a version of the record, or once
+    /// it runs out of closer peers to query or times out. The validator for the record's namespace
+    /// picks the best of the versions found, which is added to the local record store
+    /// and sent to the peers that returned an outdated version. Returns the id of the
+    /// lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key of the record
+    pub fn lookup_value(&mut self, key: Key) -> MessageKey {
+        self.start_lookup(key, LookupKind::Value)
+    }
+
+    /// Adds the sender of an add provider request as a provider of the key.
+    /// Peers can only announce themselves, so requests naming any other
+    /// provider are dropped.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key being provided
+    /// * provider - the byte representation of the providing peer
+    /// * sender - the peer that sent the request
+    pub fn add_provider_request(&mut self, key: StoreKey, provider: Peer, sender: PeerInfo) {
+        match PeerInfo::from_bytes(&provider) {
+            Some(provider) if provider == sender => {
+                let record = ProviderRecord::new(Key::new(key), provider);
+                if let Err(e) = self.store.add_provider(record) {
+                    info!("Unable to add provider for {:?}: {}", Key::new(key), e);
+                }
+            }
+            _ => info!("Rejected provider announced by {:?} for another peer", sender.address),
+        }
+    }
+
+    /// Responds to a get providers request with the known providers of the key
+    /// and the closest peers to the key.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key the providers were requested for
+    /// * req - the original request
+    pub fn get_providers_request(&mut self, key: StoreKey, req: Req) {
+        let providers: Vec<PeerInfo> = self
+            .store
+            .providers(&Key::new(key))
+            .into_iter()
+            .map(|record| record.provider)
+            .collect();
+        let nodes = self.closest_peers(self.target_info(Key::new(key)), DEFAULT_N_PEERS);
+
+        let (_, sender, _) = req.to_components();
+        if let Some(sender) = sender {
+            let resp_msg = self.prepare_providers_response(&sender, key, providers, nodes, req);
+            if let Err(e) = self.to_transport.send((sender.address, resp_msg)) {
+                println!("Error sending to transport: {:?}", e);
+            }
+        }
+    }
+
+    /// Announces the local node as a provider of a key. The local node is added
+    /// as a provider in the record store, then a lookup for the key is started and
+    /// an add provider message is sent to the closest peers it finds. Provided keys
+    /// are republished every PROVIDER_REPUBLISH_INTERVAL so they don't expire on the
+    /// peers holding them. Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key the local node provides
+    pub fn provide(&mut self, key: Key) -> Result<MessageKey, Box<dyn Error>> {
+        let local_info = self.routing_table.local_info.clone();
+        self.store.add_provider(ProviderRecord::new(key, local_info))?;
+        Ok(self.start_lookup(key, LookupKind::AddProvider))
+    }
+
+    /// Starts an iterative lookup for the providers of a key. The lookup ends as soon
+    /// as a peer returns any providers, which are then added to the local record store
+    /// and can be read with providers. Returns the id of the lookup.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key to find the providers of
+    pub fn find_providers(&mut self, key: Key) -> MessageKey {
+        self.start_lookup(key, LookupKind::Providers)
+    }
+
+    /// Returns the providers of a key held in the local record store
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key to return the providers of
+    pub fn providers(&self, key: &Key) -> Vec<PeerInfo> {
+        self.store
+            .providers(key)
+            .into_iter()
+            .map(|record| record.provider)
+            .collect()
+    }
+
+    /// Sends an add provider message for a key to each of the peers provided
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key the local node provides
+    /// * peers - the peers to announce the local node to
+    fn announce_provider(&mut self, key: Key, peers: &[PeerInfo]) {
+        let local_id = self.routing_table.local_info.id.clone();
+        let (_, msg) = self.prepare_add_provider_message(key);
+        peers.iter().filter(|peer| peer.id != local_id).for_each(|peer| {
+            if let Err(e) = self.to_transport.send((peer.address, msg.clone())) {
+                println!("Error sending to transport: {:?}", e);
+            }
+        });
+    }
+
+    /// Refreshes the expiry of every key the local node provides and announces
+    /// the local node to the closest peers to each key again.
+    pub fn republish_providers(&mut self) {
+        let provided: Vec<Key> = self.store.provided().map(|record| record.key).collect();
+        provided.into_iter().for_each(|key| {
+            if let Err(e) = self.provide(key) {
+                info!("Unable to republish provider for {:?}: {}", key, e);
+            }
+        });
+    }
+
+    /// Adds the providers returned in response to a providers lookup to the local
+    /// record store and ends the lookup, or continues the lookup with the closest
+    /// peers returned if there were no providers.
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id of the lookup
+    /// * providers - the byte representation of the providers returned
+    /// * nodes - the byte representation of the closest peers returned
+    fn handle_providers_response(&mut self, id: MessageKey, providers: Nodes, nodes: Nodes) {
+        let target = match self.lookups.get(&id) {
+            Some(lookup) => lookup.target,
+            None => return,
+        };
+
+        let providers: Vec<PeerInfo> = providers
+            .iter()
+            .filter_map(|provider| PeerInfo::from_bytes(provider))
+            .collect();
+        if providers.is_empty() {
+            self.handle_lookup_response(id, nodes);
+            return;
+        }
+
+        providers.into_iter().for_each(|provider| {
+            if let Err(e) = self.store.add_provider(ProviderRecord::new(target, provider)) {
+                info!("Unable to add provider for {:?}: {}", target, e);
+            }
+        });
+        info!("Lookup for providers of {:?} found providers", target);
+        if let Some(lookup) = self.lookups.remove(&id) {
+            self.events.publish(Event::LookupCompleted(id, target, lookup.closest));
+        }
+    }
+
+    /// Sends a store request for a record to each of the peers provided
+    /// 
+    /// # Arguments
+    /// 
+    /// * record - the record to store
+    /// * peers - the peers to store the record on
+    pub fn store_value(&mut self, record: DhtRecord, peers: &[PeerInfo]) {
+        let local_id = self.routing_table.local_info.id.clone();
+        peers.iter().filter(|peer| peer.id != local_id).for_each(|peer| {
+            let request = self.prepare_store_message(&record);
+            self.send_request(peer.address, record.key, Purpose::Store(record.key), request);
+        });
+    }
 }
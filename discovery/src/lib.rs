@@ -2,26 +2,50 @@
 pub mod routing;
 pub mod protocol;
 pub mod kad;
+pub mod lookup;
+pub mod pending;
+pub mod bootstrap;
+pub mod score;
 
 const MAX_BUCKET_LEN: usize = 30;
-const MAX_BUCKETS: usize = 10;
+const KEY_BITS: usize = 256;
 const REFRESH_INTEVAL: u128 = 900_000_000_000;
-const KAD_MESSAGE_LEN: usize = 55000;
 const REQ_TIMEOUT: usize = 60_000_000_000;
 const MAX_ACTIVE_RPCS: usize = 3;
 const DEFAULT_N_PEERS: usize = 8;
+const BOOTSTRAP_TIMEOUT: u64 = 5_000_000_000;
+const BOOTSTRAP_BACKOFF: u64 = 1_000_000_000;
+const BOOTSTRAP_RETRIES: usize = 3;
+const PROVIDER_REPUBLISH_INTERVAL: u128 = 43_200_000_000_000;
+const RECORD_REPUBLISH_INTERVAL: u128 = 3_600_000_000_000;
+const VALUE_QUORUM: usize = 3;
+const LOOKUP_TIMEOUT: u64 = 180_000_000_000;
+const DEFAULT_PROTOCOL_ID: &str = "udp2p";
 
 #[cfg(test)]
 mod tests {
 
+    use crate::bootstrap::{Bootstrap, BootstrapStatus};
     use crate::kad::Kademlia;
+    use crate::pending::Purpose;
+    use crate::protocol::{Req, Resp, RPC};
     use crate::routing::RoutingTable;
+    use crate::{BOOTSTRAP_BACKOFF, BOOTSTRAP_RETRIES, DEFAULT_PROTOCOL_ID, REQ_TIMEOUT};
     use udp2p_node::peer_id::PeerId;
     use udp2p_node::peer_key::Key;
     use udp2p_node::peer_info::PeerInfo;
+    use udp2p_protocol::event::Event;
+    use udp2p_protocol::protocol::{KadMessage, Message, MessageKey};
+    use udp2p_record::memory::MemoryStoreConfig;
+    use udp2p_record::record::DhtRecord;
+    use udp2p_record::validator::Validator;
+    use std::error::Error;
     use rand::Rng;
+    use std::collections::{HashMap, HashSet};
     use std::net::SocketAddr;
-    use udp2p_utils::utils::Distance;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::{Duration, Instant};
+    use udp2p_utils::utils::{timestamp_now, ByteRep, Distance};
     use std::cmp;
 
     fn setup(n_peers: usize) -> (RoutingTable, PeerInfo, Vec<PeerInfo>) {
@@ -45,21 +69,56 @@ mod tests {
         
     }
 
+    type TestKad = (Kademlia, Receiver<(SocketAddr, Message)>, Sender<(SocketAddr, KadMessage)>, Vec<PeerInfo>);
+
+    fn setup_kad(n_peers: usize) -> TestKad {
+        let (routing_table, _, peers) = setup(n_peers);
+        let (to_transport_tx, to_transport_rx) = channel();
+        let (to_kad_tx, to_kad_rx) = channel();
+        let kad = Kademlia::new(
+            routing_table,
+            to_transport_tx,
+            to_kad_rx,
+            HashMap::new(),
+            Duration::from_secs(20),
+            Instant::now(),
+        );
+
+        (kad, to_transport_rx, to_kad_tx, peers)
+    }
+
+    fn nodes_response(id: MessageKey, requestor: &PeerInfo, responder: &PeerInfo, nodes: &[PeerInfo]) -> KadMessage {
+        let req = Req {
+            id: id.inner(),
+            sender: requestor.as_bytes().unwrap(),
+            payload: RPC::FindNode(requestor.as_bytes().unwrap()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let resp = Resp {
+            request: req.as_bytes().unwrap(),
+            receiver: responder.as_bytes().unwrap(),
+            payload: RPC::Nodes(nodes.iter().map(|peer| peer.as_bytes().unwrap()).collect()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+
+        KadMessage::Response(resp.as_bytes().unwrap())
+    }
+
     #[test]
     fn kad_add_address_works() {
         let (mut rt, local, peers) = setup(5);
         let peer = peers[0].clone();
-        let test_kad = rt.tree.iter().any(|(k, v)| {
+        let test_kad = rt.buckets.iter().any(|v| {
             v.contains(&peer)
         });
 
-        assert_eq!(test_kad, false);
-        rt.update_peer(&peer, 0);
-        let test_kad = rt.tree.iter().any(|(k, v)| {
+        assert!(!test_kad);
+        rt.update_peer(&peer);
+        let test_kad = rt.buckets.iter().any(|v| {
             v.contains(&peer)
         });
 
-        assert_eq!(test_kad, true);
+        assert!(test_kad);
         assert!(rt.size() <= 2);
     }
 
@@ -68,35 +127,46 @@ mod tests {
         let (mut rt, local, peers) = setup(90);
         let rn: usize = rand::thread_rng().gen_range(0..peers.len()-1);
         let random_peer = peers[rn].clone();
-        let test_kad = rt.tree.iter().any(|(k, v)| {
+        let test_kad = rt.buckets.iter().any(|v| {
             v.contains(&random_peer)
         });
         assert!(!test_kad);
 
         peers.iter().for_each(|peer| {
-            rt.update_peer(peer, 0);
+            rt.update_peer(peer);
         });
 
-        let test_kad = rt.tree.iter().any(|(k, v)| {
-            v.contains(&random_peer)
+        let test_kad = rt.buckets.iter().any(|v| {
+            v.contains(&random_peer) || v.is_replacement(&random_peer)
         });
 
-        let mut sum = 0;
-        rt.tree.iter().for_each(|(k, v)| {
-            sum += v.size();
+        // Each bucket holds the peers at its distance up to its capacity, and the rest
+        // are held as replacements, so every peer is held exactly once
+        let last = rt.size() - 1;
+        let mut held = 0;
+        rt.buckets.iter().enumerate().for_each(|(index, bucket)| {
+            let at_distance = peers
+                .iter()
+                .chain(std::iter::once(&local))
+                .filter(|peer| cmp::min(local.get_key().xor(peer.get_key()).leading_zeros(), last) == index)
+                .count();
+            let nodes = at_distance.min(crate::MAX_BUCKET_LEN);
+            let replacements = (at_distance - nodes).min(crate::MAX_BUCKET_LEN);
+            assert_eq!(bucket.size(), nodes);
+            assert_eq!(peers.iter().filter(|peer| bucket.is_replacement(peer)).count(), replacements);
+            held += nodes + replacements;
         });
 
-        assert!(sum == 91);
+        assert_eq!(held, 91);
         assert!(rt.size() > 2);
         assert!(test_kad);
-
     }
 
     #[test]
     fn kad_get_closest_peers_works() {
         let (mut kad, local, peers) = setup(90);
         peers.iter().for_each(|peer| {
-            kad.update_peer(peer, 0);
+            kad.update_peer(peer);
         });
         let peer = peers[5].clone();
         let four_closest_peers = kad.get_closest_peers(peer.clone(), 4);
@@ -109,4 +179,1006 @@ mod tests {
         assert_eq!(fifty_closest_peers.len(), 50);
         
     }
+
+    #[test]
+    fn routing_table_buckets_hold_their_prefix() {
+        for _ in 0..50 {
+            let n_peers = rand::thread_rng().gen_range(0..400);
+            let (mut rt, local, peers) = setup(n_peers);
+            peers.iter().for_each(|peer| {
+                rt.update_peer(peer);
+            });
+
+            let last = rt.size() - 1;
+            rt.buckets.iter().enumerate().for_each(|(index, bucket)| {
+                assert!(bucket.size() <= crate::MAX_BUCKET_LEN);
+                bucket.get_nodes().iter().for_each(|peer| {
+                    let zeros = local.get_key().xor(peer.get_key()).leading_zeros();
+                    if index == last {
+                        assert!(zeros >= index);
+                    } else {
+                        assert_eq!(zeros, index);
+                    }
+                });
+            });
+
+            assert!(!rt.is_new(&local));
+            // A peer is only turned away when its bucket is full
+            assert!(peers.iter().all(|peer| {
+                !rt.is_new(peer) || rt.buckets[rt.bucket_index(&peer.get_key())].is_full()
+            }));
+        }
+    }
+
+    #[test]
+    fn routing_table_closest_peers_match_xor_sort() {
+        for _ in 0..50 {
+            let n_peers = rand::thread_rng().gen_range(0..400);
+            let (mut rt, _, peers) = setup(n_peers);
+            peers.iter().for_each(|peer| {
+                rt.update_peer(peer);
+            });
+
+            let (_, target, _) = setup(0);
+            let mut expected = rt.get_all_peers();
+            expected.sort_by_key(|peer| peer.get_key().xor(target.get_key()));
+
+            for count in [1, 3, 8, 20, 100, 1000] {
+                let closest = rt.get_closest_peers(target.clone(), count);
+                let brute_force: Vec<PeerInfo> = expected.iter().take(count).cloned().collect();
+                assert_eq!(closest, brute_force);
+            }
+
+            if let Some(peer) = peers.iter().find(|peer| !rt.is_new(peer)) {
+                let closest = rt.get_closest_peers(peer.clone(), 1);
+                assert_eq!(&closest[0], peer);
+            }
+        }
+    }
+
+    #[test]
+    fn routing_table_remove_peer_works() {
+        for _ in 0..20 {
+            let (mut rt, _, peers) = setup(200);
+            peers.iter().for_each(|peer| {
+                rt.update_peer(peer);
+            });
+
+            let total = rt.total_peers();
+            let stored: Vec<PeerInfo> = peers.iter().filter(|peer| !rt.is_new(peer)).cloned().collect();
+            let (removed, kept) = stored.split_at(stored.len() / 2);
+            removed.iter().for_each(|peer| {
+                assert_eq!(rt.remove_peer(peer).as_ref(), Some(peer));
+            });
+
+            assert_eq!(rt.total_peers(), total - removed.len());
+            assert!(removed.iter().all(|peer| rt.is_new(peer)));
+            assert!(kept.iter().all(|peer| !rt.is_new(peer)));
+            assert!(removed.iter().all(|peer| rt.remove_peer(peer).is_none()));
+        }
+    }
+
+    #[test]
+    fn kad_rejects_unsolicited_responses() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(3);
+        let local = kad.routing_table.local_info.clone();
+
+        let unsolicited = nodes_response(MessageKey::rand(), &local, &peers[0], &peers[1..2]);
+        kad.handle_message(&peers[0].address, &unsolicited);
+        assert!(kad.routing_table.is_new(&peers[1]));
+
+        kad.bootstrap(&[peers[0].address]);
+        let id = *kad.pending.keys().next().unwrap();
+
+        // Another peer that learns the request id can't answer in its place
+        let forged = nodes_response(id, &local, &peers[0], &peers[2..3]);
+        kad.handle_message(&peers[1].address, &forged);
+        assert!(kad.routing_table.is_new(&peers[2]));
+        assert!(kad.pending.contains_key(&id));
+
+        let solicited = nodes_response(id, &local, &peers[0], &peers[1..2]);
+        kad.handle_message(&peers[0].address, &solicited);
+        assert!(!kad.routing_table.is_new(&peers[1]));
+        assert!(!kad.pending.contains_key(&id));
+
+        // A second response to the same request is no longer pending
+        let replayed = nodes_response(id, &local, &peers[0], &peers[2..3]);
+        kad.handle_message(&peers[0].address, &replayed);
+        assert!(kad.routing_table.is_new(&peers[2]));
+    }
+
+    /// Builds a response to the ping request with the given id, carrying the payload provided
+    fn ping_response(id: MessageKey, local: &PeerInfo, payload: Vec<u8>) -> KadMessage {
+        let ping = Req {
+            id: id.inner(),
+            sender: local.as_bytes().unwrap(),
+            payload: RPC::Ping.as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let resp = Resp {
+            request: ping.as_bytes().unwrap(),
+            receiver: local.as_bytes().unwrap(),
+            payload,
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        KadMessage::Response(resp.as_bytes().unwrap())
+    }
+
+    #[test]
+    fn kad_responses_without_an_rpc_count_as_failures() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(1);
+        let local = kad.routing_table.local_info.clone();
+        kad.add_peer(peers[0].as_bytes().unwrap());
+        kad.ping_node(peers[0].clone());
+        let id = *kad.pending.keys().next().unwrap();
+
+        kad.handle_message(&peers[0].address, &ping_response(id, &local, vec![]));
+        assert!(kad.pending.is_empty());
+        assert_eq!(kad.failures(&peers[0].address), 1);
+        assert!(kad.routing_table.is_new(&peers[0]));
+    }
+
+    #[test]
+    fn kad_expired_requests_count_as_failures() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(1);
+        let bootstrap = peers[0].address;
+        kad.bootstrap(&[bootstrap]);
+        assert_eq!(kad.pending.len(), 1);
+
+        kad.expire_requests();
+        assert_eq!(kad.pending.len(), 1);
+        assert_eq!(kad.failures(&bootstrap), 0);
+
+        let timeout = Duration::from_nanos(REQ_TIMEOUT as u64) + Duration::from_secs(1);
+        kad.pending.values_mut().for_each(|req| {
+            req.sent = Instant::now().checked_sub(timeout).unwrap();
+        });
+        kad.expire_requests();
+        assert!(kad.pending.is_empty());
+        assert_eq!(kad.failures(&bootstrap), 1);
+    }
+
+    #[test]
+    fn kad_bootstrap_falls_back_to_live_seed() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(3);
+        let local = kad.routing_table.local_info.clone();
+        let (dead, live) = (peers[0].address, peers[1].address);
+        kad.bootstrap(&[dead, live]);
+        assert_eq!(kad.pending.len(), 2);
+        assert_eq!(kad.bootstrap_status(), BootstrapStatus::InProgress);
+
+        // The dead seed times out and is scheduled for a retry
+        let timeout = Purpose::Bootstrap.timeout() + Duration::from_secs(1);
+        kad.pending.values_mut().filter(|req| req.peer == dead).for_each(|req| {
+            req.sent = Instant::now().checked_sub(timeout).unwrap();
+        });
+        kad.expire_requests();
+        assert_eq!(kad.pending.len(), 1);
+        assert_eq!(kad.failures(&dead), 1);
+        assert_eq!(kad.bootstrap_status(), BootstrapStatus::InProgress);
+
+        // The live seed answers, completing the bootstrap and starting a self lookup
+        let id = *kad.pending.iter().find(|(_, req)| req.peer == live).unwrap().0;
+        kad.handle_message(&peers[1].address, &nodes_response(id, &local, &peers[1], &peers[1..3]));
+        assert_eq!(kad.bootstrap_status(), BootstrapStatus::Complete);
+        assert!(!kad.routing_table.is_new(&peers[2]));
+        assert!(kad.pending.values().any(|req| {
+            matches!(req.purpose, Purpose::Lookup(_)) && req.target == local.get_key()
+        }));
+    }
+
+    #[test]
+    fn bootstrap_backs_off_then_fails() {
+        let (_, _, peers) = setup(2);
+        let seeds: Vec<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+        let mut bootstrap = Bootstrap::new(&seeds);
+        let mut now = Instant::now();
+        let mut backoff = Duration::from_nanos(BOOTSTRAP_BACKOFF);
+
+        for _ in 0..BOOTSTRAP_RETRIES {
+            seeds.iter().for_each(|seed| bootstrap.failed(seed, now));
+            assert_eq!(bootstrap.status, BootstrapStatus::InProgress);
+            assert!(bootstrap.due(now).is_empty());
+            now += backoff;
+            assert_eq!(bootstrap.due(now), seeds);
+            backoff *= 2;
+        }
+
+        seeds.iter().for_each(|seed| bootstrap.failed(seed, now));
+        assert_eq!(bootstrap.status, BootstrapStatus::Failed);
+        assert!(bootstrap.due(now + backoff).is_empty());
+        assert_eq!(Bootstrap::new(&[]).status, BootstrapStatus::Failed);
+    }
+
+    fn request(sender: &PeerInfo, rpc: RPC) -> (MessageKey, KadMessage) {
+        let req = Req {
+            id: MessageKey::rand().inner(),
+            sender: sender.as_bytes().unwrap(),
+            payload: rpc.as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        (MessageKey::from_inner(req.id), KadMessage::Request(req.as_bytes().unwrap()))
+    }
+
+    fn request_rpc(message: &Message) -> RPC {
+        match KadMessage::from_bytes(&message.msg).unwrap() {
+            KadMessage::Request(req) => Req::from_bytes(&req).unwrap().to_components().2.unwrap(),
+            message => panic!("Expected a request, got {:?}", message),
+        }
+    }
+
+    fn response_rpc(message: &Message) -> RPC {
+        match KadMessage::from_bytes(&message.msg).unwrap() {
+            KadMessage::Response(resp) => Resp::from_bytes(&resp).unwrap().to_components().2.unwrap(),
+            _ => panic!("expected a response"),
+        }
+    }
+
+    #[test]
+    fn kad_stores_and_finds_values() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(4);
+        let local = kad.routing_table.local_info.clone();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let mut record = DhtRecord::new(Key::rand(), b"value".to_vec(), Some(peers[0].id.clone()));
+        record.expires = Some(u128::MAX);
+
+        // The record is stored, but never for longer than the local ttl
+        let store = RPC::Store(record.key.get_key(), record.as_bytes().unwrap());
+        kad.handle_message(&peers[0].address, &request(&peers[0], store).1);
+        let latest = timestamp_now() + MemoryStoreConfig::default().record_ttl;
+        let stored = kad.get_record(&record.key).unwrap();
+        assert_eq!(stored.value, record.value);
+        assert!(stored.expires.unwrap() <= latest);
+        let (addr, saved) = transport_rx.try_recv().unwrap();
+        assert_eq!(addr, peers[0].address);
+        assert!(matches!(response_rpc(&saved), RPC::Saved(key) if key == record.key.get_key()));
+
+        // A record sent under the wrong key is dropped
+        let mismatched = RPC::Store(Key::rand().get_key(), record.as_bytes().unwrap());
+        kad.handle_message(&peers[0].address, &request(&peers[0], mismatched).1);
+        assert!(transport_rx.try_recv().is_err());
+
+        let find = RPC::FindValue(record.key.get_key());
+        kad.handle_message(&peers[1].address, &request(&peers[1], find).1);
+        let (addr, value) = transport_rx.try_recv().unwrap();
+        assert_eq!(addr, peers[1].address);
+        match response_rpc(&value) {
+            RPC::Value(bytes) => assert_eq!(DhtRecord::from_bytes(&bytes).unwrap().value, record.value),
+            rpc => panic!("expected a value response, got {:?}", rpc),
+        }
+
+        // Unknown keys are answered with the closest peers
+        kad.handle_message(&peers[1].address, &request(&peers[1], RPC::FindValue(Key::rand().get_key())).1);
+        let (_, nodes) = transport_rx.try_recv().unwrap();
+        assert!(matches!(response_rpc(&nodes), RPC::Nodes(_)));
+
+        // A value lookup ends once a quorum of peers have returned the record
+        let remote = DhtRecord::new(Key::rand(), b"remote".to_vec(), Some(peers[2].id.clone()));
+        let id = kad.lookup_value(remote.key);
+        let mut answered = 0;
+        while kad.get_lookup(&id).is_some() {
+            let (req_id, req) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())).unwrap();
+            let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+            kad.handle_message(&responder.address, &value_response(req_id, &local, responder, &remote));
+            answered += 1;
+        }
+        assert_eq!(answered, crate::VALUE_QUORUM);
+        assert_eq!(kad.get_record(&remote.key).unwrap().value, remote.value);
+    }
+
+    fn value_response(id: MessageKey, requestor: &PeerInfo, responder: &PeerInfo, record: &DhtRecord) -> KadMessage {
+        let req = Req {
+            id: id.inner(),
+            sender: requestor.as_bytes().unwrap(),
+            payload: RPC::FindValue(record.key.get_key()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let resp = Resp {
+            request: req.as_bytes().unwrap(),
+            receiver: responder.as_bytes().unwrap(),
+            payload: RPC::Value(record.as_bytes().unwrap()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+
+        KadMessage::Response(resp.as_bytes().unwrap())
+    }
+
+    /// Accepts records whose value starts with a sequence number and picks the
+    /// version with the highest sequence number.
+    #[derive(Debug)]
+    struct SequenceValidator;
+
+    impl Validator for SequenceValidator {
+        fn validate(&self, record: &DhtRecord) -> Result<(), Box<dyn Error>> {
+            match record.value.first() {
+                Some(_) => Ok(()),
+                None => Err("missing sequence number".into()),
+            }
+        }
+
+        fn select(&self, _key: &Key, records: &[DhtRecord]) -> usize {
+            (0..records.len()).max_by_key(|index| records[*index].value[0]).unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn kad_validates_and_selects_records() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(4);
+        let local = kad.routing_table.local_info.clone();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        kad.add_validator("seq", Box::new(SequenceValidator));
+        let key = Key::rand();
+        let version = |seq: u8| DhtRecord::new(key, vec![seq], None).with_namespace("seq");
+        let store = |record: &DhtRecord| RPC::Store(key.get_key(), record.as_bytes().unwrap());
+
+        let invalid = DhtRecord::new(key, vec![], None).with_namespace("seq");
+        kad.handle_message(&peers[0].address, &request(&peers[0], store(&invalid)).1);
+        assert!(kad.get_record(&key).is_none());
+        assert!(transport_rx.try_recv().is_err());
+        assert!(kad.publish_record(invalid).is_err());
+
+        // An older version never replaces a newer one
+        kad.handle_message(&peers[0].address, &request(&peers[0], store(&version(2))).1);
+        kad.handle_message(&peers[1].address, &request(&peers[1], store(&version(1))).1);
+        assert_eq!(kad.get_record(&key).unwrap().value, vec![2]);
+
+        // A version in another namespace can't skip the stored namespace's validator
+        let unvalidated = DhtRecord::new(key, vec![], None);
+        kad.handle_message(&peers[1].address, &request(&peers[1], store(&unvalidated)).1);
+        let stored = kad.get_record(&key).unwrap();
+        assert_eq!((stored.namespace.as_str(), stored.value), ("seq", vec![2]));
+        transport_rx.try_iter().for_each(drop);
+
+        // A value lookup keeps the best version found and corrects peers holding older versions
+        let other = Key::rand();
+        let id = kad.lookup_value(other);
+        let mut returned = vec![];
+        for seq in [1, 3, 2] {
+            let (req_id, req) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())).unwrap();
+            let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+            let record = DhtRecord::new(other, vec![seq], None).with_namespace("seq");
+            kad.handle_message(&responder.address, &value_response(req_id, &local, responder, &record));
+            returned.push((responder.address, seq));
+        }
+        assert!(kad.get_lookup(&id).is_none());
+        assert_eq!(kad.get_record(&other).unwrap().value, vec![3]);
+
+        let mut corrected: Vec<SocketAddr> = transport_rx
+            .try_iter()
+            .filter(|(_, message)| match KadMessage::from_bytes(&message.msg) {
+                Some(KadMessage::Request(req)) => matches!(
+                    Req::from_bytes(&req).unwrap().to_components().2,
+                    Some(RPC::Store(key, value)) if key == other.get_key()
+                        && DhtRecord::from_bytes(&value).unwrap().value == vec![3]
+                ),
+                _ => false,
+            })
+            .map(|(address, _)| address)
+            .collect();
+        let mut outdated: Vec<SocketAddr> = returned
+            .iter()
+            .filter(|(_, seq)| *seq != 3)
+            .map(|(address, _)| *address)
+            .collect();
+        corrected.sort();
+        outdated.sort();
+        assert_eq!(corrected, outdated);
+    }
+
+    #[test]
+    fn kad_provides_and_finds_providers() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(6);
+        let local = kad.routing_table.local_info.clone();
+        let key = Key::rand();
+
+        let announce = RPC::AddProvider(key.get_key(), peers[0].as_bytes().unwrap());
+        kad.handle_message(&peers[0].address, &request(&peers[0], announce).1);
+        let spoofed = RPC::AddProvider(key.get_key(), peers[2].as_bytes().unwrap());
+        kad.handle_message(&peers[1].address, &request(&peers[1], spoofed).1);
+        assert_eq!(kad.providers(&key), vec![peers[0].clone()]);
+
+        kad.handle_message(&peers[1].address, &request(&peers[1], RPC::GetProviders(key.get_key())).1);
+        let (addr, resp) = transport_rx.try_recv().unwrap();
+        assert_eq!(addr, peers[1].address);
+        match response_rpc(&resp) {
+            RPC::Providers(_, providers, nodes) => {
+                assert_eq!(providers, vec![peers[0].as_bytes().unwrap()]);
+                assert!(!nodes.is_empty());
+            }
+            rpc => panic!("expected a providers response, got {:?}", rpc),
+        }
+
+        // Providing a key announces the local node to the closest peers found
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let provided = Key::rand();
+        kad.provide(provided).unwrap();
+        assert!(kad.providers(&provided).contains(&local));
+        while let Some((req_id, req)) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())) {
+            let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+            kad.handle_message(&responder.address, &nodes_response(req_id, &local, responder, &[]));
+        }
+        let announced = transport_rx
+            .try_iter()
+            .filter(|(_, message)| match KadMessage::from_bytes(&message.msg) {
+                Some(KadMessage::Request(req)) => matches!(
+                    Req::from_bytes(&req).unwrap().to_components().2,
+                    Some(RPC::AddProvider(key, _)) if key == provided.get_key()
+                ),
+                _ => false,
+            })
+            .count();
+        assert_eq!(announced, peers.len());
+
+        // A providers lookup ends as soon as a peer returns providers
+        let wanted = Key::rand();
+        let id = kad.find_providers(wanted);
+        let (req_id, req) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())).unwrap();
+        let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+        let get_req = Req {
+            id: req_id.inner(),
+            sender: local.as_bytes().unwrap(),
+            payload: RPC::GetProviders(wanted.get_key()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let resp = Resp {
+            request: get_req.as_bytes().unwrap(),
+            receiver: responder.as_bytes().unwrap(),
+            payload: RPC::Providers(wanted.get_key(), vec![peers[3].as_bytes().unwrap()], vec![])
+                .as_bytes()
+                .unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        kad.handle_message(&responder.address, &KadMessage::Response(resp.as_bytes().unwrap()));
+        assert_eq!(kad.providers(&wanted), vec![peers[3].clone()]);
+        assert!(kad.get_lookup(&id).is_none());
+    }
+
+    /// An in-process network of kademlia nodes. Messages that nodes send to the
+    /// transport are delivered straight to the node they are addressed to, and
+    /// requests to nodes that have left the network time out.
+    struct TestNet {
+        nodes: HashMap<SocketAddr, (Kademlia, Receiver<(SocketAddr, Message)>)>,
+        next_port: usize,
+    }
+
+    impl TestNet {
+        fn new() -> TestNet {
+            TestNet { nodes: HashMap::new(), next_port: 40000 }
+        }
+
+        fn join(&mut self, key: Key, seed: Option<SocketAddr>) -> SocketAddr {
+            let address: SocketAddr = format!("127.0.0.1:{}", self.next_port).parse().unwrap();
+            self.next_port += 1;
+            let info = PeerInfo::new(PeerId::from_key(&key), key, address);
+            let (to_transport_tx, to_transport_rx) = channel();
+            let (_, to_kad_rx) = channel();
+            let mut kad = Kademlia::new(
+                RoutingTable::new(info),
+                to_transport_tx,
+                to_kad_rx,
+                HashMap::new(),
+                Duration::from_secs(20),
+                Instant::now(),
+            );
+            if let Some(seed) = seed {
+                kad.bootstrap(&[seed]);
+            }
+            self.nodes.insert(address, (kad, to_transport_rx));
+            self.route();
+            address
+        }
+
+        fn leave(&mut self, address: &SocketAddr) {
+            self.nodes.remove(address);
+        }
+
+        fn node(&mut self, address: &SocketAddr) -> &mut Kademlia {
+            &mut self.nodes.get_mut(address).unwrap().0
+        }
+
+        /// Pings every peer in every routing table, evicting the nodes that have left
+        fn check_peers(&mut self) {
+            self.nodes.values_mut().for_each(|(kad, _)| {
+                kad.routing_table.get_all_peers().into_iter().for_each(|peer| kad.ping_node(peer));
+            });
+            self.route();
+        }
+
+        fn holders(&self, key: &Key) -> Vec<SocketAddr> {
+            self.nodes
+                .iter()
+                .filter(|(_, (kad, _))| kad.get_record(key).is_some())
+                .map(|(address, _)| *address)
+                .collect()
+        }
+
+        /// Delivers messages until none are left, timing out requests to nodes
+        /// that are no longer in the network whenever it goes quiet.
+        fn route(&mut self) {
+            loop {
+                let outgoing: Vec<(SocketAddr, SocketAddr, Message)> = self
+                    .nodes
+                    .iter()
+                    .flat_map(|(src, (_, transport_rx))| {
+                        transport_rx.try_iter().map(|(address, message)| (*src, address, message)).collect::<Vec<_>>()
+                    })
+                    .collect();
+
+                if outgoing.is_empty() {
+                    let live: Vec<SocketAddr> = self.nodes.keys().copied().collect();
+                    let mut expired = false;
+                    self.nodes.values_mut().for_each(|(kad, _)| {
+                        kad.pending.values_mut().filter(|req| !live.contains(&req.peer)).for_each(|req| {
+                            let timeout = req.purpose.timeout() + Duration::from_secs(1);
+                            req.sent = Instant::now().checked_sub(timeout).unwrap();
+                            expired = true;
+                        });
+                        kad.expire_requests();
+                    });
+                    if !expired {
+                        return;
+                    }
+                    continue;
+                }
+
+                outgoing.into_iter().for_each(|(src, address, message)| {
+                    if let Some((kad, _)) = self.nodes.get_mut(&address) {
+                        kad.handle_message(&src, &KadMessage::from_bytes(&message.msg).unwrap());
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn kad_records_survive_churn() {
+        let mut net = TestNet::new();
+        let seed = net.join(Key::rand(), None);
+        let addresses: Vec<SocketAddr> = (0..20).map(|_| net.join(Key::rand(), Some(seed))).collect();
+        let publisher = addresses[0];
+        let key = Key::rand();
+        net.node(&publisher).put_record(key, b"value".to_vec()).unwrap();
+        net.route();
+        let holders = net.holders(&key);
+        assert!(holders.len() > crate::DEFAULT_N_PEERS / 2);
+
+        // Holders never keep the record for longer than their own ttl
+        let latest = timestamp_now() + MemoryStoreConfig::default().record_ttl;
+        assert!(holders
+            .iter()
+            .all(|holder| net.node(holder).get_record(&key).unwrap().expires.is_some_and(|expires| expires <= latest)));
+
+        // Every holder but the publisher leaves, and republishing stores the
+        // record on the closest of the peers that remain
+        holders.iter().filter(|holder| **holder != publisher).for_each(|holder| net.leave(holder));
+        assert_eq!(net.holders(&key), vec![publisher]);
+        net.node(&publisher).republish_records();
+        net.route();
+        assert!(net.holders(&key).len() > crate::DEFAULT_N_PEERS / 2);
+
+        // A node joining next to the key is sent the record by its holders,
+        // once the nodes that left have been evicted from routing tables
+        net.check_peers();
+        let mut close = key.get_key();
+        close[31] ^= 1;
+        let newcomer = net.join(Key::new(close), Some(publisher));
+        assert!(net.node(&newcomer).get_record(&key).is_some());
+
+        // Once the publisher has left too, a new node can still find the record
+        net.leave(&publisher);
+        let late = net.join(Key::rand(), Some(newcomer));
+        net.node(&late).lookup_value(key);
+        net.route();
+        assert_eq!(net.node(&late).get_record(&key).unwrap().value, b"value".to_vec());
+    }
+
+    #[test]
+    fn kad_only_republishes_its_own_records() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(4);
+        let local = kad.routing_table.local_info.clone();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+
+        // A remote record that claims the local node as its publisher is stored
+        // without a publisher and isn't republished
+        let forged = DhtRecord::new(Key::rand(), b"forged".to_vec(), Some(local.id.clone()));
+        let store = RPC::Store(forged.key.get_key(), forged.as_bytes().unwrap());
+        kad.handle_message(&peers[0].address, &request(&peers[0], store).1);
+        let stored = kad.get_record(&forged.key).unwrap();
+        assert_eq!(stored.publisher, None);
+        transport_rx.try_iter().for_each(drop);
+        kad.republish_records();
+        assert!(kad.pending.is_empty());
+        assert!(transport_rx.try_recv().is_err());
+        assert_eq!(kad.get_record(&forged.key).unwrap().expires, stored.expires);
+
+        // A record put by the local node is republished
+        let key = Key::rand();
+        kad.put_record(key, b"value".to_vec()).unwrap();
+        kad.pending.clear();
+        transport_rx.try_iter().for_each(drop);
+        kad.republish_records();
+        assert!(!kad.pending.is_empty());
+        assert!(kad.pending.values().all(|req| peers.iter().any(|peer| peer.address == req.peer)));
+        assert_eq!(kad.get_record(&key).unwrap().publisher, Some(local.id));
+    }
+
+    #[test]
+    fn kad_full_bucket_evicts_unresponsive_lru() {
+        let (mut kad, _transport_rx, _kad_tx, _) = setup_kad(0);
+        let local = kad.routing_table.local_info.clone();
+
+        // Collect enough peers to overflow the bucket furthest from the local node
+        let (_, _, peers) = setup(400);
+        let far: Vec<PeerInfo> = peers
+            .into_iter()
+            .filter(|peer| local.get_key().xor(peer.get_key()).leading_zeros() == 0)
+            .take(crate::MAX_BUCKET_LEN + 1)
+            .collect();
+        assert_eq!(far.len(), crate::MAX_BUCKET_LEN + 1);
+
+        far[..crate::MAX_BUCKET_LEN].iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        assert!(kad.pending.is_empty());
+
+        let newcomer = far[crate::MAX_BUCKET_LEN].clone();
+        kad.add_peer(newcomer.as_bytes().unwrap());
+        assert!(kad.routing_table.is_new(&newcomer));
+        let ping = kad.pending.values().next().unwrap().clone();
+        assert_eq!(ping.peer, far[0].address);
+
+        let timeout = Duration::from_nanos(REQ_TIMEOUT as u64) + Duration::from_secs(1);
+        kad.pending.values_mut().for_each(|req| {
+            req.sent = Instant::now().checked_sub(timeout).unwrap();
+        });
+        kad.expire_requests();
+        assert!(kad.routing_table.is_new(&far[0]));
+        assert!(!kad.routing_table.is_new(&newcomer));
+    }
+
+    #[test]
+    fn kad_rejects_pongs_for_another_peer() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(2);
+        let local = kad.routing_table.local_info.clone();
+        kad.add_peer(peers[0].as_bytes().unwrap());
+
+        // A pong naming another peer doesn't add it, and the pinged peer is evicted
+        kad.ping_node(peers[0].clone());
+        let id = *kad.pending.keys().next().unwrap();
+        let pong = RPC::Pong(peers[1].as_bytes().unwrap()).as_bytes().unwrap();
+        kad.handle_message(&peers[0].address, &ping_response(id, &local, pong));
+        assert!(kad.routing_table.is_new(&peers[1]));
+        assert!(kad.routing_table.is_new(&peers[0]));
+        assert_eq!(kad.failures(&peers[0].address), 1);
+
+        // As is a pong with the pinged peer's key but another address
+        kad.add_peer(peers[0].as_bytes().unwrap());
+        kad.ping_node(peers[0].clone());
+        let id = *kad.pending.keys().next().unwrap();
+        let mut moved = peers[0].clone();
+        moved.address = peers[1].address;
+        let pong = RPC::Pong(moved.as_bytes().unwrap()).as_bytes().unwrap();
+        kad.handle_message(&peers[0].address, &ping_response(id, &local, pong));
+        assert!(kad.routing_table.is_new(&peers[0]));
+        assert!(kad.pending.is_empty());
+    }
+
+    #[test]
+    fn kad_publishes_discovery_events() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(4);
+        let local = kad.routing_table.local_info.clone();
+        let events = kad.events().subscribe();
+
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        kad.add_peer(peers[0].as_bytes().unwrap());
+        let discovered: Vec<SocketAddr> = events
+            .try_iter()
+            .map(|event| match event {
+                Event::PeerDiscovered(peer) => peer.address,
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(discovered, peers.iter().map(|peer| peer.address).collect::<Vec<_>>());
+
+        let id = kad.lookup(local.get_key());
+        while let Some(req_id) = kad.pending.keys().next().copied() {
+            let req = kad.pending[&req_id].clone();
+            let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+            kad.handle_message(&responder.address, &nodes_response(req_id, &local, responder, &[]));
+        }
+        match events.try_recv() {
+            Ok(Event::LookupCompleted(lookup, target, closest)) => {
+                assert_eq!(lookup, id);
+                assert_eq!(target, local.get_key());
+                assert_eq!(closest.len(), peers.len());
+            }
+            event => panic!("Expected a completed lookup, got {:?}", event),
+        }
+
+        kad.ping_node(peers[0].clone());
+        let timeout = Duration::from_nanos(REQ_TIMEOUT as u64) + Duration::from_secs(1);
+        kad.pending.values_mut().for_each(|req| {
+            req.sent = Instant::now().checked_sub(timeout).unwrap();
+        });
+        kad.expire_requests();
+        match events.try_recv() {
+            Ok(Event::PeerEvicted(peer)) => assert_eq!(peer.address, peers[0].address),
+            event => panic!("Expected an evicted peer, got {:?}", event),
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn kad_lookup_queries_closest_peers_until_complete() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(20);
+        let local = kad.routing_table.local_info.clone();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+
+        let (_, target, _) = setup(0);
+        let id = kad.lookup(target.get_key());
+        assert_eq!(kad.pending.len(), crate::MAX_ACTIVE_RPCS);
+        assert!(kad.pending.values().all(|req| req.purpose == Purpose::Lookup(id)));
+
+        let mut queried = vec![];
+        while let Some((req_id, req)) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())) {
+            assert!(kad.pending.len() <= crate::MAX_ACTIVE_RPCS);
+            queried.push(req.peer);
+            let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+            kad.handle_message(&responder.address, &nodes_response(req_id, &local, responder, &[]));
+        }
+
+        let mut expected = peers.clone();
+        expected.sort_by_key(|peer| peer.get_key().xor(target.get_key()));
+        let expected: Vec<SocketAddr> = expected.iter().take(crate::DEFAULT_N_PEERS).map(|peer| peer.address).collect();
+        queried.sort();
+        let mut sorted_expected = expected.clone();
+        sorted_expected.sort();
+        assert_eq!(queried, sorted_expected);
+        assert!(kad.get_lookup(&id).is_none());
+    }
+
+    #[test]
+    fn kad_refreshes_stale_buckets() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(90);
+        let local = kad.routing_table.local_info.clone();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        transport_rx.try_iter().for_each(drop);
+        assert!(kad.routing_table.size() > 2);
+        assert!(kad.routing_table.get_stale_indices().is_empty());
+
+        let index = 1;
+        kad.routing_table.buckets[index].last_updated -= crate::REFRESH_INTEVAL + 1;
+        assert_eq!(kad.routing_table.get_stale_indices(), vec![index]);
+        let before = timestamp_now();
+        kad.refresh_buckets();
+
+        // The bucket is touched, and a lookup starts for a key in its range
+        assert!(kad.routing_table.buckets[index].last_updated >= before);
+        assert!(kad.routing_table.get_stale_indices().is_empty());
+        let targets: HashSet<Key> = transport_rx
+            .try_iter()
+            .map(|(_, message)| match request_rpc(&message) {
+                RPC::FindNode(target) => PeerInfo::from_bytes(&target).unwrap().get_key(),
+                rpc => panic!("Expected a find node request, got {:?}", rpc),
+            })
+            .collect();
+        assert_eq!(targets.len(), 1);
+        assert!(targets.iter().all(|target| local.get_key().xor(*target).leading_zeros() == index));
+    }
+
+    #[test]
+    fn kad_kill_saves_snapshot_and_stops() {
+        let (mut kad, transport_rx, kad_tx, peers) = setup_kad(3);
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let path = std::env::temp_dir().join(format!("udp2p-kill-{}.json", hex::encode(MessageKey::rand().inner())));
+        kad.set_snapshot_path(path.clone());
+        kad.lookup(Key::rand());
+        assert!(!kad.pending.is_empty());
+
+        let local = kad.routing_table.local_info.address;
+        kad.handle_message(&local, &KadMessage::Kill);
+        assert!(kad.is_stopped());
+        assert!(kad.pending.is_empty());
+        assert_eq!(RoutingTable::load(&path).unwrap().len(), 3);
+        std::fs::remove_file(&path).unwrap();
+
+        // Every peer is told the local node is leaving
+        let mut told: Vec<SocketAddr> = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(request_rpc(message), RPC::Leave))
+            .map(|(address, _)| address)
+            .collect();
+        told.sort();
+        let mut expected: Vec<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+        expected.sort();
+        assert_eq!(told, expected);
+
+        // Requests are no longer answered
+        let (_, ping) = request(&peers[0], RPC::Ping);
+        kad_tx.send((peers[0].address, ping)).unwrap();
+        kad.recv();
+        assert!(transport_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn kad_leave_removes_peer_and_fails_its_requests() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(12);
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let events = kad.events().subscribe();
+        let id = kad.lookup(Key::rand());
+        let (leaving, waiting) = {
+            let req = kad.pending.values().next().unwrap();
+            (peers.iter().find(|peer| peer.address == req.peer).unwrap().clone(), kad.pending.len())
+        };
+
+        let (_, leave) = request(&leaving, RPC::Leave);
+        kad.handle_message(&leaving.address, &leave);
+        assert!(kad.routing_table.is_new(&leaving));
+        assert_eq!(kad.failures(&leaving.address), 0);
+        match events.try_recv() {
+            Ok(Event::PeerEvicted(peer)) => assert_eq!(peer.address, leaving.address),
+            event => panic!("Expected an evicted peer, got {:?}", event),
+        }
+
+        // The lookup moves on to another peer straight away
+        assert!(kad.pending.values().all(|req| req.peer != leaving.address));
+        assert_eq!(kad.pending.len(), waiting);
+        assert!(!kad.get_lookup(&id).unwrap().closest.contains(&leaving));
+    }
+
+    #[test]
+    fn kad_fails_lookup_queries_answered_with_another_rpc() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(1);
+        kad.add_peer(peers[0].as_bytes().unwrap());
+        let local = kad.routing_table.local_info.clone();
+        let events = kad.events().subscribe();
+        let id = kad.lookup(Key::rand());
+        let req_id = *kad.pending.keys().next().unwrap();
+
+        let find = Req {
+            id: req_id.inner(),
+            sender: local.as_bytes().unwrap(),
+            payload: RPC::FindNode(local.as_bytes().unwrap()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let pong = Resp {
+            request: find.as_bytes().unwrap(),
+            receiver: peers[0].as_bytes().unwrap(),
+            payload: RPC::Pong(peers[0].as_bytes().unwrap()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        kad.handle_message(&peers[0].address, &KadMessage::Response(pong.as_bytes().unwrap()));
+
+        // The query fails, so the lookup finishes rather than waiting forever
+        assert!(kad.get_lookup(&id).is_none());
+        assert_eq!(kad.failures(&peers[0].address), 1);
+        assert!(matches!(events.try_recv(), Ok(Event::LookupCompleted(lookup, _, _)) if lookup == id));
+    }
+
+    #[test]
+    fn kad_ignores_forged_leaves() {
+        let (mut kad, _transport_rx, _kad_tx, peers) = setup_kad(2);
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let events = kad.events().subscribe();
+
+        // A LEAVE claiming to be from another peer doesn't evict it
+        let (_, forged) = request(&peers[0], RPC::Leave);
+        kad.handle_message(&peers[1].address, &forged);
+        assert!(!kad.routing_table.is_new(&peers[0]));
+        assert!(!kad.routing_table.is_new(&peers[1]));
+        assert!(events.try_recv().is_err());
+
+        // The peer itself can still leave
+        kad.handle_message(&peers[0].address, &forged);
+        assert!(kad.routing_table.is_new(&peers[0]));
+    }
+
+    #[test]
+    fn kad_scores_peers_and_bans_misbehaving_ones() {
+        let (mut kad, transport_rx, kad_tx, peers) = setup_kad(3);
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+        let events = kad.events().subscribe();
+
+        // Deliveries reported on the event bus are scored
+        kad.events().publish(Event::MessageDelivered(peers[0].address));
+        kad.events().publish(Event::DeliveryFailed(peers[1].address));
+        kad.recv();
+        events.try_iter().for_each(drop);
+        assert!(kad.scores().score(&peers[0].address) > 0.0);
+        assert!(kad.scores().score(&peers[1].address) < 0.0);
+        assert!(kad.scores().accepts_gossip(&peers[1].address));
+
+        // Scores decay away over time, including the penalty for being slow
+        let slow: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+        kad.scores_mut().latency(slow, Duration::from_secs(2));
+        kad.scores_mut().decay();
+        assert!(kad.scores().score(&slow) < 0.0);
+        (0..100).for_each(|_| kad.scores_mut().decay());
+        assert_eq!(kad.scores().score(&peers[1].address), 0.0);
+        assert_eq!(kad.scores().score(&slow), 0.0);
+
+        // Peers that keep sending invalid messages are banned
+        (0..3).for_each(|_| kad.scores_mut().invalid(peers[2].address));
+        assert!(!kad.scores().accepts_gossip(&peers[2].address));
+        kad.recv();
+        assert!(kad.scores().is_banned(&peers[2].address));
+        assert!(kad.routing_table.is_new(&peers[2]));
+        match events.try_recv() {
+            Ok(Event::PeerEvicted(peer)) => assert_eq!(peer.address, peers[2].address),
+            event => panic!("Expected an evicted peer, got {:?}", event),
+        }
+        assert!(matches!(events.try_recv(), Ok(Event::PeerBanned(address)) if address == peers[2].address));
+
+        // Banned peers aren't added back or answered
+        kad.add_peer(peers[2].as_bytes().unwrap());
+        assert!(kad.routing_table.is_new(&peers[2]));
+        transport_rx.try_iter().for_each(drop);
+        let (_, ping) = request(&peers[2], RPC::Ping);
+        kad_tx.send((peers[2].address, ping)).unwrap();
+        kad.recv();
+        assert!(transport_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn kad_refuses_peers_from_other_networks() {
+        let (mut kad, transport_rx, _kad_tx, peers) = setup_kad(2);
+        kad.set_protocol_id("testnet");
+        let local = kad.routing_table.local_info.clone();
+        let events = kad.events().subscribe();
+
+        // Requests from other networks aren't answered and their senders aren't added
+        let (_, ping) = request(&peers[0], RPC::Ping);
+        kad.handle_message(&peers[0].address, &ping);
+        assert!(transport_rx.try_recv().is_err());
+        assert!(kad.routing_table.is_new(&peers[0]));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::ProtocolMismatch(address, protocol)) if address == peers[0].address && protocol == DEFAULT_PROTOCOL_ID
+        ));
+
+        // Responses from other networks are treated as if the peer never answered
+        kad.bootstrap(&[peers[1].address]);
+        let req_id = *kad.pending.keys().next().unwrap();
+        kad.handle_message(&peers[1].address, &nodes_response(req_id, &local, &peers[1], &peers[..1]));
+        assert!(kad.pending.is_empty());
+        assert!(kad.routing_table.is_new(&peers[0]));
+        assert!(kad.routing_table.is_new(&peers[1]));
+        assert_eq!(kad.failures(&peers[1].address), 1);
+        assert!(matches!(events.try_recv(), Ok(Event::ProtocolMismatch(address, _)) if address == peers[1].address));
+    }
+
+    #[test]
+    fn kad_warm_start_validates_snapshot_peers() {
+        let (mut rt, _, peers) = setup(20);
+        peers.iter().for_each(|peer| {
+            rt.update_peer(peer);
+        });
+        let path = std::env::temp_dir().join(format!("udp2p-snapshot-{}.json", hex::encode(MessageKey::rand().inner())));
+        rt.save(&path).unwrap();
+        assert_eq!(RoutingTable::load(&path).unwrap().len(), 20);
+
+        let (mut kad, _transport_rx, _kad_tx, _) = setup_kad(0);
+        let local = kad.routing_table.local_info.clone();
+        assert_eq!(kad.warm_start(&path).unwrap(), 20);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(kad.pending.len(), 20);
+        assert!(peers.iter().all(|peer| kad.routing_table.is_new(peer)));
+
+        let (req_id, req) = kad.pending.iter().next().map(|(k, v)| (*k, v.clone())).unwrap();
+        let responder = peers.iter().find(|peer| peer.address == req.peer).unwrap();
+        let ping = Req {
+            id: req_id.inner(),
+            sender: local.as_bytes().unwrap(),
+            payload: RPC::Ping.as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        let pong = Resp {
+            request: ping.as_bytes().unwrap(),
+            receiver: local.as_bytes().unwrap(),
+            payload: RPC::Pong(responder.as_bytes().unwrap()).as_bytes().unwrap(),
+            protocol: DEFAULT_PROTOCOL_ID.to_string(),
+        };
+        kad.handle_message(&responder.address, &KadMessage::Response(pong.as_bytes().unwrap()));
+        assert!(!kad.routing_table.is_new(responder));
+        assert_eq!(kad.routing_table.total_peers(), 2);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::{DEFAULT_N_PEERS, LOOKUP_TIMEOUT};
+use udp2p_record::record::DhtRecord;
+use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use udp2p_utils::utils::Distance;
+
+/// What an iterative lookup is looking for. Nodes lookups find the closest
+/// peers to the target, Value lookups find the record stored under the target,
+/// Providers lookups find the peers providing the value of the target,
+/// AddProvider lookups find the closest peers to the target and then announce
+/// the local node to them as a provider of the target, and Store lookups find
+/// the closest peers to the target and then send them the record stored under it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupKind {
+    Nodes,
+    Value,
+    Providers,
+    AddProvider,
+    Store,
+}
+
+/// The state of an iterative node lookup. Maintains the DEFAULT_N_PEERS
+/// closest peers to the target heard of so far, sorted by XOR distance,
+/// the further peers heard of, which replace any of the closest that fail,
+/// the addresses that have already been queried, the addresses that failed
+/// to answer, which are never counted among the closest, and the number of
+/// queries that are still waiting on a response. The lookup is complete
+/// once every one of the closest peers has been queried and answered
+/// (or timed out). Value and Providers lookups query peers for the record or
+/// the providers of the target rather than for its closest peers. Providers
+/// lookups finish early once a peer returns any providers, and Value lookups
+/// collect the versions of the record returned by each peer and finish early
+/// once VALUE_QUORUM versions have been found. Lookups that are still running
+/// LOOKUP_TIMEOUT after they started are finished with the peers found so far.
+#[derive(Clone, Debug)]
+pub struct Lookup {
+    pub target: Key,
+    pub closest: Vec<PeerInfo>,
+    pub reserve: Vec<PeerInfo>,
+    pub queried: HashSet<SocketAddr>,
+    pub failed: HashSet<SocketAddr>,
+    pub in_flight: usize,
+    pub kind: LookupKind,
+    pub records: Vec<(SocketAddr, DhtRecord)>,
+    pub started: Instant,
+}
+
+impl Lookup {
+    /// Creates a new lookup for a target key
+    ///
+    /// # Arguments
+    ///
+    /// * target - the key being looked up
+    /// * seeds - the closest peers to the target in the local routing table
+    /// * kind - what the lookup is looking for
+    pub fn new(target: Key, seeds: Vec<PeerInfo>, kind: LookupKind) -> Lookup {
+        let mut lookup = Lookup {
+            target,
+            closest: vec![],
+            reserve: vec![],
+            queried: HashSet::new(),
+            failed: HashSet::new(),
+            in_flight: 0,
+            kind,
+            records: vec![],
+            started: Instant::now(),
+        };
+        lookup.insert(seeds);
+        lookup
+    }
+
+    /// Merges newly learned peers into the closest peers, keeping the
+    /// DEFAULT_N_PEERS closest to the target and holding the rest in reserve.
+    ///
+    /// # Arguments
+    ///
+    /// * peers - the peers returned by a queried node
+    pub fn insert(&mut self, peers: Vec<PeerInfo>) {
+        let mut known: Vec<PeerInfo> = self.closest.drain(..).chain(self.reserve.drain(..)).collect();
+        peers.into_iter().for_each(|peer| {
+            if !known.contains(&peer) && !self.failed.contains(&peer.address) {
+                known.push(peer);
+            }
+        });
+        let target = self.target;
+        known.sort_by_key(|peer| peer.get_key().xor(target));
+        self.reserve = known.split_off(DEFAULT_N_PEERS.min(known.len()));
+        self.closest = known;
+    }
+
+    /// Returns the closest peers that haven't been queried yet, up to
+    /// `parallelism` queries in flight, and marks them as queried.
+    ///
+    /// # Arguments
+    ///
+    /// * parallelism - the maximum number of queries to have in flight at once
+    pub fn next_peers(&mut self, parallelism: usize) -> Vec<PeerInfo> {
+        let available = parallelism.saturating_sub(self.in_flight);
+        let next: Vec<PeerInfo> = self
+            .closest
+            .iter()
+            .filter(|peer| !self.queried.contains(&peer.address))
+            .take(available)
+            .cloned()
+            .collect();
+
+        next.iter().for_each(|peer| {
+            self.queried.insert(peer.address);
+        });
+        self.in_flight += next.len();
+        next
+    }
+
+    /// Records that a query has been answered or has timed out
+    pub fn query_finished(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    /// Records that a query has timed out, replacing the peer in the closest peers
+    /// with the closest peer held in reserve
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer that failed to answer
+    pub fn query_failed(&mut self, peer: &SocketAddr) {
+        self.query_finished();
+        self.failed.insert(*peer);
+        self.closest.retain(|closest| closest.address != *peer);
+        self.insert(vec![]);
+    }
+
+    /// Checks if the lookup has been running for longer than LOOKUP_TIMEOUT
+    /// and returns true or false
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed() > Duration::from_nanos(LOOKUP_TIMEOUT)
+    }
+
+    /// Checks if every one of the closest peers has been queried and answered
+    /// and returns true or false
+    pub fn is_complete(&self) -> bool {
+        self.in_flight == 0
+            && self
+                .closest
+                .iter()
+                .all(|peer| self.queried.contains(&peer.address))
+    }
+}
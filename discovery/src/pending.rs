@@ -0,0 +1,64 @@
+use crate::{BOOTSTRAP_TIMEOUT, REQ_TIMEOUT};
+use udp2p_node::peer_key::Key;
+use udp2p_protocol::protocol::MessageKey;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The reason an outgoing request was sent. Used to route the
+/// matching response, or the timeout if no response arrives, back to
+/// the correct handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    Bootstrap,
+    Ping,
+    Lookup(MessageKey),
+    Store(Key),
+}
+
+impl Purpose {
+    /// Returns how long a request sent for this purpose is waited on before
+    /// it times out. Bootstrap requests use a shorter timeout so that dead
+    /// seeds are retried, or given up on, quickly.
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Purpose::Bootstrap => Duration::from_nanos(BOOTSTRAP_TIMEOUT),
+            _ => Duration::from_nanos(REQ_TIMEOUT as u64),
+        }
+    }
+}
+
+/// An outgoing request that is waiting on a response. Contains the
+/// address the request was sent to, the key the request is about
+/// (the node being looked up, or the peer being pinged), the purpose
+/// of the request and the instant it was sent.
+#[derive(Clone, Debug)]
+pub struct PendingRequest {
+    pub peer: SocketAddr,
+    pub target: Key,
+    pub purpose: Purpose,
+    pub sent: Instant,
+}
+
+impl PendingRequest {
+    /// Creates a new pending request with a start time of now
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address the request was sent to
+    /// * target - the key the request is about
+    /// * purpose - the reason the request was sent
+    pub fn new(peer: SocketAddr, target: Key, purpose: Purpose) -> PendingRequest {
+        PendingRequest {
+            peer,
+            target,
+            purpose,
+            sent: Instant::now(),
+        }
+    }
+
+    /// Checks if the request has gone unanswered for longer than the timeout
+    /// for its purpose and returns true or false
+    pub fn is_expired(&self) -> bool {
+        self.sent.elapsed() > self.purpose.timeout()
+    }
+}
@@ -1,4 +1,3 @@
-use crate::KAD_MESSAGE_LEN;
 use udp2p_node::peer_info::PeerInfo;
 use udp2p_node::peer_key::Key;
 use udp2p_protocol::protocol::{
@@ -24,36 +23,47 @@ impl_ByteRep!(for RPC, Req, Resp);
 
 
 /// RPC is an enum of the different types of remote procedure calls that a
-/// kademlia instance may receive from or send to peers in the network
+/// kademlia instance may receive from or send to peers in the network.
+/// The Value in Store and Value RPCs is the byte representation of a DhtRecord.
+/// Providers responses contain the known providers of the key followed by the
+/// closest peers to the key. Leave is sent by a peer that is shutting down,
+/// and isn't answered.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RPC {
     Ping,
     NewPeer(Peer),
     Store(StoreKey, Value),
     FindNode(Peer),
-    FindValue(Value),
+    FindValue(StoreKey),
     Nodes(Nodes),
     Value(Value),
     Saved(StoreKey),
     Pong(Peer),
+    AddProvider(StoreKey, Peer),
+    GetProviders(StoreKey),
+    Providers(StoreKey, Nodes, Nodes),
+    Leave,
 }
 
-/// A struct that contains an RPC request, the sender of the request
-/// and an ID for the request.
+/// A struct that contains an RPC request, the sender of the request,
+/// an ID for the request and the protocol id of the sender's network.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Req {
     pub id: InnerKey,
     pub sender: Peer,
     pub payload: RPCBytes,
+    pub protocol: String,
 }
 
 /// A struct that contains an RPC response, the original request that
-/// we are responding to and the original receiver of the request.
+/// we are responding to, the original receiver of the request and the
+/// protocol id of the receiver's network.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Resp {
     pub request: RequestBytes,
     pub receiver: Peer,
     pub payload: RPCBytes,
+    pub protocol: String,
 }
 
 impl Req {
@@ -1,10 +1,14 @@
-use crate::{MAX_BUCKETS, MAX_BUCKET_LEN, REFRESH_INTEVAL};
+use crate::{KEY_BITS, MAX_BUCKET_LEN, REFRESH_INTEVAL};
 use udp2p_node::peer_info::PeerInfo;
 use udp2p_node::peer_key::Key;
 use udp2p_utils::utils::Distance;
 use std::hash::Hash;
 use std::{cmp, mem};
-use udp2p_utils::utils::{timestamp_now};
+use udp2p_utils::utils::{timestamp_now, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::collections::{BTreeMap, HashMap};
 use ritelinked::LinkedHashMap;
 use udp2p_node::peer_id::PeerId;
@@ -13,26 +17,52 @@ use udp2p_protocol::protocol::InnerKey;
 
 /// The derivative data type used to maintain clusters of peers
 /// in the routing table with the same xor prefix to the local peer.
+/// Each peer is stored alongside the last time it was seen. Peers that
+/// are discovered while the bucket is full are held in a replacement
+/// cache until one of the bucket's peers is evicted.
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct KBucket {
-    nodes: LinkedHashMap<PeerId, PeerInfo>,
-    last_updated: u128,
+    nodes: LinkedHashMap<PeerId, (PeerInfo, Timestamp)>,
+    replacements: LinkedHashMap<PeerId, PeerInfo>,
+    pub(crate) last_updated: u128,
 }
 
-/// The core data structure which maintains a HahsMap of xor prefix -> kbucket
+/// The core data structure which maintains a binary trie of kbuckets
 /// used for peer lookups and discovery, also contains local info to measure
 /// distance of incoming peers and requested lookups.
+///
+/// Only the branch of the trie containing the local node is ever split, so
+/// the trie is stored as a vector where the bucket at index `i` holds the peers
+/// whose xor distance to the local node has exactly `i` leading zero bits. The
+/// last bucket is the unsplit leaf containing the local node and holds every
+/// peer with at least that many leading zero bits.
 #[derive(Debug, Clone)]
 pub struct RoutingTable {
-    pub tree: HashMap<String, KBucket>,
+    pub buckets: Vec<KBucket>,
     pub local_info: PeerInfo,
 }
 
+/// A snapshot of the peers in a routing table and the last time each was
+/// seen, written to disk so that a restarted node can reconnect to the
+/// network without a bootstrap node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub peers: Vec<(PeerInfo, Timestamp)>,
+    pub saved: Timestamp,
+}
+
+impl Default for KBucket {
+    fn default() -> Self {
+        KBucket::new()
+    }
+}
+
 impl KBucket {
     /// Creates a new KBucket with a "last update" of now, and a capacity of MAX_BUCKET_LEN.
     pub fn new() -> Self {
         KBucket {
             nodes: LinkedHashMap::with_capacity(MAX_BUCKET_LEN),
+            replacements: LinkedHashMap::with_capacity(MAX_BUCKET_LEN),
             last_updated: timestamp_now(),
         }
     }
@@ -48,7 +78,57 @@ impl KBucket {
     /// * peer - the peer to be inserted into the kbucket
     pub fn upsert(&mut self, peer: &PeerInfo) {
         self.last_updated = timestamp_now();
-        self.nodes.insert(peer.id.clone(), peer.clone());    
+        self.replacements.remove(&peer.id);
+        self.nodes.insert(peer.id.clone(), (peer.clone(), self.last_updated));
+    }
+
+    /// Inserts a peer into the bucket's replacement cache, if the cache is
+    /// full the oldest replacement is dropped to make room.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer to hold as a replacement
+    pub fn add_replacement(&mut self, peer: &PeerInfo) {
+        self.replacements.insert(peer.id.clone(), peer.clone());
+        if self.replacements.len() > MAX_BUCKET_LEN {
+            self.replacements.pop_front();
+        }
+    }
+
+    /// Returns the least recently seen peer in the bucket without removing it
+    pub fn lru(&self) -> Option<PeerInfo> {
+        self.nodes.front().map(|(_, (peer, _))| peer.clone())
+    }
+
+    /// Removes a peer from the bucket and promotes the most recently
+    /// discovered replacement in its place. Returns the replacement if one existed.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer to evict
+    pub fn evict(&mut self, peer: &PeerInfo) -> Option<PeerInfo> {
+        self.nodes.remove(&peer.id)?;
+        let (_, replacement) = self.replacements.pop_back()?;
+        self.upsert(&replacement);
+        Some(replacement)
+    }
+
+    /// Removes a peer from the bucket's replacement cache, if it exists.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer to remove
+    pub fn remove_replacement(&mut self, peer: &PeerInfo) -> Option<PeerInfo> {
+        self.replacements.remove(&peer.id)
+    }
+
+    /// Checks if the bucket's replacement cache contains the peer and returns true or false
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer to check
+    pub fn is_replacement(&self, peer: &PeerInfo) -> bool {
+        self.replacements.contains_key(&peer.id)
     }
 
 
@@ -61,21 +141,41 @@ impl KBucket {
         self.nodes.contains_key(&peer.id)
     }
 
-    /// Chreates and returns a new bucket.
+    /// Splits the bucket at the given depth of the trie. Peers (and replacements)
+    /// whose xor distance to the local node shares more than `depth` leading zero
+    /// bits are moved into a new bucket which is returned, the peers that remain
+    /// share exactly `depth` leading zero bits. The order of least recently seen
+    /// peers is kept in both buckets.
     /// 
-    /// TODO:
+    /// # Arguments
     /// 
-    /// Need to implement actual splitting functionality, currently this just
-    /// creates and returns a new bucket.
+    /// * local - the local node's key
+    /// * depth - the number of leading zero bits shared by every peer in this bucket
     /// 
-    pub fn split(&mut self, index: usize, prefix: String) -> KBucket {
-        let mut new_bucket = KBucket::new();        
-        return new_bucket
+    pub fn split(&mut self, local: &Key, depth: usize) -> KBucket {
+        let mut new_bucket = KBucket::new();
+        new_bucket.last_updated = self.last_updated;
+        let deeper = |peer: &PeerInfo| local.xor(peer.get_key()).leading_zeros() > depth;
+
+        let (moved, kept): (Vec<_>, Vec<_>) = self.nodes.drain().partition(|(_, (peer, _))| deeper(peer));
+        self.nodes.extend(kept);
+        new_bucket.nodes.extend(moved);
+
+        let (moved, kept): (Vec<_>, Vec<_>) = self.replacements.drain().partition(|(_, peer)| deeper(peer));
+        self.replacements.extend(kept);
+        new_bucket.replacements.extend(moved);
+
+        new_bucket
     }
 
     /// Returns a vector of all the Peers in the bucket without their key
     pub fn get_nodes(&self) -> Vec<PeerInfo> {
-        self.nodes.clone().iter().map(|(k, v)| v.clone()).collect()
+        self.nodes.iter().map(|(_, (peer, _))| peer.clone()).collect()
+    }
+
+    /// Returns a vector of all the Peers in the bucket along with the time they were last seen
+    pub fn get_entries(&self) -> Vec<(PeerInfo, Timestamp)> {
+        self.nodes.values().cloned().collect()
     }
 
     /// Removes the least recently used peer from the bucket
@@ -84,7 +184,7 @@ impl KBucket {
         if self.size() == 0 {
             None
         } else {
-            Some(self.nodes.pop_front().unwrap().1)
+            Some(self.nodes.pop_front().unwrap().1 .0)
         }
     }
 
@@ -94,7 +194,7 @@ impl KBucket {
     /// 
     /// * peer - the peer that we are requesting be removed.
     pub fn remove_peer(&mut self, peer: &PeerInfo) -> Option<PeerInfo> {
-        self.nodes.remove(&peer.id)
+        self.nodes.remove(&peer.id).map(|(peer, _)| peer)
     }
 
     /// Checks if the bucket is equal to or greater than MAX_BUCKET_LEN
@@ -110,6 +210,13 @@ impl KBucket {
         diff > REFRESH_INTEVAL
     }
 
+    /// Resets the bucket's last updated time to now, used once a refresh
+    /// of the bucket has been started so it isn't refreshed again until it
+    /// goes stale again.
+    pub fn touch(&mut self) {
+        self.last_updated = timestamp_now();
+    }
+
     /// Returns the number of entries in the bucket.
     pub fn size(&self) -> usize {
         self.nodes.len()
@@ -124,34 +231,44 @@ impl RoutingTable {
     /// 
     /// * local_info - the local nodes PeerInfo to be inserted into the routing table
     pub fn new(local_info: PeerInfo) -> Self {
-        let mut tree = HashMap::new();
         let mut kbucket = KBucket::new();
         kbucket.upsert(&local_info);
-        tree.insert(local_info.get_key().xor(local_info.get_key()).get_prefix(0), kbucket);
-        RoutingTable { tree, local_info }
+        RoutingTable { buckets: vec![kbucket], local_info }
+    }
+
+    /// Returns the index of the kbucket that a key belongs in
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key to find the kbucket for
+    pub fn bucket_index(&self, key: &Key) -> usize {
+        let distance = self.local_info.get_key().xor(*key);
+        cmp::min(distance.leading_zeros(), self.buckets.len() - 1)
     }
 
-    /// Recursively inserts or updates a peer into the routing table in the proper
-    /// kbucket.
+    /// Inserts or updates a peer into the routing table in the proper kbucket.
+    /// When the bucket containing the local node is full it is split and the insert
+    /// is retried. Any other full bucket holds the peer in its replacement cache
+    /// and returns its least recently seen peer. The caller should ping that peer
+    /// and evict it if it doesn't respond, which promotes the replacement.
     /// 
     /// * peer_info - the peer to update
-    /// * traverse - the xor prefix length to start at.
-    /// 
-    pub fn update_peer(&mut self, peer_info: &PeerInfo, traverse: usize) -> bool {
-        let distance = self.local_info.get_key().xor(peer_info.get_key());
-        let prefix = distance.get_prefix(traverse);
-        if let Some(bucket) = self.tree.get_mut(&prefix) {
-            if !bucket.is_full() {
-                bucket.upsert(peer_info);
-                return true
-            } else {
-                self.update_peer(peer_info, traverse + 1)
-            }
+    /// 
+    pub fn update_peer(&mut self, peer_info: &PeerInfo) -> Option<PeerInfo> {
+        let index = self.bucket_index(&peer_info.get_key());
+        let last = self.buckets.len() - 1;
+        let bucket = &mut self.buckets[index];
+        if !bucket.is_full() || bucket.contains(peer_info) {
+            bucket.upsert(peer_info);
+            None
+        } else if index == last && last < KEY_BITS - 1 {
+            let local = self.local_info.get_key();
+            let new_bucket = self.buckets[last].split(&local, last);
+            self.buckets.push(new_bucket);
+            self.update_peer(peer_info)
         } else {
-            let mut new_bucket = KBucket::new();
-            new_bucket.upsert(peer_info);
-            self.tree.insert(prefix, new_bucket);
-            return true
+            bucket.add_replacement(peer_info);
+            bucket.lru()
         }
     }
 
@@ -163,79 +280,34 @@ impl RoutingTable {
     /// * count - the number of closest peers to find.\
     /// 
     pub fn get_closest_peers(&self, peer_info: PeerInfo, count: usize) -> Vec<PeerInfo> {
-        // If the requested number of peers exceeds or is equal to the total number of peers, 
-        // in our local routing table simply return all the peers from the local routing table
-        if self.total_peers() <= count {
-            return self.get_all_peers()
-        }
-
-        // Otherwise, get the distance as measured by the XOR of the local key to the peer's key
-        let distance = self.local_info.get_key().xor(peer_info.get_key());
-
-        // Clone the tree and retain only the kbuckets that have the same prefix
-        // as the distance.
-        let mut cloned_tree = self.tree.clone();
-        cloned_tree.retain(|k, bucket| {
-            let prefix = distance.get_prefix(k.len()-1);
-            prefix == *k 
-        });
-
-        // count the total number of peers contained in the cloned tree
-        // after filtering out the buckets with different prefixes.
-        let total = cloned_tree.iter().fold(0, |acc, (k, v)| acc + v.size());
-
-        // Check of the cloned tree is empty or the total peers is less than the
-        // requested number of peers.
-        if cloned_tree.is_empty() || total < count {
-            // if it is then just get all the peers we know of
-            let mut closest = self.get_all_peers();
-            // sortt them by the XOR distance to the peer
-            closest.sort_by_key(|peer| peer.get_key().xor(peer_info.get_key()));
-            // Truncate the vector of peers to only include the number requested
-            closest.truncate(count);
-            // And return this vector.
-            return closest
-        }
+        let target = peer_info.get_key();
+        let index = self.bucket_index(&target);
+
+        // Peers in the target's own bucket are the closest to it. Every peer in a
+        // deeper bucket differs from the target at the same bit, so those are
+        // next and have to be sorted together. Peers in shallower buckets differ
+        // from the target at their own bucket's bit, so each shallower bucket is
+        // further away than the last.
+        let mut groups = vec![self.buckets[index].get_nodes()];
+        groups.push(
+            self.buckets[index + 1..]
+                .iter()
+                .flat_map(|bucket| bucket.get_nodes())
+                .collect(),
+        );
+        groups.extend(self.buckets[..index].iter().rev().map(|bucket| bucket.get_nodes()));
 
-        // if the cloned tree is not empty and the total number of peers is equal to
-        // or exceeds the number requested, collect the prefixes, and the nodes
-        // and return them as a vector of (prefix, Vec<PeerInfo>) tuples.
-        let mut closest: Vec<_> = cloned_tree.iter().map(|(k, v)| {
-            (k, v.get_nodes())
-        }).collect();
-
-        // Sort them by the lenght of the prefix and reverse it so that the longest
-        // prefixes are first.
-        closest.sort_unstable_by_key(|(k, v)| k.len());
-        closest.reverse();
-
-        // intialize an iterator over the vector and return
-        // the first vector of peers.
-        let mut iter = closest.iter();
-        let mut ret = {
-            if let Some((k, nodes)) = iter.next() {
-                nodes.clone()
-            } else {
-                vec![]
-            }
-        };
-
-        // Until the length of the return vector is equal to the
-        // number of nodes, continue to iterate over the vector of tuples
-        // and extend the return vector with the nodes in each tuple
-        while ret.len() < count {
-            if let Some((k, nodes)) = iter.next() {
-                ret.extend(nodes.clone())
-            } else {
+        let mut closest = Vec::with_capacity(count);
+        for mut group in groups {
+            if closest.len() >= count {
                 break
             }
+            group.sort_by_key(|peer| peer.get_key().xor(target));
+            closest.extend(group);
         }
 
-        // sort the return vector by XOR to the requesting peer and truncate to
-        // only include the number requested. return the return vector. 
-        ret.sort_by_key(|peer| peer.get_key().xor(peer_info.get_key()));
-        ret.truncate(count);
-        ret
+        closest.truncate(count);
+        closest
     }
 
     /// Removes the least recently used peer from a given kbucket
@@ -244,22 +316,8 @@ impl RoutingTable {
     /// 
     /// * peer_key - the key of the peer used to find the kbucket that the LRU peer will be removed from
     pub fn remove_lru(&mut self, peer_key: &InnerKey) -> Option<PeerInfo> {
-        // TODO:
-        // This will no longer work because of the change to how the
-        // tree and kbuckets are organized, restructure to work with the
-        // prefix key system.
-        let index = cmp::min(
-            self.local_info.get_key().xor(Key::new(*peer_key)).leading_zeros(),
-            self.tree.len() - 1,
-        );
-
-        let prefix = self.local_info.get_key().xor(Key::new(*peer_key)).get_prefix(index);
-
-        if let Some(bucket) = self.tree.get_mut(&prefix) {
-            return bucket.remove_lru();
-        }
-
-        None
+        let index = self.bucket_index(&Key::new(*peer_key));
+        self.buckets[index].remove_lru()
     }
 
     /// Removes a specified peer from the routing table
@@ -267,36 +325,61 @@ impl RoutingTable {
     /// # Arguments
     /// 
     /// * peer_info - the peer to remove from the routing table.
-    pub fn remove_peer(&mut self, peer_info: &PeerInfo) {
-        // TODO:
-        // This will no longer work because of the change to how the
-        // tree and kbuckets are organized, restructure to work with the
-        // prefix key system.
-        let index = cmp::min(
-            self.local_info.get_key().xor(peer_info.get_key()).leading_zeros(),
-            self.tree.len() - 1,
-        );
+    pub fn remove_peer(&mut self, peer_info: &PeerInfo) -> Option<PeerInfo> {
+        let index = self.bucket_index(&peer_info.get_key());
+        self.buckets[index].remove_peer(peer_info)
+    }
 
-        let prefix = self.local_info.get_key().xor(peer_info.get_key()).get_prefix(index);
-        
-        if let Some(bucket) = self.tree.get_mut(&prefix) {
-            println!("{}", bucket.size());
-            bucket.remove_peer(peer_info);
-            println!("{}", bucket.size());
-        }
+    /// Returns the least recently seen peer from each kbucket, excluding the local node.
+    /// These are the peers that are checked for liveness at each ping pong event.
+    pub fn get_lru_peers(&self) -> Vec<PeerInfo> {
+        self.buckets
+            .iter()
+            .filter_map(|bucket| {
+                bucket
+                    .get_nodes()
+                    .into_iter()
+                    .find(|peer| peer.id != self.local_info.id)
+            })
+            .collect()
     }
 
+    /// Removes an unresponsive peer from the routing table and promotes
+    /// the most recent replacement from its kbucket's replacement cache.
+    /// Returns the evicted peer if it was in the routing table.
+    /// 
+    /// # Arguments
+    /// 
+    /// * key - the key of the peer to evict
+    pub fn evict(&mut self, key: &Key) -> Option<PeerInfo> {
+        let index = self.bucket_index(key);
+        let bucket = &mut self.buckets[index];
+        let peer = bucket.get_nodes().into_iter().find(|peer| &peer.key == key)?;
+        bucket.evict(&peer);
+        Some(peer)
+    }
 
-    /// Get stale buckets and return a vector of the keys of those buckets
-    pub fn get_stale_indices(&self) -> Vec<String> {
-        let mut ret = Vec::new();
-        self.tree.iter().for_each(|(prefix, bucket)| {
-            if bucket.is_stale() {
-                ret.push(prefix.clone())
-            }
-        });
+    /// Removes a peer that has left the network from the routing table and from
+    /// its kbucket's replacement cache, promoting the most recent replacement in
+    /// its place. Returns the peer if it was in the routing table.
+    /// 
+    /// # Arguments
+    /// 
+    /// * peer - the peer that left
+    pub fn remove_departed(&mut self, peer: &PeerInfo) -> Option<PeerInfo> {
+        let index = self.bucket_index(&peer.get_key());
+        self.buckets[index].remove_replacement(peer);
+        self.evict(&peer.get_key())
+    }
 
-        ret
+    /// Get stale buckets and return a vector of the indices of those buckets
+    pub fn get_stale_indices(&self) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.is_stale())
+            .map(|(index, _)| index)
+            .collect()
     }
 
     /// Check if a peer is a new peer or already exists in the routing table.
@@ -307,23 +390,53 @@ impl RoutingTable {
     /// * peer - the peer to check
     /// 
     pub fn is_new(&self, peer: &PeerInfo) -> bool {
-        !self.tree.iter().any(|(_, bucket)| bucket.contains(peer))
+        !self.buckets[self.bucket_index(&peer.get_key())].contains(peer)
     }
 
     /// Get the number of kbuckets in the tree
     pub fn size(&self) -> usize {
-        self.tree.len()
+        self.buckets.len()
     }
 
     /// Get the total number of peers in the tree (sum of the size of all the kbuckets)
     pub fn total_peers(&self) -> usize {
-        self.tree.iter().fold(0, |acc, (k, v)| acc + v.size())
+        self.buckets.iter().fold(0, |acc, bucket| acc + bucket.size())
     }
 
     /// Get all the peers in the routing table and return them as a vector of PeerInfo
     pub fn get_all_peers(&self) -> Vec<PeerInfo> {
-        self.tree.iter().map(|(k, v)| {
-            v.get_nodes()
-        }).collect::<Vec<_>>().into_iter().flatten().collect()
+        self.buckets.iter().flat_map(|bucket| bucket.get_nodes()).collect()
+    }
+
+    /// Writes a snapshot of every peer in the routing table, other than the local node,
+    /// and the time it was last seen to a file.
+    /// 
+    /// # Arguments
+    /// 
+    /// * path - the file to write the snapshot to
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let peers = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.get_entries())
+            .filter(|(peer, _)| peer.id != self.local_info.id)
+            .collect();
+        let snapshot = Snapshot { peers, saved: timestamp_now() };
+        let bytes = serde_json::to_vec(&snapshot)?;
+        fs::write(path, bytes)
+    }
+
+    /// Reads a snapshot written by save and returns its peers, most recently seen first.
+    /// The peers are not inserted into any routing table, as they may have gone offline
+    /// since the snapshot was taken and should be validated first.
+    /// 
+    /// # Arguments
+    /// 
+    /// * path - the file to read the snapshot from
+    pub fn load(path: &Path) -> io::Result<Vec<(PeerInfo, Timestamp)>> {
+        let bytes = fs::read(path)?;
+        let mut snapshot: Snapshot = serde_json::from_slice(&bytes)?;
+        snapshot.peers.sort_by_key(|(_, last_seen)| cmp::Reverse(*last_seen));
+        Ok(snapshot.peers)
     }
 }
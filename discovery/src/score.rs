@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The weights and thresholds used to score peers.
+///
+/// * delivery_weight - the score of a peer that every message was delivered to, and the
+///   penalty for one that nothing could be delivered to
+/// * invalid_weight - the penalty for each invalid message a peer has sent
+/// * behaviour_weight - the penalty for each request a peer has made beyond the protocol's
+///   limits, such as asking for the same gossip message too many times
+/// * mesh_weight - the score of a peer that has been in a gossip mesh for mesh_cap
+/// * mesh_cap - the time in a mesh after which a peer earns no more score for it
+/// * first_delivery_weight - the score for each valid message a peer was the first to send
+/// * first_delivery_cap - the number of first deliveries after which a peer earns no more score for them
+/// * latency_weight - the penalty for a peer whose requests take latency_cap or longer to answer
+/// * latency_cap - the latency at which a peer gets the full latency penalty
+/// * decay - the factor each count is multiplied by at every kademlia interval
+/// * gossip_threshold - peers scoring below this aren't sent gossip
+/// * ban_threshold - peers scoring below this are banned
+/// * ban_duration - how long a banned peer is ignored for
+#[derive(Clone, Debug)]
+pub struct ScoreConfig {
+    pub delivery_weight: f64,
+    pub invalid_weight: f64,
+    pub behaviour_weight: f64,
+    pub mesh_weight: f64,
+    pub mesh_cap: Duration,
+    pub first_delivery_weight: f64,
+    pub first_delivery_cap: f64,
+    pub latency_weight: f64,
+    pub latency_cap: Duration,
+    pub decay: f64,
+    pub gossip_threshold: f64,
+    pub ban_threshold: f64,
+    pub ban_duration: Duration,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        ScoreConfig {
+            delivery_weight: 10.0,
+            invalid_weight: 20.0,
+            behaviour_weight: 5.0,
+            mesh_weight: 5.0,
+            mesh_cap: Duration::from_secs(60),
+            first_delivery_weight: 1.0,
+            first_delivery_cap: 10.0,
+            latency_weight: 5.0,
+            latency_cap: Duration::from_secs(1),
+            decay: 0.9,
+            gossip_threshold: -10.0,
+            ban_threshold: -50.0,
+            ban_duration: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// What is known about a single peer's behaviour. Counts are decayed over time
+/// so that peers recover from old failures, latency is a moving average of the
+/// time the peer takes to answer requests, decayed like the counts so a peer
+/// that was once slow recovers too.
+#[derive(Clone, Debug, Default)]
+pub struct PeerScore {
+    delivered: f64,
+    failed: f64,
+    invalid: f64,
+    misbehaviour: f64,
+    mesh_time: Duration,
+    first_deliveries: f64,
+    latency: Option<Duration>,
+}
+
+impl PeerScore {
+    /// Calculates the score of the peer. Peers start at zero, delivering messages
+    /// and taking part in gossip meshes raise the score, and failed deliveries,
+    /// invalid messages, requests beyond the protocol's limits and slow responses
+    /// lower it.
+    ///
+    /// # Arguments
+    ///
+    /// * config - the weights to score the peer with
+    pub fn score(&self, config: &ScoreConfig) -> f64 {
+        let mut score = 0.0;
+        let sent = self.delivered + self.failed;
+        if sent > 0.0 {
+            score += config.delivery_weight * (self.delivered - self.failed) / sent;
+        }
+        score -= config.invalid_weight * self.invalid;
+        score -= config.behaviour_weight * self.misbehaviour;
+
+        let in_mesh = self.mesh_time.as_secs_f64() / config.mesh_cap.as_secs_f64();
+        score += config.mesh_weight * in_mesh.min(1.0);
+        score += config.first_delivery_weight * self.first_deliveries.min(config.first_delivery_cap);
+
+        if let Some(latency) = self.latency {
+            let slow = latency.as_secs_f64() / config.latency_cap.as_secs_f64();
+            score -= config.latency_weight * slow.min(1.0);
+        }
+        score
+    }
+
+    /// Multiplies each count and the latency by the decay factor
+    ///
+    /// # Arguments
+    ///
+    /// * decay - the decay factor
+    fn decay(&mut self, decay: f64) {
+        self.delivered *= decay;
+        self.failed *= decay;
+        self.invalid *= decay;
+        self.misbehaviour *= decay;
+        self.first_deliveries *= decay;
+        self.mesh_time = self.mesh_time.mul_f64(decay);
+        self.latency = self.latency.map(|latency| latency.mul_f64(decay));
+    }
+
+    /// Checks if every count and the latency have decayed away, so the score can be forgotten
+    fn is_idle(&self) -> bool {
+        self.delivered + self.failed + self.invalid + self.misbehaviour + self.first_deliveries < 0.01
+            && self.mesh_time < Duration::from_secs(1)
+            && self.latency.is_none_or(|latency| latency < Duration::from_millis(1))
+    }
+}
+
+/// The scores of every peer the local node has interacted with, along with the
+/// peers that have been banned and the instant each ban started.
+#[derive(Clone, Debug, Default)]
+pub struct PeerScores {
+    scores: HashMap<SocketAddr, PeerScore>,
+    banned: HashMap<SocketAddr, Instant>,
+    config: ScoreConfig,
+}
+
+impl PeerScores {
+    /// Creates a new set of peer scores
+    ///
+    /// # Arguments
+    ///
+    /// * config - the weights and thresholds to score peers with
+    pub fn new(config: ScoreConfig) -> PeerScores {
+        PeerScores {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the weights and thresholds peers are scored with
+    pub fn config(&self) -> &ScoreConfig {
+        &self.config
+    }
+
+    /// Returns the score of a peer, peers without a score have a score of zero
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    pub fn score(&self, peer: &SocketAddr) -> f64 {
+        self.scores
+            .get(peer)
+            .map(|score| score.score(&self.config))
+            .unwrap_or(0.0)
+    }
+
+    /// Checks if a peer scores at or above the gossip threshold and returns true or false
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    pub fn accepts_gossip(&self, peer: &SocketAddr) -> bool {
+        self.score(peer) >= self.config.gossip_threshold
+    }
+
+    /// Records a message that was delivered to a peer
+    pub fn delivered(&mut self, peer: SocketAddr) {
+        self.scores.entry(peer).or_default().delivered += 1.0;
+    }
+
+    /// Records a message or request that a peer failed to acknowledge or answer
+    pub fn failed(&mut self, peer: SocketAddr) {
+        self.scores.entry(peer).or_default().failed += 1.0;
+    }
+
+    /// Records an invalid message sent by a peer
+    pub fn invalid(&mut self, peer: SocketAddr) {
+        self.scores.entry(peer).or_default().invalid += 1.0;
+    }
+
+    /// Records a request a peer made beyond the protocol's limits
+    pub fn misbehaved(&mut self, peer: SocketAddr) {
+        self.scores.entry(peer).or_default().misbehaviour += 1.0;
+    }
+
+    /// Records a valid message that a peer was the first to send
+    pub fn first_delivery(&mut self, peer: SocketAddr) {
+        self.scores.entry(peer).or_default().first_deliveries += 1.0;
+    }
+
+    /// Records time that a peer spent in a gossip mesh
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    /// * time - the time spent in the mesh
+    pub fn in_mesh(&mut self, peer: SocketAddr, time: Duration) {
+        self.scores.entry(peer).or_default().mesh_time += time;
+    }
+
+    /// Records the time a peer took to answer a request in the peer's average latency
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    /// * latency - the time between sending the request and receiving the response
+    pub fn latency(&mut self, peer: SocketAddr, latency: Duration) {
+        let score = self.scores.entry(peer).or_default();
+        score.latency = Some(match score.latency {
+            Some(average) => average.mul_f64(0.8) + latency.mul_f64(0.2),
+            None => latency,
+        });
+    }
+
+    /// Returns the peers that score below the ban threshold but haven't been banned yet
+    pub fn below_ban_threshold(&self) -> Vec<SocketAddr> {
+        self.scores
+            .iter()
+            .filter(|(peer, score)| {
+                !self.banned.contains_key(peer) && score.score(&self.config) < self.config.ban_threshold
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Bans a peer for the ban duration, forgetting its score
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    pub fn ban(&mut self, peer: SocketAddr) {
+        self.scores.remove(&peer);
+        self.banned.insert(peer, Instant::now());
+    }
+
+    /// Checks if a peer is banned and returns true or false
+    ///
+    /// # Arguments
+    ///
+    /// * peer - the address of the peer
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.banned.contains_key(peer)
+    }
+
+    /// Decays every score, forgets scores that have decayed away and lifts
+    /// bans that have lasted the ban duration
+    pub fn decay(&mut self) {
+        let decay = self.config.decay;
+        self.scores.values_mut().for_each(|score| score.decay(decay));
+        self.scores.retain(|_, score| !score.is_idle());
+        let ban_duration = self.config.ban_duration;
+        self.banned.retain(|_, banned| banned.elapsed() < ban_duration);
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use udp2p_protocol::event::{Event, EventBus};
 use udp2p_protocol::protocol::{InnerKey, Packet, AddressBytes, Packets};
 use udp2p_utils::utils::ByteRep;
 use log::info;
@@ -14,6 +15,9 @@ use log::info;
 /// id and a hashmap of key == packet number, value = quaduple of a set of destinations
 /// set of returned receipts, the packets, and the number attempts. The timer is used to to 
 /// determine whether enough time has passed to attempt to resend unacknowledged packets.
+/// Messages that are fully acknowledged, or that run out of attempts, are reported on the
+/// event bus.
+#[allow(clippy::type_complexity)]
 #[derive(Debug, Clone)]
 pub struct GDUdp {
     pub addr: SocketAddr,
@@ -21,6 +25,7 @@ pub struct GDUdp {
     pub outbox: HashMap<InnerKey, HashMap<usize, (HashSet<SocketAddr>, HashSet<SocketAddr>, Packet, usize)>>,
     pub timer: Instant,
     pub log: String,
+    pub events: EventBus,
 }
 
 impl GDUdp {
@@ -43,11 +48,15 @@ impl GDUdp {
             outbox: HashMap::new(),
             timer: Instant::now(),
             log: "log.log".to_string(),
+            events: EventBus::new(),
         }
     }
 
     /// Loops through the outbox and resends packets that haven't been acknowldged
-    /// and tracks the number of attempts.
+    /// and tracks the number of attempts. Messages whose packets have all been
+    /// acknowledged are removed and reported as delivered, and messages with a packet
+    /// that has run out of attempts are removed and reported as failed for every peer
+    /// that didn't acknowledge it.
     /// TODO: 
     /// 
     /// add a GDUDPConfig struct that contains config information like number of attempts
@@ -57,9 +66,31 @@ impl GDUdp {
     /// 
     /// * sock - the UDP socket for the local node used to resend unacknowldged packets.
     pub fn maintain(&mut self, sock: &UdpSocket) {
+        let events = self.events.clone();
         self.outbox.retain(|_, map| {
-            map.retain(|_, (sent_set, ack_set, _, attempts)| sent_set != ack_set && *attempts < 5);
-            !map.is_empty()
+            let failed: HashSet<SocketAddr> = map
+                .values()
+                .filter(|(_, _, _, attempts)| *attempts >= 5)
+                .flat_map(|(sent_set, ack_set, _, _)| sent_set.difference(ack_set).copied())
+                .collect();
+            if !failed.is_empty() {
+                failed.into_iter().for_each(|peer| {
+                    events.publish(Event::DeliveryFailed(peer));
+                });
+                return false;
+            }
+
+            if map.values().all(|(sent_set, ack_set, _, _)| sent_set == ack_set) {
+                let delivered: HashSet<SocketAddr> = map
+                    .values()
+                    .flat_map(|(sent_set, _, _, _)| sent_set.iter().copied())
+                    .collect();
+                delivered.into_iter().for_each(|peer| {
+                    events.publish(Event::MessageDelivered(peer));
+                });
+                return false;
+            }
+            true
         });
         
         self.outbox.clone().iter().for_each(|(_, map)| {
@@ -67,7 +98,7 @@ impl GDUdp {
                 if *attempts < 5 {
                     let resend: HashSet<_> = sent_set.difference(ack_set).collect();
                     resend.iter().for_each(|peer| {
-                        self.send_reliable(peer, &packet, sock);
+                        self.send_reliable(peer, packet, sock);
                     });
                 }
             });
@@ -89,8 +120,8 @@ impl GDUdp {
         buf: &mut [u8],
     ) -> Result<(usize, SocketAddr), std::io::Error> {
         match sock.recv_from(buf) {
-            Err(e) => return Err(e),
-            Ok((amt, src)) => return Ok((amt, src)),
+            Err(e) => Err(e),
+            Ok((amt, src)) => Ok((amt, src)),
         }
     }
 
@@ -146,12 +177,12 @@ impl GDUdp {
     ) {
         if let Some(map) = self.outbox.get_mut(&packet.id) {
             if let Some((sent_set, _, _, attempts)) = map.get_mut(&packet.n) {
-                sent_set.insert(peer.clone());
+                sent_set.insert(*peer);
                 *attempts += 1;
             } else {
                 let mut sent_set = HashSet::new();
                 let ack_set: HashSet<SocketAddr> = HashSet::new();
-                sent_set.insert(peer.clone());
+                sent_set.insert(*peer);
                 let attempts = 1;
                 map.insert(packet.n, (sent_set, ack_set, packet.clone(), attempts));
             }
@@ -159,7 +190,7 @@ impl GDUdp {
             let mut map = HashMap::new();
             let mut sent_set = HashSet::new();
             let ack_set: HashSet<SocketAddr> = HashSet::new();
-            sent_set.insert(peer.clone());
+            sent_set.insert(*peer);
             let attempts = 1;
             map.insert(packet.n, (sent_set, ack_set, packet.clone(), attempts));
             self.outbox.insert(packet.id, map);
@@ -1,6 +1,6 @@
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::mpsc::{Sender, Receiver, channel};
-use udp2p_protocol::protocol::{AckMessage, Message, MessageKey, Header};
+use udp2p_protocol::protocol::{AckMessage, Message};
 use udp2p_node::peer_id::PeerId;
 use udp2p_node::peer_key::Key;
 use udp2p_node::peer_info::PeerInfo;
@@ -8,16 +8,19 @@ use udp2p_discovery::kad::Kademlia;
 use udp2p_discovery::routing::RoutingTable;
 use udp2p_transport::transport::Transport;
 use udp2p_transport::handler::MessageHandler;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::thread;
 use std::env::args;
 use udp2p_gossip::gossip::{GossipConfig, GossipService};
-use udp2p_gossip::protocol::GossipMessage;
+use udp2p_gossip::protocol::{DeliveredMessage, GossipEnvelope, GossipMessage, GossipRpc};
 use rand::{thread_rng, Rng};
+use rand::rngs::OsRng;
+use ed25519_dalek::SigningKey;
 use std::time::{Duration, Instant};
 use udp2p_utils::utils::ByteRep;
 
 
+#[allow(clippy::type_complexity)]
 fn main() {
     // Bind a UDP Socket to a Socket Address with a random port between
     // 9292 and 19292 on the localhost address.
@@ -35,19 +38,21 @@ fn main() {
     let (to_gossip_tx, to_gossip_rx) = channel();
     let (to_kad_tx, to_kad_rx) = channel();
     let (incoming_ack_tx, incoming_ack_rx): (Sender<AckMessage>, Receiver<AckMessage>) = channel();
-    let (to_app_tx, _to_app_rx) = channel::<GossipMessage>();
+    let (to_app_tx, _to_app_rx) = channel::<DeliveredMessage>();
 
     // Initialize local peer information
-    let key: Key = Key::rand();
+    // The local key is the public key of the identity that messages are signed with
+    let keypair = SigningKey::generate(&mut OsRng);
+    let key: Key = Key::new(keypair.verifying_key().to_bytes());
     let id: PeerId = PeerId::from_key(&key);
-    let info: PeerInfo = PeerInfo::new(id, key, addr.clone());
+    let info: PeerInfo = PeerInfo::new(id, key, addr);
 
     // initialize a kademlia, transport and message handler instance
     let routing_table = RoutingTable::new(info.clone());
     let ping_pong = Instant::now();
     let interval = Duration::from_secs(20);
-    let kad = Kademlia::new(routing_table, to_transport_tx.clone(), to_kad_rx, HashSet::new(), interval, ping_pong.clone());
-    let mut transport = Transport::new(addr.clone(), incoming_ack_rx, to_transport_rx);
+    let kad = Kademlia::new(routing_table, to_transport_tx.clone(), to_kad_rx, HashMap::new(), interval, ping_pong);
+    let mut transport = Transport::new(addr, incoming_ack_rx, to_transport_rx);
     let mut message_handler = MessageHandler::new(
         to_transport_tx.clone(),
         incoming_ack_tx.clone(),
@@ -57,7 +62,7 @@ fn main() {
     );
     let protocol_id = String::from("vrrb-0.1.0-test-net");
     let gossip_config = GossipConfig::new(
-        protocol_id,
+        protocol_id.clone(),
         8,
         3,
         8,
@@ -71,7 +76,7 @@ fn main() {
     let heartbeat = Instant::now();
     let ping_pong = Instant::now();
     let mut gossip = GossipService::new(
-        addr.clone(),
+        addr,
         to_gossip_rx,
         to_transport_tx.clone(),
         to_app_tx.clone(),
@@ -80,6 +85,7 @@ fn main() {
         heartbeat,
         ping_pong,
     );
+    gossip.set_identity(keypair.clone());
 
     // Inform the local node of their address (since the port is randomized)
     println!("My Address: {:?}", addr);
@@ -95,15 +101,19 @@ fn main() {
         });
 
         loop {
-            let local = addr.clone();
+            let local = addr;
             let mut buf = [0u8; 65536];
             message_handler.recv_msg(&thread_sock, &mut buf, local);
         }
     });
 
-    if let Some(to_dial) = args().nth(1) {
-        let bootstrap: SocketAddr = to_dial.parse().expect("Unable to parse address");
-        gossip.kad.bootstrap(&bootstrap);
+    // Any arguments are the addresses of seed nodes to bootstrap from
+    let seeds: Vec<SocketAddr> = args()
+        .skip(1)
+        .map(|seed| seed.parse().expect("Unable to parse address"))
+        .collect();
+    if !seeds.is_empty() {
+        gossip.kad.bootstrap(&seeds);
         if let Some(bytes) = info.as_bytes() {
             gossip.kad.add_peer(bytes)
         }
@@ -113,25 +123,21 @@ fn main() {
         }
     }
 
+    gossip.subscribe("chat");
+
     let thread_to_gossip = to_gossip_tx.clone();
     thread::spawn(move || {
+        let mut seqno = 0;
         loop {
             let mut line = String::new();
             let input = std::io::stdin().read_line(&mut line);
-            if let Ok(_) = input {
-                let msg_id = MessageKey::rand();
-                let msg = GossipMessage {
-                    id: msg_id.inner(),
-                    data: line.trim().as_bytes().to_vec(),
-                    sender: addr.clone()
-                };
+            if input.is_ok() {
+                seqno += 1;
+                let msg = GossipMessage::new(&keypair, seqno, "chat", line.trim().as_bytes().to_vec(), addr);
 
-                let message = Message {
-                    head: Header::Gossip,
-                    msg: msg.as_bytes().unwrap()
-                };
+                let message = GossipEnvelope::message(&protocol_id, GossipRpc::Message(msg));
 
-                if let Err(_) = thread_to_gossip.clone().send((addr.clone(), message)) {
+                if thread_to_gossip.clone().send((addr, message)).is_err() {
                     println!("Error sending message to gossip")
                 }
             }        
@@ -0,0 +1,356 @@
+use crate::protocol::Topic;
+use udp2p_protocol::protocol::{InnerKey, Message, MessageKey};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The cache of recent messages that are gossiped about and served to IWants.
+/// The ids of the messages received in each heartbeat are kept in their own window,
+/// newest first, separately from the messages themselves. At each heartbeat a new
+/// window is started and the messages in windows older than the history length are
+/// dropped, so expiring messages never touches the rest of the cache. The number of
+/// messages and the bytes they take up are also limited, and the oldest messages
+/// are dropped first when either limit is reached.
+#[derive(Debug)]
+pub struct MessageCache {
+    windows: VecDeque<VecDeque<MessageKey>>,
+    messages: HashMap<MessageKey, (Topic, Message)>,
+    history_len: usize,
+    max_messages: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl MessageCache {
+    /// Creates a new, empty message cache
+    ///
+    /// # Arguments
+    ///
+    /// * history_len - the number of heartbeats messages are kept for
+    /// * max_messages - the most messages the cache holds at once
+    /// * max_bytes - the most bytes the messages in the cache take up at once
+    pub fn new(history_len: usize, max_messages: usize, max_bytes: usize) -> MessageCache {
+        MessageCache {
+            windows: VecDeque::from(vec![VecDeque::new()]),
+            messages: HashMap::new(),
+            history_len: history_len.max(1),
+            max_messages,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Adds a message to the current window, dropping the oldest messages if the
+    /// cache is full. Messages already in the cache, and messages too large to ever
+    /// fit in it, aren't added.
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    /// * topic - the topic the message was published to
+    /// * message - the message as it is sent to peers
+    pub fn put(&mut self, id: MessageKey, topic: &str, message: Message) {
+        let size = message.msg.len();
+        if self.messages.contains_key(&id) || size > self.max_bytes || self.max_messages == 0 {
+            return;
+        }
+        while self.messages.len() >= self.max_messages || self.bytes + size > self.max_bytes {
+            if !self.drop_oldest() {
+                break;
+            }
+        }
+
+        self.bytes += size;
+        self.messages.insert(id, (topic.to_string(), message));
+        if let Some(window) = self.windows.front_mut() {
+            window.push_back(id);
+        }
+    }
+
+    /// Returns a message in the cache
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn get(&self, id: &MessageKey) -> Option<&Message> {
+        self.messages.get(id).map(|(_, message)| message)
+    }
+
+    /// Checks if a message is in the cache and returns true or false
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn contains(&self, id: &MessageKey) -> bool {
+        self.messages.contains_key(id)
+    }
+
+    /// Returns the number of messages in the cache
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Checks if the cache is empty and returns true or false
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns the number of bytes the messages in the cache take up
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns the ids of the messages received in the most recent windows, grouped by topic
+    ///
+    /// # Arguments
+    ///
+    /// * windows - the number of windows to return the ids from
+    pub fn recent(&self, windows: usize) -> HashMap<Topic, Vec<InnerKey>> {
+        let mut recent: HashMap<Topic, Vec<InnerKey>> = HashMap::new();
+        self.windows.iter().take(windows).flatten().for_each(|id| {
+            if let Some((topic, _)) = self.messages.get(id) {
+                recent.entry(topic.clone()).or_default().push(id.inner());
+            }
+        });
+        recent
+    }
+
+    /// Starts a new window, dropping the messages in windows older than the history length
+    pub fn shift(&mut self) {
+        self.windows.push_front(VecDeque::new());
+        while self.windows.len() > self.history_len {
+            if let Some(window) = self.windows.pop_back() {
+                window.iter().for_each(|id| self.remove(id));
+            }
+        }
+    }
+
+    /// Drops the oldest message in the cache, returns false if the cache is empty
+    fn drop_oldest(&mut self) -> bool {
+        while let Some(window) = self.windows.back_mut() {
+            if let Some(id) = window.pop_front() {
+                self.remove(&id);
+                return true;
+            }
+            if self.windows.len() == 1 {
+                return false;
+            }
+            self.windows.pop_back();
+        }
+        false
+    }
+
+    /// Removes a message from the cache
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    fn remove(&mut self, id: &MessageKey) {
+        if let Some((_, message)) = self.messages.remove(id) {
+            self.bytes -= message.msg.len();
+        }
+    }
+}
+
+/// The ids of every message that has been received, used to drop duplicates.
+/// Ids are kept for longer than the messages in the message cache, and are
+/// expired in the order they were seen, so only the expired ids are visited.
+/// The number of ids is limited too, and the oldest ids are forgotten first
+/// once the limit is reached.
+#[derive(Debug)]
+pub struct SeenCache {
+    order: VecDeque<(MessageKey, Instant)>,
+    ids: HashSet<MessageKey>,
+    ttl: Duration,
+    max_ids: usize,
+}
+
+impl SeenCache {
+    /// Creates a new, empty seen cache
+    ///
+    /// # Arguments
+    ///
+    /// * ttl - how long ids are remembered for
+    /// * max_ids - the maximum number of ids to remember
+    pub fn new(ttl: Duration, max_ids: usize) -> SeenCache {
+        SeenCache {
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+            ttl,
+            max_ids,
+        }
+    }
+
+    /// Remembers an id, forgetting the oldest ids if there are more than the
+    /// limit. Returns false if the id had already been seen
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn insert(&mut self, id: MessageKey) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back((id, Instant::now()));
+        while self.order.len() > self.max_ids {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Checks if an id has been seen and returns true or false
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn contains(&self, id: &MessageKey) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Returns the number of ids remembered
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Checks if no ids are remembered and returns true or false
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Forgets the ids that were seen longer ago than the ttl
+    pub fn prune(&mut self) {
+        while let Some((id, seen)) = self.order.front() {
+            if seen.elapsed() <= self.ttl {
+                break;
+            }
+            self.ids.remove(id);
+            self.order.pop_front();
+        }
+    }
+}
+
+/// The messages waiting on the application to report how they validated, kept
+/// with the peer each came from. The number of messages and the bytes they take
+/// up are limited, and the oldest messages are dropped first when either limit
+/// is reached. Messages that are never reported are expired in the order they
+/// arrived, once they have waited longer than the ttl.
+#[derive(Debug)]
+pub struct ValidationQueue {
+    order: VecDeque<(MessageKey, Instant)>,
+    messages: HashMap<MessageKey, (SocketAddr, Message, Instant)>,
+    ttl: Duration,
+    max_messages: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl ValidationQueue {
+    /// Creates a new, empty validation queue
+    ///
+    /// # Arguments
+    ///
+    /// * ttl - how long messages wait for their validation
+    /// * max_messages - the most messages the queue holds at once
+    /// * max_bytes - the most bytes the messages in the queue take up at once
+    pub fn new(ttl: Duration, max_messages: usize, max_bytes: usize) -> ValidationQueue {
+        ValidationQueue {
+            order: VecDeque::new(),
+            messages: HashMap::new(),
+            ttl,
+            max_messages,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Adds a message to wait for its validation, dropping the oldest messages if
+    /// the queue is full. Messages already waiting, and messages too large to ever
+    /// fit in the queue, aren't added.
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    /// * src - the peer the message came from
+    /// * message - the message as it was received
+    pub fn insert(&mut self, id: MessageKey, src: SocketAddr, message: Message) {
+        let size = message.msg.len();
+        if self.messages.contains_key(&id) || size > self.max_bytes || self.max_messages == 0 {
+            return;
+        }
+        while self.messages.len() >= self.max_messages || self.bytes + size > self.max_bytes {
+            if !self.drop_oldest() {
+                break;
+            }
+        }
+        // Reported messages are left in the order until they expire, so it is
+        // compacted if they start to outnumber the messages still waiting
+        if self.order.len() > self.max_messages * 2 {
+            self.order.retain(|(id, _)| self.messages.contains_key(id));
+        }
+
+        let now = Instant::now();
+        self.bytes += size;
+        self.messages.insert(id, (src, message, now));
+        self.order.push_back((id, now));
+    }
+
+    /// Removes a message once its validation has been reported and returns the
+    /// peer it came from along with the message
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn remove(&mut self, id: &MessageKey) -> Option<(SocketAddr, Message)> {
+        let (src, message, _) = self.messages.remove(id)?;
+        self.bytes -= message.msg.len();
+        Some((src, message))
+    }
+
+    /// Checks if a message is waiting for its validation and returns true or false
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    pub fn contains(&self, id: &MessageKey) -> bool {
+        self.messages.contains_key(id)
+    }
+
+    /// Returns the number of messages waiting for their validation
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Checks if no messages are waiting for their validation and returns true or false
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns the number of bytes the messages in the queue take up
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Drops the messages that have waited longer than the ttl
+    pub fn prune(&mut self) {
+        while let Some((_, received)) = self.order.front() {
+            if received.elapsed() <= self.ttl {
+                break;
+            }
+            self.drop_oldest();
+        }
+    }
+
+    /// Drops the oldest message still waiting, returns false if the queue is empty
+    fn drop_oldest(&mut self) -> bool {
+        while let Some((id, received)) = self.order.pop_front() {
+            let waiting = matches!(self.messages.get(&id), Some((_, _, inserted)) if *inserted == received);
+            if waiting {
+                self.remove(&id);
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -1,16 +1,22 @@
 #![allow(dead_code)]
-use crate::protocol::GossipMessage;
+use crate::cache::{MessageCache, SeenCache, ValidationQueue};
+use crate::protocol::{DeliveredMessage, GossipEnvelope, GossipMessage, GossipRpc, Topic};
+use crate::validator::{MessageValidator, MessageValidators, TopicValidator, Validation, ValidationStatus};
 use udp2p_discovery::kad::Kademlia;
-use udp2p_protocol::protocol::{Message, MessageKey};
+use udp2p_protocol::event::Event;
+use udp2p_protocol::protocol::{InnerKey, Message, MessageData, MessageKey};
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::Duration;
-use udp2p_utils::utils::ByteRep;
 use std::time::Instant;
-use rand::Rng;
+use rand::seq::SliceRandom;
 use udp2p_traits::routable::Routable;
 use log::info;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use udp2p_utils::utils::timestamp_now;
 
 /// A configuration struct for the user to pass different
 /// parameters into the gossip struct.
@@ -34,26 +40,58 @@ pub struct GossipConfig {
     // Time between heartbeats
     interval: Duration,
     check: usize,
+    // Maximum number of messages to keep in cache
+    max_messages: usize,
+    // Maximum number of bytes of messages to keep in cache
+    max_bytes: usize,
+    // Maximum number of ids of seen messages to remember
+    max_seen: usize,
+    // Number of heartbeats to remember the ids of seen messages
+    seen_len: usize,
+    // Maximum number of message ids to serve from a single IWant
+    max_iwant_len: usize,
+    // Maximum number of times to serve a message to the same peer
+    max_retransmissions: usize,
 }
 
 
 /// The core gossip struct. This is the gosisp engine that handles incoming and outgoing
-/// messages, peer sampling, and other message
+/// messages, peer sampling, and other message. Messages are published to topics, the
+/// service keeps the topics the local node is subscribed to, the topics each neighbour
+/// has announced it is subscribed to, and the neighbours the local subscriptions
+/// have been announced to. For each subscribed topic it keeps a mesh of neighbours
+/// that full messages are pushed to, which is kept between the configured low and
+/// high number of peers at each heartbeat. Recent messages are kept in a bounded
+/// cache for gossip and IWants, while the ids of every message seen are kept for
+/// longer to drop duplicates. Messages waiting on asynchronous validation are kept
+/// in a queue with the same limits as the cache. Messages the local node publishes are
+/// signed with its identity key and numbered with the next sequence number.
+/// The number of times each cached message has been served to each peer in answer
+/// to IWants is kept until the message leaves the cache.
+/// Neighbours are pinged every check heartbeats, and the pings still waiting on
+/// a pong are kept with the instant they were sent.
 pub struct GossipService {
     address: SocketAddr,
     to_gossip_rx: Receiver<(SocketAddr, Message)>,
     pub to_transport_tx: Sender<(SocketAddr, Message)>,
-    pub to_app_tx: Sender<GossipMessage>,
+    pub to_app_tx: Sender<DeliveredMessage>,
     pub kad: Kademlia,
-    cache: HashMap<MessageKey, (Message, Instant)>,
+    cache: MessageCache,
+    served: HashMap<(SocketAddr, MessageKey), usize>,
+    topics: HashSet<Topic>,
+    peer_topics: HashMap<SocketAddr, HashSet<Topic>>,
+    announced: HashSet<SocketAddr>,
+    mesh: HashMap<Topic, HashSet<SocketAddr>>,
+    validators: MessageValidators,
+    validating: ValidationQueue,
+    seen: SeenCache,
+    rejections: HashMap<SocketAddr, usize>,
+    keypair: SigningKey,
+    seqno: u64,
     config: GossipConfig,
     heartbeat: Instant,
     ping_pong: Instant,
-    // TODO: add pending pings
-    // to clean when received
-    // and check expired pings to
-    // remove unresponsive nodes
-    // from the routing table
+    pings: HashMap<MessageKey, (SocketAddr, Instant)>,
 }
 
 impl GossipConfig {
@@ -73,6 +111,7 @@ impl GossipConfig {
     /// * interval - the length of a heartbeat
     /// * check - the number of heartbeats between sending ping messages
     /// 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         history_len: usize,
@@ -95,10 +134,76 @@ impl GossipConfig {
             min_gossip,
             factor,
             interval,
-            check
+            check,
+            max_messages: 5_000,
+            max_bytes: 16 * 1024 * 1024,
+            max_seen: 50_000,
+            seen_len: 120,
+            max_iwant_len: 500,
+            max_retransmissions: 3,
         }
     }
 
+    /// Limits the number of messages kept in the cache and the bytes they take up,
+    /// the oldest messages are dropped first once either limit is reached. The
+    /// same limits apply to the messages waiting on asynchronous validation.
+    /// 
+    /// # Arguments
+    /// 
+    /// * max_messages - the maximum number of messages to keep in the cache
+    /// * max_bytes - the maximum number of bytes of messages to keep in the cache
+    /// 
+    pub fn with_cache_limits(mut self, max_messages: usize, max_bytes: usize) -> GossipConfig {
+        self.max_messages = max_messages;
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Limits the number of ids of seen messages that are remembered, the oldest
+    /// ids are forgotten first once the limit is reached. The limit is never less
+    /// than the maximum number of messages kept in the cache.
+    /// 
+    /// # Arguments
+    /// 
+    /// * max_seen - the maximum number of ids of seen messages to remember
+    /// 
+    pub fn with_max_seen(mut self, max_seen: usize) -> GossipConfig {
+        self.max_seen = max_seen;
+        self
+    }
+
+    /// Sets the number of heartbeats the ids of seen messages are remembered for,
+    /// which is never less than the number of heartbeats messages are cached for
+    /// 
+    /// # Arguments
+    /// 
+    /// * seen_len - the number of heartbeats to remember the ids of seen messages
+    /// 
+    pub fn with_seen_len(mut self, seen_len: usize) -> GossipConfig {
+        self.seen_len = seen_len;
+        self
+    }
+
+    /// Limits how many messages are served in answer to IWants. Repeated ids in an
+    /// IWant are served once, and a peer that asks for more ids than max_iwant_len or
+    /// for a message it has already been sent max_retransmissions times is penalised.
+    /// 
+    /// # Arguments
+    /// 
+    /// * max_iwant_len - the maximum number of messages to serve from a single IWant
+    /// * max_retransmissions - the maximum number of times to serve a message to the same peer
+    /// 
+    pub fn with_iwant_limits(mut self, max_iwant_len: usize, max_retransmissions: usize) -> GossipConfig {
+        self.max_iwant_len = max_iwant_len;
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    /// Returns the protocol id of the network
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     /// Return the minimum number of peers to gossip to
     pub fn min(&self) -> usize {
         self.min_gossip
@@ -110,6 +215,23 @@ impl GossipConfig {
     }
 }
 
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig::new(
+            String::from("udp2p-gossip"),
+            8,
+            3,
+            8,
+            3,
+            12,
+            3,
+            0.4,
+            Duration::from_millis(250),
+            80,
+        )
+    }
+}
+
 impl GossipService {
 
     /// Creates a new instance of GossipService
@@ -125,32 +247,73 @@ impl GossipService {
     /// * heartbeat - the time of the last heartbeat
     /// * ping_pong - the time of the last ping message sent
     /// 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         address: SocketAddr,
         to_gossip_rx: Receiver<(SocketAddr, Message)>,
         to_transport_tx: Sender<(SocketAddr, Message)>,
-        to_app_tx: Sender<GossipMessage>,
-        kad: Kademlia,
+        to_app_tx: Sender<DeliveredMessage>,
+        mut kad: Kademlia,
         config: GossipConfig,
         heartbeat: Instant,
         ping_pong: Instant,
     ) -> GossipService {
+        kad.set_protocol_id(config.id());
+        let cache = MessageCache::new(config.history_len, config.max_messages, config.max_bytes);
+        let validating = ValidationQueue::new(
+            config.interval * config.history_len as u32,
+            config.max_messages,
+            config.max_bytes,
+        );
+        let seen = SeenCache::new(
+            config.interval * cmp::max(config.seen_len, config.history_len) as u32,
+            cmp::max(config.max_seen, config.max_messages),
+        );
         GossipService {
             address,
             to_gossip_rx,
             to_transport_tx,
             to_app_tx,
-            cache: HashMap::new(),
+            cache,
+            served: HashMap::new(),
+            topics: HashSet::new(),
+            peer_topics: HashMap::new(),
+            announced: HashSet::new(),
+            mesh: HashMap::new(),
+            validators: MessageValidators::new(),
+            validating,
+            seen,
+            rejections: HashMap::new(),
+            keypair: SigningKey::generate(&mut OsRng),
+            seqno: timestamp_now() as u64,
             kad,
             config,
             heartbeat,
             ping_pong,
+            pings: HashMap::new(),
         }
     }
 
-    /// The main gossip service loop
+    /// Replaces the identity key that published messages are signed with, which is
+    /// randomly generated unless one is set. The local node's key should be the
+    /// identity's public key, so that its id is the origin of the messages it publishes.
+    /// 
+    /// # Arguments
+    /// 
+    /// * keypair - the local node's identity key
+    /// 
+    pub fn set_identity(&mut self, keypair: SigningKey) {
+        self.keypair = keypair;
+    }
+
+    /// Returns the public key that messages published by the local node are verified with
+    pub fn identity(&self) -> VerifyingKey {
+        self.keypair.verifying_key()
+    }
+
+    /// The main gossip service loop, runs until the kademlia instance is shut down
     pub fn start(&mut self) {
-        loop {
+        while !self.kad.is_stopped() {
             self.kad.recv();
             self.recv();
             self.gossip();
@@ -168,79 +331,461 @@ impl GossipService {
         false 
     }
 
-    /// Dissemenates messages that are still "alive" and in the message cache.
+    /// At each heartbeat announces the local subscriptions to new neighbours, maintains
+    /// the mesh, credits mesh peers with the time spent in the mesh, gossips the ids of
+    /// messages cached in the last history_gossip heartbeats, drops messages older than
+    /// history_len heartbeats from the cache and forgets the ids of messages seen more
+    /// than seen_len heartbeats ago. Every check heartbeats neighbours are pinged.
     pub fn gossip(&mut self) {
         let now = Instant::now();
-        let cache_clone = self.cache.clone();
         if self.heartbeat() {
-            cache_clone.iter().for_each(|(key, (message, expires))| {
-                if now.duration_since(*expires) < self.config.interval * self.config.history_gossip as u32 {
-                    if let Some(gossip_message) = GossipMessage::from_bytes(&message.msg) {
-                        let src = gossip_message.sender;
-                        self.publish(&src, message.clone())     
-                    }
+            self.announce_subscriptions();
+            self.maintain_mesh();
+            self.score_mesh();
+
+            let recent = self.cache.recent(self.config.history_gossip);
+            recent.into_iter().for_each(|(topic, ids)| self.emit_gossip(topic, ids));
+
+            self.cache.shift();
+            self.served.retain(|(_, id), _| self.cache.contains(id));
+            self.seen.prune();
+            self.validating.prune();
+
+            if now.duration_since(self.ping_pong) > self.config.interval * self.config.check as u32 {
+                self.ping_neighbours();
+                self.ping_pong = now;
+            }
+        }
+    }
+
+    /// Sends the ids of recent messages on a topic in an IHave to a random sample of the
+    /// topic's subscribers outside the local node's mesh, who request any they are
+    /// missing with an IWant. The sample is the configured factor of those peers,
+    /// but never fewer than min_gossip. Peers scoring below the gossip threshold
+    /// are left out.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic the messages were published to
+    /// * ids - the ids of the recent messages
+    /// 
+    fn emit_gossip(&mut self, topic: Topic, ids: Vec<InnerKey>) {
+        let mesh = self.mesh.get(&topic).cloned().unwrap_or_default();
+        let mut peers: Vec<SocketAddr> = self
+            .subscribers(&topic)
+            .into_iter()
+            .filter(|peer| !mesh.contains(peer) && self.kad.scores().accepts_gossip(peer))
+            .collect();
+        let n_peers = cmp::max(self.config.min_gossip, (peers.len() as f64 * self.config.factor) as usize);
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(n_peers);
+        self.send_rpc(&peers, GossipRpc::IHave(topic, ids));
+    }
+
+    /// Subscribes the local node to a topic, so messages published to it are
+    /// delivered to the application, announces the subscription to neighbours
+    /// and grafts up to the target number of its subscribers into the topic's mesh.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to subscribe to
+    /// 
+    pub fn subscribe(&mut self, topic: &str) {
+        if self.topics.insert(topic.to_string()) {
+            let neighbours: Vec<SocketAddr> = self.announced.iter().copied().collect();
+            self.send_rpc(&neighbours, GossipRpc::Subscribe(topic.to_string()));
+            self.mesh.insert(topic.to_string(), HashSet::new());
+            self.graft(topic, self.config.target);
+        }
+    }
+
+    /// Unsubscribes the local node from a topic, announces it to neighbours
+    /// and prunes every peer in the topic's mesh.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to unsubscribe from
+    /// 
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if self.topics.remove(topic) {
+            let neighbours: Vec<SocketAddr> = self.announced.iter().copied().collect();
+            self.send_rpc(&neighbours, GossipRpc::Unsubscribe(topic.to_string()));
+            if let Some(mesh) = self.mesh.remove(topic) {
+                let mesh: Vec<SocketAddr> = mesh.into_iter().collect();
+                self.send_rpc(&mesh, GossipRpc::Prune(topic.to_string()));
+            }
+        }
+    }
+
+    /// Returns the peers in the local node's mesh for a topic
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to get the mesh of
+    /// 
+    pub fn mesh(&self, topic: &str) -> Vec<SocketAddr> {
+        self.mesh
+            .get(topic)
+            .map(|mesh| mesh.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Keeps the mesh of each subscribed topic between the low and high number of
+    /// peers. Peers that have unsubscribed or left are dropped, peers scoring below
+    /// zero are pruned, if too few peers remain subscribers are grafted up to the
+    /// target, and if there are too many peers random peers are pruned down to the target.
+    fn maintain_mesh(&mut self) {
+        let topics: Vec<Topic> = self.mesh.keys().cloned().collect();
+        topics.iter().for_each(|topic| {
+            let subscribers: HashSet<SocketAddr> = self.subscribers(topic).into_iter().collect();
+            let scores = self.kad.scores();
+            let mut negative = vec![];
+            let mut mesh: Vec<SocketAddr> = match self.mesh.get_mut(topic) {
+                Some(mesh) => {
+                    mesh.retain(|peer| subscribers.contains(peer));
+                    negative.extend(mesh.iter().filter(|peer| scores.score(peer) < 0.0));
+                    mesh.retain(|peer| scores.score(peer) >= 0.0);
+                    mesh.iter().copied().collect()
                 }
-                if now.duration_since(*expires) > self.config.interval * self.config.history_len as u32 {
-                    self.cache.remove(&key);
+                None => return,
+            };
+            self.send_rpc(&negative, GossipRpc::Prune(topic.clone()));
+
+            if mesh.len() < self.config.low {
+                self.graft(topic, self.config.target.saturating_sub(mesh.len()));
+            } else if mesh.len() > self.config.high {
+                mesh.shuffle(&mut rand::thread_rng());
+                let pruned = mesh.split_off(self.config.target);
+                if let Some(topic_mesh) = self.mesh.get_mut(topic) {
+                    pruned.iter().for_each(|peer| {
+                        topic_mesh.remove(peer);
+                    });
                 }
-            });
+                self.send_rpc(&pruned, GossipRpc::Prune(topic.clone()));
+            }
+        });
+    }
 
-            // TODO: Add Ping Pong messages every x heartbeats.
-            if now.duration_since(self.ping_pong) > self.config.interval * self.config.check as u32 {
-                // Send Ping message to peers
+    /// Grafts random subscribers of a topic that aren't already in its mesh, and that
+    /// don't score below zero, into the mesh
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to graft peers for
+    /// * count - the number of peers to graft
+    /// 
+    fn graft(&mut self, topic: &str, count: usize) {
+        let mesh = match self.mesh.get(topic) {
+            Some(mesh) => mesh,
+            None => return,
+        };
+        let mut candidates: Vec<SocketAddr> = self
+            .subscribers(topic)
+            .into_iter()
+            .filter(|peer| !mesh.contains(peer) && self.kad.scores().score(peer) >= 0.0)
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(count);
+
+        if let Some(mesh) = self.mesh.get_mut(topic) {
+            mesh.extend(candidates.iter().copied());
+        }
+        self.send_rpc(&candidates, GossipRpc::Graft(topic.to_string()));
+    }
+
+    /// Checks if the local node is subscribed to a topic and returns true or false
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to check
+    /// 
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.topics.contains(topic)
+    }
+
+    /// Returns the neighbours that have announced they are subscribed to a topic
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to get the subscribers of
+    /// 
+    pub fn subscribers(&self, topic: &str) -> Vec<SocketAddr> {
+        self.peer_topics
+            .iter()
+            .filter(|(_, topics)| topics.contains(topic))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Publishes a message to a topic, sending it to the neighbours subscribed to
+    /// the topic. Returns the id of the message.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to publish to
+    /// * data - the message to publish
+    /// 
+    pub fn publish(&mut self, topic: &str, data: MessageData) -> MessageKey {
+        self.seqno += 1;
+        let msg = GossipMessage::new(&self.keypair, self.seqno, topic, data, self.address);
+        let id = MessageKey::from_inner(msg.id);
+
+        let message = GossipEnvelope::message(&self.config.id, GossipRpc::Message(msg.hop()));
+        let src = self.address;
+        self.forward(&src, topic, message.clone());
+        self.seen.insert(id);
+        self.cache.put(id, topic, message);
+        id
+    }
+
+    /// Registers a validator that every message published to a topic by another
+    /// node must be accepted by before it is delivered or forwarded.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to validate
+    /// * validator - the validator for messages on the topic
+    /// 
+    pub fn add_validator(&mut self, topic: &str, validator: Box<dyn MessageValidator>) {
+        self.validators.register(topic, TopicValidator::Sync(validator));
+    }
+
+    /// Validates the messages published to a topic asynchronously. Each message
+    /// is delivered to the application, and is only forwarded once the application
+    /// accepts it with report_validation. Messages that aren't reported within
+    /// history_len heartbeats are dropped, as are the oldest messages waiting once
+    /// more than the cache limits are waiting.
+    /// 
+    /// # Arguments
+    /// 
+    /// * topic - the topic to validate
+    /// 
+    pub fn add_async_validator(&mut self, topic: &str) {
+        self.validators.register(topic, TopicValidator::Async);
+    }
+
+    /// Reports the application's validation of a message on an asynchronously
+    /// validated topic. Accepted messages are forwarded, and rejected messages
+    /// are counted against the peer they came from.
+    /// 
+    /// # Arguments
+    /// 
+    /// * id - the id of the message
+    /// * validation - the result of validating the message
+    /// 
+    pub fn report_validation(&mut self, id: MessageKey, validation: Validation) {
+        if let Some((src, msg)) = self.validating.remove(&id) {
+            if let Some(GossipRpc::Message(message)) = GossipEnvelope::open(&msg).map(|envelope| envelope.rpc) {
+                self.apply_validation(&src, message, validation, None);
             }
         }
     }
 
-    /// Forwards a message and an intended destination address to the transport layer
+    /// Returns the number of messages from a peer that have been rejected
     /// 
     /// # Arguments
     /// 
-    /// * src - the destination socket address
-    /// * message - a Message to be packetized and forwarded to the destination
+    /// * peer - the peer to check
     /// 
-    pub fn publish(&mut self, src: &SocketAddr, message: Message) {
-        let local = self.kad.routing_table.local_info.clone();
-        let gossip_to = {
-            let mut sample = HashSet::new();
-            let peers = self.kad.routing_table.get_closest_peers(local, 30).clone();
-            if peers.len() > 7 {
-
-                let infection_factor = self.config.factor;
-                let n_peers = peers.len() as f64 * infection_factor;
-                for _ in 0..n_peers as usize {
-                    let rn: usize = rand::thread_rng().gen_range(0..peers.len());
-                    let address = peers[rn].get_address();
-                    if &address != src && address != self.address {
-                        sample.insert(address);
-                    }
+    pub fn rejections(&self, peer: &SocketAddr) -> usize {
+        self.rejections.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Acts on the validation of a message. Accepted messages are delivered to the
+    /// application if the local node is subscribed to their topic, then forwarded
+    /// with one more hop and cached. Rejected messages are counted against the peer
+    /// they came from.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the peer the message came from
+    /// * message - the gossip message
+    /// * validation - the result of validating the message
+    /// * deliver - the status to deliver the message to the application with, if it
+    ///   still needs to be delivered
+    /// 
+    fn apply_validation(&mut self, src: &SocketAddr, message: GossipMessage, validation: Validation, deliver: Option<ValidationStatus>) {
+        match validation {
+            Validation::Accept => {
+                if let Some(status) = deliver {
+                    self.deliver(src, &message, status);
                 }
-            
-                sample
-
-            } else {
-                peers.iter().for_each(|peer| {
-                    let address = peer.get_address();
-                    if address != self.address {
-                        sample.insert(address);
-                    }
-                });
+                if *src != self.address {
+                    self.kad.scores_mut().first_delivery(*src);
+                }
+                let key = MessageKey::from_inner(message.id);
+                let msg = GossipEnvelope::message(&self.config.id, GossipRpc::Message(message.hop()));
+                self.forward(src, &message.topic, msg.clone());
+                self.cache.put(key, &message.topic, msg);
+            }
+            Validation::Reject => {
+                info!("Rejected message {:?} from {:?}", message.id, src);
+                *self.rejections.entry(*src).or_insert(0) += 1;
+                self.kad.scores_mut().invalid(*src);
+            }
+            Validation::Ignore => {}
+        }
+    }
+
+    /// Credits each peer in a mesh with the time since the last heartbeat
+    fn score_mesh(&mut self) {
+        let peers: HashSet<SocketAddr> = self.mesh.values().flatten().copied().collect();
+        let interval = self.config.interval;
+        peers.into_iter().for_each(|peer| self.kad.scores_mut().in_mesh(peer, interval));
+    }
+
+    /// Delivers a message to the application if the local node is subscribed to
+    /// its topic and it didn't come from the local node, along with the peer that
+    /// relayed it, the time it was received and how it was validated.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the peer the message came from
+    /// * message - the gossip message
+    /// * validation - how the message was validated
+    /// 
+    fn deliver(&self, src: &SocketAddr, message: &GossipMessage, validation: ValidationStatus) {
+        if *src == self.address || !self.topics.contains(&message.topic) {
+            return;
+        }
+        let delivered = DeliveredMessage {
+            message: message.clone(),
+            origin: message.origin_id(),
+            relayed_by: *src,
+            hops: message.hops,
+            received: timestamp_now(),
+            validation,
+        };
+        if let Err(e) = self.to_app_tx.send(delivered) {
+            info!("Error sending message to application layer: {:?}", e)
+        }
+    }
 
-                sample
+    /// Forwards a message to the local node's mesh for its topic, never sending it back to
+    /// the peer it came from or to the local node. Messages on topics the local node isn't
+    /// subscribed to are sent to a random sample of the topic's subscribers, up to the target.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the peer the message came from
+    /// * topic - the topic the message was published to
+    /// * message - a Message to be packetized and forwarded to the destination
+    /// 
+    pub fn forward(&mut self, src: &SocketAddr, topic: &str, message: Message) {
+        let gossip_to: Vec<SocketAddr> = match self.mesh.get(topic) {
+            Some(mesh) => mesh
+                .iter()
+                .filter(|address| *address != src && **address != self.address)
+                .copied()
+                .collect(),
+            None => {
+                let mut fanout: Vec<SocketAddr> = self
+                    .subscribers(topic)
+                    .into_iter()
+                    .filter(|address| address != src && *address != self.address)
+                    .filter(|address| self.kad.scores().accepts_gossip(address))
+                    .collect();
+                fanout.shuffle(&mut rand::thread_rng());
+                fanout.truncate(self.config.target);
+                fanout
             }
         };
 
         gossip_to.iter().for_each(|peer| {
-            if let Err(_) = self.to_transport_tx.send((peer.clone(), message.clone())) {
+            if self.to_transport_tx.send((*peer, message.clone())).is_err() {
                 println!("Error forwarding to transport")
             }
         });
     }
 
+    /// Drops the neighbours that haven't answered a ping within check heartbeats, then
+    /// pings every neighbour that isn't already waiting on one. Unresponsive neighbours
+    /// are no longer sampled for gossip or kept in any mesh, and are reported to
+    /// kademlia to be evicted from the routing table.
+    pub fn ping_neighbours(&mut self) {
+        let timeout = self.config.interval * self.config.check as u32;
+        let expired: Vec<MessageKey> = self
+            .pings
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.iter().for_each(|id| {
+            if let Some((peer, _)) = self.pings.remove(id) {
+                info!("Neighbour {:?} didn't answer a ping", peer);
+                self.peer_topics.remove(&peer);
+                self.announced.remove(&peer);
+                self.mesh.values_mut().for_each(|mesh| {
+                    mesh.remove(&peer);
+                });
+                self.kad.report_unresponsive(&peer);
+            }
+        });
+
+        let pinging: HashSet<SocketAddr> = self.pings.values().map(|(peer, _)| *peer).collect();
+        let neighbours: Vec<SocketAddr> = self.announced.difference(&pinging).copied().collect();
+        neighbours.into_iter().for_each(|peer| {
+            let id = MessageKey::rand();
+            self.pings.insert(id, (peer, Instant::now()));
+            self.send_rpc(&[peer], GossipRpc::Ping(id.inner()));
+        });
+    }
+
+    /// Announces each of the local subscriptions to neighbours that have joined the
+    /// routing table since the last heartbeat, and forgets the subscriptions of, and
+    /// pings to, neighbours that have left it.
+    fn announce_subscriptions(&mut self) {
+        let neighbours: HashSet<SocketAddr> = self
+            .kad
+            .routing_table
+            .get_all_peers()
+            .iter()
+            .map(|peer| peer.get_address())
+            .filter(|address| *address != self.address)
+            .collect();
+        self.peer_topics.retain(|peer, _| neighbours.contains(peer));
+        self.announced.retain(|peer| neighbours.contains(peer));
+        self.pings.retain(|_, (peer, _)| neighbours.contains(peer));
+
+        let new: Vec<SocketAddr> = neighbours.difference(&self.announced).copied().collect();
+        self.topics.clone().into_iter().for_each(|topic| {
+            self.send_rpc(&new, GossipRpc::Subscribe(topic));
+        });
+        self.announced.extend(new);
+    }
+
+    /// Sends a gossip rpc to each of the peers provided
+    /// 
+    /// # Arguments
+    /// 
+    /// * peers - the peers to send the rpc to
+    /// * rpc - the rpc to send
+    /// 
+    fn send_rpc(&self, peers: &[SocketAddr], rpc: GossipRpc) {
+        let message = GossipEnvelope::message(&self.config.id, rpc);
+        peers.iter().for_each(|peer| {
+            if self.to_transport_tx.send((*peer, message.clone())).is_err() {
+                println!("Error forwarding to transport")
+            }
+        });
+    }
 
-    /// handles an incoming message by placing it in the cache and forwarding to peers
-    /// if it is not already in the cache. If it is already in the cache it is ignored
-    /// If the protocol is 'chat' and the source is not the local node it prints the message
+    /// handles an incoming message. Messages from other networks, whose envelope carries a
+    /// different protocol id, are rejected. A new gossip message is rejected unless it is signed by
+    /// its origin and its id matches its origin and sequence number, without being marked
+    /// as seen so that it can't suppress the genuine message. Otherwise it is validated
+    /// with the validator for its topic, unless it came from the local node. Once accepted it is placed in the cache
+    /// and forwarded to peers subscribed to its topic, and delivered to the application if the
+    /// local node is subscribed to it and it didn't come from the local node. Messages on
+    /// asynchronously validated topics are delivered first and held until the application
+    /// reports on them. Messages that have already been seen are ignored. Subscription
+    /// announcements update the sender's topics. Grafts add the sender to the topic's mesh,
+    /// or are answered with a prune if the local node isn't subscribed to the topic or the
+    /// sender scores below zero, and prunes remove the sender from the mesh.
+    /// IHaves for subscribed topics are answered with an IWant for the messages that
+    /// haven't been seen, and IWants are answered with the full messages still in the cache,
+    /// up to the configured IWant limits.
+    /// Pings are answered with a pong, and pongs clear the ping they answer.
     /// 
     /// # Arguments
     /// 
@@ -248,28 +793,135 @@ impl GossipService {
     /// * msg - the incoming message to be handled
     /// 
     fn handle_message(&mut self, src: &SocketAddr, msg: &Message) {
-        if let Some(message) = GossipMessage::from_bytes(&msg.msg){
-            if !self.cache.contains_key(&MessageKey::from_inner(message.id)) {
-                if *src != self.address {
-                    if let Err(e) = self.to_app_tx.send(message.clone()) {
-                        info!("Error sending message to application layer: {:?}", e)
+        if self.kad.scores().is_banned(src) {
+            return;
+        }
+        let rpc = match GossipEnvelope::open(msg) {
+            Some(envelope) if envelope.protocol == self.config.id => envelope.rpc,
+            Some(envelope) => {
+                info!("Rejected gossip from {:?} on the {:?} network", src, envelope.protocol);
+                self.kad.events().publish(Event::ProtocolMismatch(*src, envelope.protocol));
+                return;
+            }
+            None => return,
+        };
+        match rpc {
+            GossipRpc::Message(message) => {
+                let key = MessageKey::from_inner(message.id);
+                if self.seen.contains(&key) {
+                    return;
+                }
+                if !message.verify() {
+                    self.apply_validation(src, message, Validation::Reject, None);
+                    return;
+                }
+                self.seen.insert(key);
+
+                let (validation, status) = match self.validators.get(&message.topic) {
+                    _ if *src == self.address => (Validation::Accept, ValidationStatus::Unvalidated),
+                    Some(TopicValidator::Sync(validator)) => (validator.validate(&message), ValidationStatus::Validated),
+                    Some(TopicValidator::Async) => {
+                        self.deliver(src, &message, ValidationStatus::Pending);
+                        self.validating.insert(key, *src, msg.clone());
+                        return;
+                    }
+                    None => (Validation::Accept, ValidationStatus::Unvalidated),
+                };
+                self.apply_validation(src, message, validation, Some(status));
+            }
+            GossipRpc::Subscribe(topic) => {
+                self.peer_topics.entry(*src).or_default().insert(topic);
+            }
+            GossipRpc::Unsubscribe(topic) => {
+                if let Some(topics) = self.peer_topics.get_mut(src) {
+                    topics.remove(&topic);
+                }
+                if let Some(mesh) = self.mesh.get_mut(&topic) {
+                    mesh.remove(src);
+                }
+            }
+            GossipRpc::Graft(topic) => {
+                self.peer_topics.entry(*src).or_default().insert(topic.clone());
+                let accepted = self.kad.scores().score(src) >= 0.0;
+                match self.mesh.get_mut(&topic) {
+                    Some(mesh) if accepted => {
+                        mesh.insert(*src);
+                    }
+                    _ => self.send_rpc(&[*src], GossipRpc::Prune(topic)),
+                }
+            }
+            GossipRpc::Prune(topic) => {
+                if let Some(mesh) = self.mesh.get_mut(&topic) {
+                    mesh.remove(src);
+                }
+            }
+            GossipRpc::IHave(topic, ids) if self.topics.contains(&topic) => {
+                let missing: Vec<InnerKey> = ids
+                    .into_iter()
+                    .filter(|id| !self.seen.contains(&MessageKey::from_inner(*id)))
+                    .collect();
+                if !missing.is_empty() {
+                    self.send_rpc(&[*src], GossipRpc::IWant(missing));
+                }
+            }
+            GossipRpc::Ping(id) => self.send_rpc(&[*src], GossipRpc::Pong(id)),
+            GossipRpc::Pong(id) => {
+                let id = MessageKey::from_inner(id);
+                if let Some((peer, sent)) = self.pings.get(&id).copied() {
+                    if peer == *src {
+                        self.pings.remove(&id);
+                        self.kad.scores_mut().latency(peer, sent.elapsed());
                     }
                 }
-                let key = MessageKey::from_inner(message.id);
-                self.publish(src, msg.clone());
-                self.cache.entry(key).or_insert((msg.clone(), Instant::now()));
             }
+            GossipRpc::IWant(ids) => self.serve_iwant(src, ids),
+            _ => {}
+        }
+    }
+
+    /// Sends a peer the cached messages it asked for in an IWant. Each id is served
+    /// once, no more than max_iwant_len messages are served, and a message isn't
+    /// served to the same peer more than max_retransmissions times. A peer that
+    /// asks for more than that is penalised once for the IWant.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the peer that sent the IWant
+    /// * ids - the ids of the messages the peer asked for
+    /// 
+    fn serve_iwant(&mut self, src: &SocketAddr, ids: Vec<InnerKey>) {
+        let mut wanted = HashSet::new();
+        let ids: Vec<MessageKey> = ids
+            .into_iter()
+            .map(MessageKey::from_inner)
+            .filter(|id| wanted.insert(*id))
+            .collect();
+        let mut excessive = ids.len() > self.config.max_iwant_len;
+        ids.into_iter().take(self.config.max_iwant_len).for_each(|id| {
+            let Some(message) = self.cache.get(&id) else {
+                return;
+            };
+            let served = self.served.entry((*src, id)).or_default();
+            if *served >= self.config.max_retransmissions {
+                excessive = true;
+                return;
+            }
+            *served += 1;
+            if self.to_transport_tx.send((*src, message.clone())).is_err() {
+                println!("Error forwarding to transport")
+            }
+        });
+        if excessive {
+            info!("Penalised {:?} for an IWant beyond the limits", src);
+            self.kad.scores_mut().misbehaved(*src);
         }
     }
 
     /// receives messages coming into the "to_gossip_rx"
     pub fn recv(&mut self) {
         let res = self.to_gossip_rx.try_recv();
-        match res {
-            Ok((src, msg)) => {
-                self.handle_message(&src, &msg);
-            }
-            Err(_) => {}
+        if let Ok((src, msg)) = res {
+            self.handle_message(&src, &msg);
         }
     }
 }
@@ -1,5 +1,663 @@
+pub mod cache;
 pub mod gossip;
 pub mod protocol;
+pub mod validator;
 
 #[cfg(test)]
-mod tests { }
+mod tests {
+
+    use crate::cache::{MessageCache, SeenCache, ValidationQueue};
+    use crate::gossip::{GossipConfig, GossipService};
+    use crate::protocol::{DeliveredMessage, GossipEnvelope, GossipMessage, GossipRpc};
+    use crate::validator::{Validation, ValidationStatus};
+    use udp2p_discovery::kad::Kademlia;
+    use udp2p_discovery::routing::RoutingTable;
+    use udp2p_node::peer_id::PeerId;
+    use udp2p_node::peer_info::PeerInfo;
+    use udp2p_node::peer_key::Key;
+    use udp2p_protocol::event::Event;
+    use udp2p_protocol::protocol::{Header, InnerKey, Message, MessageKey};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::{Duration, Instant};
+    use udp2p_utils::utils::{timestamp_now, ByteRep};
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    type TestGossip = (
+        GossipService,
+        Sender<(SocketAddr, Message)>,
+        Receiver<(SocketAddr, Message)>,
+        Receiver<DeliveredMessage>,
+        Vec<PeerInfo>,
+    );
+
+    fn peer(port: u16) -> PeerInfo {
+        let key = Key::rand();
+        let address: SocketAddr = format!("127.0.0.1:{}", port).parse().expect("Unable to parse address");
+        PeerInfo::new(PeerId::from_key(&key), key, address)
+    }
+
+    fn setup_gossip(n_peers: u16) -> TestGossip {
+        setup_gossip_with(n_peers, GossipConfig::default())
+    }
+
+    fn setup_gossip_with(n_peers: u16, config: GossipConfig) -> TestGossip {
+        let local = peer(30000);
+        let (to_transport_tx, to_transport_rx) = channel();
+        let (to_gossip_tx, to_gossip_rx) = channel();
+        let (_to_kad_tx, to_kad_rx) = channel();
+        let (to_app_tx, to_app_rx) = channel();
+        let mut kad = Kademlia::new(
+            RoutingTable::new(local.clone()),
+            to_transport_tx.clone(),
+            to_kad_rx,
+            HashMap::new(),
+            Duration::from_secs(20),
+            Instant::now(),
+        );
+        let peers: Vec<PeerInfo> = (1..=n_peers).map(|n| peer(30000 + n)).collect();
+        peers.iter().for_each(|peer| kad.add_peer(peer.as_bytes().unwrap()));
+
+        let gossip = GossipService::new(
+            local.address,
+            to_gossip_rx,
+            to_transport_tx,
+            to_app_tx,
+            kad,
+            config,
+            Instant::now(),
+            Instant::now(),
+        );
+        (gossip, to_gossip_tx, to_transport_rx, to_app_rx, peers)
+    }
+
+    fn heartbeat(gossip: &mut GossipService) {
+        std::thread::sleep(Duration::from_millis(300));
+        gossip.gossip();
+    }
+
+    fn send_rpc(to_gossip_tx: &Sender<(SocketAddr, Message)>, gossip: &mut GossipService, src: SocketAddr, rpc: GossipRpc) {
+        to_gossip_tx.send((src, envelope(rpc))).unwrap();
+        gossip.recv();
+    }
+
+    fn rpc(message: &Message) -> GossipRpc {
+        GossipEnvelope::open(message).unwrap().rpc
+    }
+
+    fn envelope(rpc: GossipRpc) -> Message {
+        GossipEnvelope::message(GossipConfig::default().id(), rpc)
+    }
+
+    fn signed(topic: &str, data: &[u8], sender: SocketAddr) -> GossipMessage {
+        GossipMessage::new(&SigningKey::generate(&mut OsRng), 1, topic, data.to_vec(), sender)
+    }
+
+    fn gossip_message(topic: &str, sender: SocketAddr) -> Message {
+        envelope(GossipRpc::Message(signed(topic, b"data", sender)))
+    }
+
+    #[test]
+    fn gossip_only_sends_topics_to_subscribers() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(3);
+        transport_rx.try_iter().for_each(drop);
+
+        // Subscriptions are announced to neighbours at the next heartbeat
+        gossip.subscribe("blocks");
+        heartbeat(&mut gossip);
+        let mut announced: Vec<SocketAddr> = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(rpc(message), GossipRpc::Subscribe(topic) if topic == "blocks"))
+            .map(|(address, _)| address)
+            .collect();
+        announced.sort();
+        assert_eq!(announced, peers.iter().map(|peer| peer.address).collect::<Vec<_>>());
+
+        // Messages are only sent to the peers subscribed to their topic
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Subscribe("blocks".to_string()));
+        assert_eq!(gossip.subscribers("blocks"), vec![peers[0].address]);
+        heartbeat(&mut gossip);
+        assert_eq!(gossip.mesh("blocks"), vec![peers[0].address]);
+        transport_rx.try_iter().for_each(drop);
+        gossip.publish("blocks", b"block".to_vec());
+        gossip.publish("txns", b"txn".to_vec());
+        let sent: Vec<(SocketAddr, GossipRpc)> = transport_rx.try_iter().map(|(address, message)| (address, rpc(&message))).collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, peers[0].address);
+        assert!(matches!(&sent[0].1, GossipRpc::Message(message) if message.topic == "blocks"));
+
+        // Only messages on subscribed topics are delivered to the application
+        to_gossip_tx.send((peers[1].address, gossip_message("txns", peers[1].address))).unwrap();
+        to_gossip_tx.send((peers[1].address, gossip_message("blocks", peers[1].address))).unwrap();
+        gossip.recv();
+        gossip.recv();
+        let delivered: Vec<DeliveredMessage> = app_rx.try_iter().collect();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].message.topic, "blocks");
+
+        gossip.unsubscribe("blocks");
+        assert!(!gossip.is_subscribed("blocks"));
+        let unsubscribed = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(rpc(message), GossipRpc::Unsubscribe(_)))
+            .count();
+        assert_eq!(unsubscribed, peers.len());
+    }
+
+    #[test]
+    fn gossip_keeps_mesh_between_low_and_high() {
+        let (mut gossip, to_gossip_tx, transport_rx, _app_rx, peers) = setup_gossip(20);
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+        });
+        heartbeat(&mut gossip);
+        transport_rx.try_iter().for_each(drop);
+
+        // Subscribing grafts the target number of subscribers
+        gossip.subscribe("blocks");
+        let grafted: Vec<SocketAddr> = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(rpc(message), GossipRpc::Graft(_)))
+            .map(|(address, _)| address)
+            .collect();
+        let mut mesh = gossip.mesh("blocks");
+        assert_eq!(mesh.len(), 8);
+        mesh.sort();
+        let mut sorted = grafted.clone();
+        sorted.sort();
+        assert_eq!(sorted, mesh);
+
+        // Full messages are only pushed along the mesh
+        gossip.publish("blocks", b"block".to_vec());
+        let mut sent: Vec<SocketAddr> = transport_rx.try_iter().map(|(address, _)| address).collect();
+        sent.sort();
+        assert_eq!(sent, mesh);
+
+        // Too few peers are topped back up to the target
+        mesh[..6].iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, *peer, GossipRpc::Prune("blocks".to_string()));
+        });
+        assert_eq!(gossip.mesh("blocks").len(), 2);
+        heartbeat(&mut gossip);
+        assert_eq!(gossip.mesh("blocks").len(), 8);
+        transport_rx.try_iter().for_each(drop);
+
+        // Too many peers are pruned back down to the target
+        let outside: Vec<SocketAddr> = peers
+            .iter()
+            .map(|peer| peer.address)
+            .filter(|peer| !gossip.mesh("blocks").contains(peer))
+            .take(6)
+            .collect();
+        outside.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, *peer, GossipRpc::Graft("blocks".to_string()));
+        });
+        assert_eq!(gossip.mesh("blocks").len(), 14);
+        heartbeat(&mut gossip);
+        assert_eq!(gossip.mesh("blocks").len(), 8);
+        let pruned = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(rpc(message), GossipRpc::Prune(_)))
+            .count();
+        assert_eq!(pruned, 6);
+
+        // Grafts for topics the local node isn't subscribed to are pruned
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Graft("txns".to_string()));
+        let (address, message) = transport_rx.try_recv().unwrap();
+        assert_eq!(address, peers[0].address);
+        assert!(matches!(rpc(&message), GossipRpc::Prune(topic) if topic == "txns"));
+        assert!(gossip.mesh("txns").is_empty());
+    }
+
+    #[test]
+    fn gossip_sends_ids_lazily_outside_the_mesh() {
+        let (mut gossip, to_gossip_tx, transport_rx, _app_rx, peers) = setup_gossip(20);
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+        });
+        gossip.subscribe("blocks");
+        heartbeat(&mut gossip);
+        let mesh = gossip.mesh("blocks");
+        let id = gossip.publish("blocks", b"block".to_vec());
+        transport_rx.try_iter().for_each(drop);
+
+        // Only ids are gossiped, and only to a sample of subscribers outside the mesh
+        heartbeat(&mut gossip);
+        let sent: Vec<(SocketAddr, GossipRpc)> = transport_rx.try_iter().map(|(address, message)| (address, rpc(&message))).collect();
+        assert_eq!(sent.len(), 4);
+        sent.iter().for_each(|(address, rpc)| {
+            assert!(!mesh.contains(address));
+            assert!(matches!(rpc, GossipRpc::IHave(topic, ids) if topic == "blocks" && ids == &vec![id.inner()]));
+        });
+
+        // Missing messages are requested, and known ones are not
+        let unknown = MessageKey::rand().inner();
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IHave("blocks".to_string(), vec![id.inner(), unknown]));
+        let (address, message) = transport_rx.try_recv().unwrap();
+        assert_eq!(address, peers[0].address);
+        assert!(matches!(rpc(&message), GossipRpc::IWant(ids) if ids == vec![unknown]));
+        assert!(transport_rx.try_recv().is_err());
+
+        // Requested messages are served from the cache
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::IWant(vec![id.inner(), unknown]));
+        let (address, message) = transport_rx.try_recv().unwrap();
+        assert_eq!(address, peers[1].address);
+        assert!(matches!(rpc(&message), GossipRpc::Message(message) if message.id == id.inner()));
+        assert!(transport_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn gossip_limits_iwants_and_penalises_repeats() {
+        let config = GossipConfig::default().with_iwant_limits(2, 1);
+        let (mut gossip, to_gossip_tx, transport_rx, _app_rx, peers) = setup_gossip_with(2, config);
+        let ids: Vec<InnerKey> = (0..3).map(|n| gossip.publish("blocks", vec![n]).inner()).collect();
+        transport_rx.try_iter().for_each(drop);
+        let served = |transport_rx: &Receiver<(SocketAddr, Message)>| -> Vec<InnerKey> {
+            transport_rx
+                .try_iter()
+                .filter_map(|(_, message)| match rpc(&message) {
+                    GossipRpc::Message(message) => Some(message.id),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Repeated ids are served once
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IWant(vec![ids[0], ids[0]]));
+        assert_eq!(served(&transport_rx), vec![ids[0]]);
+        assert_eq!(gossip.kad.scores().score(&peers[0].address), 0.0);
+
+        // No more than max_iwant_len messages are served, and asking for more is penalised
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::IWant(ids.clone()));
+        assert_eq!(served(&transport_rx), ids[..2].to_vec());
+        assert!(gossip.kad.scores().score(&peers[1].address) < 0.0);
+
+        // A message isn't served to the same peer more than max_retransmissions times
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IWant(vec![ids[0]]));
+        assert!(served(&transport_rx).is_empty());
+        assert!(gossip.kad.scores().score(&peers[0].address) < 0.0);
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IWant(vec![ids[1]]));
+        assert_eq!(served(&transport_rx), vec![ids[1]]);
+    }
+
+    #[test]
+    fn gossip_bounds_the_cache_and_remembers_ids_longer() {
+        let config = GossipConfig::new(
+            String::from("udp2p-gossip"),
+            2,
+            1,
+            8,
+            3,
+            12,
+            3,
+            0.4,
+            Duration::from_millis(250),
+            80,
+        )
+        .with_cache_limits(3, usize::MAX)
+        .with_seen_len(40);
+        let (mut gossip, to_gossip_tx, transport_rx, _app_rx, peers) = setup_gossip_with(1, config);
+        let ids: Vec<MessageKey> = (0..4).map(|n| gossip.publish("blocks", vec![n])).collect();
+        transport_rx.try_iter().for_each(drop);
+
+        // The oldest message is dropped once the cache is full
+        let wanted: Vec<InnerKey> = ids.iter().map(|id| id.inner()).collect();
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IWant(wanted.clone()));
+        let served: Vec<InnerKey> = transport_rx
+            .try_iter()
+            .filter_map(|(_, message)| match rpc(&message) {
+                GossipRpc::Message(message) => Some(message.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(served, wanted[1..].to_vec());
+
+        // Messages are dropped after history_len heartbeats, but their ids are still seen
+        heartbeat(&mut gossip);
+        heartbeat(&mut gossip);
+        transport_rx.try_iter().for_each(drop);
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IWant(wanted.clone()));
+        assert!(transport_rx.try_recv().is_err());
+        gossip.subscribe("blocks");
+        transport_rx.try_iter().for_each(drop);
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::IHave("blocks".to_string(), wanted));
+        assert!(transport_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn message_cache_drops_the_oldest_messages_over_its_limits() {
+        let mut cache = MessageCache::new(3, 10, 100);
+        let message = |size: usize| Message {
+            head: Header::Gossip,
+            msg: vec![0; size],
+        };
+        let ids: Vec<MessageKey> = (0..4).map(|_| MessageKey::rand()).collect();
+
+        cache.put(ids[0], "blocks", message(40));
+        cache.shift();
+        cache.put(ids[1], "blocks", message(40));
+        cache.put(ids[2], "txns", message(10));
+        assert_eq!((cache.len(), cache.bytes()), (3, 90));
+
+        // Going over the byte limit drops the oldest messages first
+        cache.put(ids[3], "blocks", message(30));
+        assert!(!cache.contains(&ids[0]));
+        assert_eq!((cache.len(), cache.bytes()), (3, 80));
+
+        // Messages too large for the cache are never added
+        cache.put(MessageKey::rand(), "blocks", message(101));
+        assert_eq!(cache.len(), 3);
+
+        // Only the most recent windows are gossiped, and windows past the history are dropped
+        cache.shift();
+        cache.put(ids[0], "blocks", message(10));
+        let recent = cache.recent(1);
+        assert_eq!(recent.get("blocks"), Some(&vec![ids[0].inner()]));
+        assert_eq!(cache.recent(2).get("txns"), Some(&vec![ids[2].inner()]));
+        cache.shift();
+        cache.shift();
+        assert_eq!((cache.len(), cache.bytes()), (1, 10));
+        cache.shift();
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn seen_cache_forgets_the_oldest_ids_over_its_limit() {
+        let mut seen = SeenCache::new(Duration::from_secs(60), 3);
+        let ids: Vec<MessageKey> = (0..4).map(|_| MessageKey::rand()).collect();
+        assert!(ids[..3].iter().all(|id| seen.insert(*id)));
+        assert!(!seen.insert(ids[0]));
+        assert_eq!(seen.len(), 3);
+
+        // The oldest id is forgotten once the limit is reached
+        assert!(seen.insert(ids[3]));
+        assert_eq!(seen.len(), 3);
+        assert!(!seen.contains(&ids[0]));
+        assert!(ids[1..].iter().all(|id| seen.contains(id)));
+
+        // Ids are still forgotten once they are older than the ttl
+        let mut seen = SeenCache::new(Duration::from_millis(10), 3);
+        seen.insert(ids[0]);
+        std::thread::sleep(Duration::from_millis(20));
+        seen.prune();
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn validation_queue_bounds_and_expires_unreported_messages() {
+        let mut queue = ValidationQueue::new(Duration::from_millis(50), 3, 100);
+        let src: SocketAddr = "127.0.0.1:9292".parse().unwrap();
+        let message = |size: usize| Message {
+            head: Header::Gossip,
+            msg: vec![0; size],
+        };
+        let ids: Vec<MessageKey> = (0..5).map(|_| MessageKey::rand()).collect();
+
+        queue.insert(ids[0], src, message(40));
+        queue.insert(ids[1], src, message(40));
+        queue.insert(ids[2], src, message(10));
+        assert_eq!((queue.len(), queue.bytes()), (3, 90));
+
+        // Going over either limit drops the oldest messages first
+        queue.insert(ids[3], src, message(30));
+        assert!(!queue.contains(&ids[0]));
+        assert_eq!((queue.len(), queue.bytes()), (3, 80));
+        queue.insert(ids[4], src, message(5));
+        assert!(!queue.contains(&ids[1]));
+        assert_eq!((queue.len(), queue.bytes()), (3, 45));
+
+        // Reported messages are removed, and the rest expire once the ttl has passed
+        assert_eq!(queue.remove(&ids[2]).map(|(from, _)| from), Some(src));
+        assert!(queue.remove(&ids[2]).is_none());
+        queue.prune();
+        assert_eq!((queue.len(), queue.bytes()), (2, 35));
+        std::thread::sleep(Duration::from_millis(60));
+        queue.prune();
+        assert!(queue.is_empty());
+        assert_eq!(queue.bytes(), 0);
+    }
+
+    #[test]
+    fn gossip_only_forwards_valid_messages() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(3);
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("txns".to_string()));
+        });
+        gossip.subscribe("blocks");
+        gossip.subscribe("txns");
+        gossip.add_validator("blocks", Box::new(|message: &GossipMessage| match message.data.as_slice() {
+            b"valid" => Validation::Accept,
+            b"stale" => Validation::Ignore,
+            _ => Validation::Reject,
+        }));
+        gossip.add_async_validator("txns");
+        transport_rx.try_iter().for_each(drop);
+
+        let message = |topic: &str, data: &[u8]| signed(topic, data, peers[0].address);
+
+        // Rejected and ignored messages are dropped, only rejections count against the sender
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(message("blocks", b"invalid")));
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(message("blocks", b"stale")));
+        assert!(transport_rx.try_recv().is_err());
+        assert!(app_rx.try_recv().is_err());
+        assert_eq!(gossip.rejections(&peers[0].address), 1);
+
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(message("blocks", b"valid")));
+        assert_eq!(app_rx.try_recv().unwrap().validation, ValidationStatus::Validated);
+        assert_eq!(transport_rx.try_iter().count(), 2);
+
+        // Async messages are delivered straight away, but only forwarded once accepted
+        let accepted = message("txns", b"txn");
+        let rejected = message("txns", b"txn");
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(accepted.clone()));
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(rejected.clone()));
+        assert!(app_rx.try_iter().all(|delivered| delivered.validation == ValidationStatus::Pending));
+        assert!(transport_rx.try_recv().is_err());
+
+        gossip.report_validation(MessageKey::from_inner(accepted.id), Validation::Accept);
+        gossip.report_validation(MessageKey::from_inner(rejected.id), Validation::Reject);
+        let sent: Vec<GossipRpc> = transport_rx.try_iter().map(|(_, message)| rpc(&message)).collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|rpc| matches!(rpc, GossipRpc::Message(message) if message.id == accepted.id)));
+        assert!(app_rx.try_recv().is_err());
+        assert_eq!(gossip.rejections(&peers[0].address), 2);
+
+        // Messages that have been seen aren't validated again
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::Message(rejected));
+        assert_eq!(gossip.rejections(&peers[1].address), 0);
+        assert!(transport_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn gossip_only_accepts_messages_signed_by_their_origin() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(3);
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+        });
+        gossip.subscribe("blocks");
+        heartbeat(&mut gossip);
+        transport_rx.try_iter().for_each(drop);
+
+        // Published messages are signed and their ids derived from the origin
+        let id = gossip.publish("blocks", b"block".to_vec());
+        let (_, message) = transport_rx.try_recv().unwrap();
+        match rpc(&message) {
+            GossipRpc::Message(message) => {
+                assert!(message.verify());
+                assert_eq!(message.origin, gossip.identity().to_bytes());
+                assert_eq!(GossipMessage::message_id(&message.origin, message.seqno), id.inner());
+            }
+            rpc => panic!("Expected a message, got {:?}", rpc),
+        }
+        transport_rx.try_iter().for_each(drop);
+
+        // Messages rewritten by a relay, or reusing another origin's id, are rejected
+        let genuine = signed("blocks", b"block", peers[0].address);
+        let mut rewritten = genuine.clone();
+        rewritten.sender = peers[1].address;
+        let mut reused = signed("blocks", b"spam", peers[1].address);
+        reused.id = genuine.id;
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::Message(rewritten));
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::Message(reused));
+        assert!(app_rx.try_recv().is_err());
+        assert!(transport_rx.try_recv().is_err());
+        assert_eq!(gossip.rejections(&peers[1].address), 2);
+
+        // Forgeries don't stop the genuine message being delivered
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Message(genuine.clone()));
+        let delivered = app_rx.try_recv().unwrap();
+        assert_eq!(delivered.message.id, genuine.id);
+        assert_eq!(delivered.message.sender, peers[0].address);
+        assert_eq!(transport_rx.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn gossip_delivers_messages_with_how_they_arrived() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(2);
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Subscribe("blocks".to_string()));
+        gossip.subscribe("blocks");
+        transport_rx.try_iter().for_each(drop);
+
+        // The hop count isn't signed, so relays can increment it
+        let keypair = SigningKey::generate(&mut OsRng);
+        let mut message = GossipMessage::new(&keypair, 1, "blocks", b"block".to_vec(), peers[0].address);
+        message.hops = 2;
+        let before = timestamp_now();
+        send_rpc(&to_gossip_tx, &mut gossip, peers[1].address, GossipRpc::Message(message.clone()));
+
+        let delivered = app_rx.try_recv().unwrap();
+        assert_eq!(delivered.message.id, message.id);
+        assert_eq!(delivered.origin, PeerId::from_key(&Key::new(keypair.verifying_key().to_bytes())));
+        assert_eq!(delivered.relayed_by, peers[1].address);
+        assert_eq!(delivered.hops, 2);
+        assert!(delivered.received >= before && delivered.received <= timestamp_now());
+        assert_eq!(delivered.validation, ValidationStatus::Unvalidated);
+
+        // Forwarded messages carry one more hop
+        let (address, forwarded) = transport_rx.try_recv().unwrap();
+        assert_eq!(address, peers[0].address);
+        assert!(matches!(rpc(&forwarded), GossipRpc::Message(forwarded) if forwarded.hops == 3 && forwarded.verify()));
+    }
+
+    #[test]
+    fn gossip_rejects_other_networks() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(1);
+        assert_eq!(gossip.kad.protocol_id(), GossipConfig::default().id());
+        let events = gossip.kad.events().subscribe();
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Subscribe("blocks".to_string()));
+        gossip.subscribe("blocks");
+        transport_rx.try_iter().for_each(drop);
+
+        // Every rpc carries the local protocol id
+        gossip.publish("blocks", b"block".to_vec());
+        let (_, message) = transport_rx.try_recv().unwrap();
+        assert_eq!(GossipEnvelope::open(&message).unwrap().protocol, GossipConfig::default().id());
+
+        // Rpcs from other networks are rejected
+        let message = GossipEnvelope::message("testnet", GossipRpc::Message(signed("blocks", b"block", peers[0].address)));
+        to_gossip_tx.send((peers[0].address, message)).unwrap();
+        gossip.recv();
+        assert!(app_rx.try_recv().is_err());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::ProtocolMismatch(address, protocol)) if address == peers[0].address && protocol == "testnet"
+        ));
+    }
+
+    #[test]
+    fn gossip_drops_neighbours_that_stop_answering_pings() {
+        let config = GossipConfig::new("udp2p-gossip".to_string(), 8, 3, 8, 3, 12, 3, 0.4, Duration::from_millis(250), 1);
+        let (mut gossip, to_gossip_tx, transport_rx, _app_rx, peers) = setup_gossip_with(3, config);
+        let events = gossip.kad.events().subscribe();
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+        });
+
+        // Neighbours are pinged once the local node knows about them
+        heartbeat(&mut gossip);
+        let pings: Vec<(SocketAddr, InnerKey)> = transport_rx
+            .try_iter()
+            .filter_map(|(address, message)| match rpc(&message) {
+                GossipRpc::Ping(id) => Some((address, id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pings.len(), peers.len());
+
+        // Pings are answered with a pong carrying the same id
+        let id = MessageKey::rand().inner();
+        send_rpc(&to_gossip_tx, &mut gossip, peers[0].address, GossipRpc::Ping(id));
+        let (address, message) = transport_rx.try_recv().unwrap();
+        assert_eq!(address, peers[0].address);
+        assert!(matches!(rpc(&message), GossipRpc::Pong(pong) if pong == id));
+
+        // Neighbours that don't answer are dropped and evicted from the routing table
+        let silent = peers[2].address;
+        pings.iter().filter(|(address, _)| *address != silent).for_each(|(address, id)| {
+            send_rpc(&to_gossip_tx, &mut gossip, *address, GossipRpc::Pong(*id));
+        });
+        heartbeat(&mut gossip);
+        assert!(!gossip.subscribers("blocks").contains(&silent));
+        assert_eq!(gossip.subscribers("blocks").len(), 2);
+        assert!(gossip.kad.routing_table.is_new(&peers[2]));
+        assert!(!gossip.kad.routing_table.is_new(&peers[0]));
+        assert!(events
+            .try_iter()
+            .any(|event| matches!(event, Event::PeerEvicted(peer) if peer.address == silent)));
+    }
+
+    #[test]
+    fn gossip_avoids_low_scoring_peers() {
+        let (mut gossip, to_gossip_tx, transport_rx, app_rx, peers) = setup_gossip(20);
+        peers.iter().for_each(|peer| {
+            send_rpc(&to_gossip_tx, &mut gossip, peer.address, GossipRpc::Subscribe("blocks".to_string()));
+        });
+        gossip.subscribe("blocks");
+        heartbeat(&mut gossip);
+        transport_rx.try_iter().for_each(drop);
+
+        // Mesh peers scoring below zero are pruned, and can't graft themselves back
+        let failing = gossip.mesh("blocks")[0];
+        gossip.kad.scores_mut().failed(failing);
+        heartbeat(&mut gossip);
+        assert!(!gossip.mesh("blocks").contains(&failing));
+        assert!(transport_rx
+            .try_iter()
+            .any(|(address, message)| address == failing && matches!(rpc(&message), GossipRpc::Prune(_))));
+        send_rpc(&to_gossip_tx, &mut gossip, failing, GossipRpc::Graft("blocks".to_string()));
+        assert!(!gossip.mesh("blocks").contains(&failing));
+        assert!(matches!(rpc(&transport_rx.try_recv().unwrap().1), GossipRpc::Prune(_)));
+
+        // Peers below the gossip threshold aren't sent ids
+        let mesh = gossip.mesh("blocks");
+        let outside: Vec<SocketAddr> = peers
+            .iter()
+            .map(|peer| peer.address)
+            .filter(|peer| !mesh.contains(peer) && *peer != failing)
+            .collect();
+        outside[1..].iter().for_each(|peer| gossip.kad.scores_mut().invalid(*peer));
+        gossip.publish("blocks", b"block".to_vec());
+        transport_rx.try_iter().for_each(drop);
+        heartbeat(&mut gossip);
+        let mut gossiped: Vec<SocketAddr> = transport_rx
+            .try_iter()
+            .filter(|(_, message)| matches!(rpc(message), GossipRpc::IHave(..)))
+            .map(|(address, _)| address)
+            .collect();
+        gossiped.sort();
+        let mut expected = vec![outside[0], failing];
+        expected.sort();
+        assert_eq!(gossiped, expected);
+
+        // Banned peers are ignored
+        gossip.subscribe("txns");
+        gossip.kad.scores_mut().ban(peers[0].address);
+        to_gossip_tx.send((peers[0].address, gossip_message("txns", peers[0].address))).unwrap();
+        gossip.recv();
+        assert!(app_rx.try_recv().is_err());
+    }
+}
@@ -1,15 +1,186 @@
+use crate::validator::ValidationStatus;
 use std::net::SocketAddr;
 use udp2p_utils::impl_ByteRep;
-use udp2p_utils::utils::ByteRep;
+use udp2p_utils::utils::{ByteRep, Timestamp};
 use serde::{Serialize, Deserialize};
-use udp2p_protocol::protocol::{InnerKey, MessageData};
+use udp2p_protocol::protocol::{Header, InnerKey, Message, MessageData};
+use udp2p_node::peer_id::PeerId;
+use udp2p_node::peer_key::Key;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
 
-impl_ByteRep!(for GossipMessage);
+impl_ByteRep!(for GossipMessage, GossipRpc, GossipEnvelope);
 
-/// The gossip message 
+/// The name of a topic that messages are published to
+pub type Topic = String;
+
+/// The gossip message. Messages are signed by the node that published them, the
+/// origin, whose public key is carried in the message. The id is derived from the
+/// origin and the sequence number the origin gave the message, so it can't be reused
+/// by another node, and the signature covers every other field but the hop count,
+/// so relays can't rewrite the sender's address or the message. A node's key is the
+/// origin's public key, so the id of the peer that published the message follows
+/// from the key it was signed with. The hop count is incremented by every node
+/// that sends the message, including its origin.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GossipMessage {
     pub id: InnerKey,
+    pub topic: Topic,
     pub data: MessageData,
     pub sender: SocketAddr,
-}
\ No newline at end of file
+    pub origin: [u8; 32],
+    pub seqno: u64,
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub hops: u32,
+}
+
+impl GossipMessage {
+    /// Creates a new gossip message signed by the local node
+    /// 
+    /// # Arguments
+    /// 
+    /// * keypair - the local node's identity key
+    /// * seqno - the sequence number of the message, unique to the local node
+    /// * topic - the topic the message is published to
+    /// * data - the message data
+    /// * sender - the local node's socket address
+    /// 
+    pub fn new(keypair: &SigningKey, seqno: u64, topic: &str, data: MessageData, sender: SocketAddr) -> GossipMessage {
+        let origin = keypair.verifying_key().to_bytes();
+        let mut message = GossipMessage {
+            id: GossipMessage::message_id(&origin, seqno),
+            topic: topic.to_string(),
+            data,
+            sender,
+            origin,
+            seqno,
+            signature: vec![],
+            hops: 0,
+        };
+        message.signature = keypair.sign(&message.signed_bytes()).to_bytes().to_vec();
+        message
+    }
+
+    /// Returns the id of a message, the hash of its origin and sequence number
+    /// 
+    /// # Arguments
+    /// 
+    /// * origin - the public key of the node that published the message
+    /// * seqno - the sequence number of the message
+    /// 
+    pub fn message_id(origin: &[u8; 32], seqno: u64) -> InnerKey {
+        let mut hasher = Sha256::new();
+        hasher.update(origin);
+        hasher.update(seqno.to_be_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Returns the id of the peer that published the message, derived from the public
+    /// key the message was signed with
+    pub fn origin_id(&self) -> PeerId {
+        PeerId::from_key(&Key::new(self.origin))
+    }
+
+    /// Checks that the message's id matches its origin and sequence number, and that
+    /// it was signed by its origin, and returns true or false
+    pub fn verify(&self) -> bool {
+        if self.id != GossipMessage::message_id(&self.origin, self.seqno) {
+            return false;
+        }
+        let signature = match Signature::from_slice(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        match VerifyingKey::from_bytes(&self.origin) {
+            Ok(origin) => origin.verify(&self.signed_bytes(), &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns a copy of the message with one more hop, to be sent on to peers
+    pub fn hop(&self) -> GossipMessage {
+        GossipMessage {
+            hops: self.hops.saturating_add(1),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the bytes of the message that are signed, every field but the hop count and the signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        let fields = (&self.id, &self.topic, &self.data, &self.sender, &self.origin, self.seqno);
+        serde_json::to_vec(&fields).unwrap_or_default()
+    }
+}
+
+/// A gossip message delivered to the application, along with how it got to the
+/// local node: the id of the peer that published it, the peer that
+/// relayed it to the local node, the number of hops it took, when it was received
+/// and how it was validated.
+#[derive(Debug, Clone)]
+pub struct DeliveredMessage {
+    pub message: GossipMessage,
+    pub origin: PeerId,
+    pub relayed_by: SocketAddr,
+    pub hops: u32,
+    pub received: Timestamp,
+    pub validation: ValidationStatus,
+}
+
+/// GossipRpc is an enum of the different messages that a gossip instance
+/// may receive from or send to its neighbours. Subscribe and Unsubscribe
+/// announce the topics the sender wants messages for, Graft and Prune add
+/// the receiver to or remove it from the sender's mesh for a topic. IHave lists
+/// the ids of messages on a topic that the sender has recently seen, and IWant
+/// requests the full messages for ids the sender is missing. Ping checks that a
+/// neighbour is still alive, and is answered with a Pong carrying the same id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GossipRpc {
+    Message(GossipMessage),
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    Graft(Topic),
+    Prune(Topic),
+    IHave(Topic, Vec<InnerKey>),
+    IWant(Vec<InnerKey>),
+    Ping(InnerKey),
+    Pong(InnerKey),
+}
+
+/// The envelope every gossip rpc is sent in, carrying the protocol id of the
+/// sender's network so that nodes on different networks ignore each other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GossipEnvelope {
+    pub protocol: String,
+    pub rpc: GossipRpc,
+}
+
+impl GossipEnvelope {
+    /// Wraps a gossip rpc in an envelope and returns it as a message ready to send
+    /// 
+    /// # Arguments
+    /// 
+    /// * protocol - the protocol id of the local node's network
+    /// * rpc - the rpc to send
+    /// 
+    pub fn message(protocol: &str, rpc: GossipRpc) -> Message {
+        let envelope = GossipEnvelope {
+            protocol: protocol.to_string(),
+            rpc,
+        };
+        Message {
+            head: Header::Gossip,
+            msg: envelope.as_bytes().unwrap(),
+        }
+    }
+
+    /// Unwraps the envelope from a received gossip message
+    /// 
+    /// # Arguments
+    /// 
+    /// * message - the message that was received
+    /// 
+    pub fn open(message: &Message) -> Option<GossipEnvelope> {
+        GossipEnvelope::from_bytes(&message.msg)
+    }
+}
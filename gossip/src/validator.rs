@@ -0,0 +1,80 @@
+use crate::protocol::{GossipMessage, Topic};
+use std::collections::HashMap;
+
+/// The result of validating a gossip message. Accepted messages are forwarded
+/// to peers, rejected messages are dropped and counted against the peer that
+/// sent them, and ignored messages are dropped without penalising anyone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validation {
+    Accept,
+    Reject,
+    Ignore,
+}
+
+/// How a message delivered to the application was validated. Validated messages
+/// were accepted by the validator for their topic, unvalidated messages were
+/// published to a topic without a validator, and pending messages are waiting
+/// on the application to report the result of validating them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationStatus {
+    Validated,
+    Unvalidated,
+    Pending,
+}
+
+/// A trait applied to the checks for the messages published to a topic.
+/// Any function or closure from a message to a Validation is a validator.
+pub trait MessageValidator: Send {
+    fn validate(&self, message: &GossipMessage) -> Validation;
+}
+
+impl<F> MessageValidator for F
+where
+    F: Fn(&GossipMessage) -> Validation + Send,
+{
+    fn validate(&self, message: &GossipMessage) -> Validation {
+        self(message)
+    }
+}
+
+/// How the messages published to a topic are validated. Sync validators are
+/// called before a message is delivered or forwarded. Async topics deliver each
+/// message to the application first, which reports the result back to the
+/// gossip service once it has validated the message.
+pub enum TopicValidator {
+    Sync(Box<dyn MessageValidator>),
+    Async,
+}
+
+/// The validators registered for each topic. Messages on topics without
+/// a validator are always accepted.
+#[derive(Default)]
+pub struct MessageValidators {
+    validators: HashMap<Topic, TopicValidator>,
+}
+
+impl MessageValidators {
+    /// Creates a new set of validators with none registered
+    pub fn new() -> MessageValidators {
+        MessageValidators::default()
+    }
+
+    /// Registers the validator for a topic, replacing any validator registered before
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to validate
+    /// * validator - how messages on the topic are validated
+    pub fn register(&mut self, topic: &str, validator: TopicValidator) {
+        self.validators.insert(topic.to_string(), validator);
+    }
+
+    /// Returns the validator registered for a topic, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic of the message being validated
+    pub fn get(&self, topic: &str) -> Option<&TopicValidator> {
+        self.validators.get(topic)
+    }
+}
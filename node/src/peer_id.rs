@@ -7,7 +7,7 @@ use sha256::digest_bytes;
 impl_ByteRep!(for PeerId);
 
 /// A tuple struct containing the hash representation of a 256 bit key
-#[derive(Clone, Debug, Hash, Serialize, Deserialize, Eq)]
+#[derive(Clone, Debug, Hash, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PeerId(String);
 
 impl PeerId {
@@ -45,14 +45,8 @@ impl PeerId {
     /// # Arguments
     /// 
     /// * value - a hashstring representation of a 256 bit key
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(value: String) -> Self {
         serde_json::from_str(&value).unwrap()
     }
 }
-
-
-impl PartialEq for PeerId {
-    fn eq(&self, other: &PeerId) -> bool {
-        self.get_id().eq(&other.get_id())
-    }
-}
\ No newline at end of file
@@ -5,12 +5,13 @@ use crate::peer_key::Key;
 use serde::{Serialize, Deserialize};
 use udp2p_utils::utils::Distance;
 use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 use udp2p_utils::utils::ByteRep;
 use udp2p_utils::impl_ByteRep;
 
 impl_ByteRep!(for PeerInfo);
 
-#[derive(Clone, Debug, Hash, Serialize, Deserialize, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq)]
 pub struct PeerInfoDistancePair(pub PeerInfo, pub Key);
 
 impl PartialEq for PeerInfo {
@@ -19,6 +20,12 @@ impl PartialEq for PeerInfo {
     }
 }
 
+impl Hash for PeerInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
 impl PartialOrd for PeerInfo {
     fn partial_cmp(&self, other: &PeerInfo) -> Option<Ordering> {
         Some(other.key.get_key().cmp(&self.key.get_key()))
@@ -31,9 +38,15 @@ impl PartialEq for PeerInfoDistancePair {
     }
 }
 
+impl Hash for PeerInfoDistancePair {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
 impl PartialOrd for PeerInfoDistancePair {
     fn partial_cmp(&self, other: &PeerInfoDistancePair) -> Option<Ordering> {
-        Some(other.1.cmp(&self.1))
+        Some(self.cmp(other))
     }
 }
 
@@ -44,7 +57,7 @@ impl Ord for PeerInfoDistancePair {
 }
 
 /// The core identifying struct for a node in the network
-#[derive(Clone, Debug, Hash, Serialize, Deserialize, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq)]
 pub struct PeerInfo {
     pub id: PeerId,
     pub key: Key,
@@ -72,7 +85,7 @@ impl PeerInfo {
 
     /// gets the local nodes's key
     pub fn get_key(&self) -> Key {
-        self.key.clone()
+        self.key
     }
 }
 
@@ -43,7 +43,7 @@ impl Key {
         let mut ret = Key::rand();
         let bytes = idx / 8;
         let bit = idx % 8;
-        (0..bytes).into_iter().for_each(|i| {
+        (0..bytes).for_each(|i| {
             ret.0[i] = 0;
         });
         ret.0[bytes] &= 0xFF >>(bit);
@@ -61,18 +61,11 @@ impl Key {
     /// 
     /// # Arguments
     /// 
-    /// * size - the length of the prefix to return
+    /// * size - the length of the prefix to return, in bits
     /// 
     pub fn get_prefix(&self, size: usize) -> String {
         let binary = self.get_binary();
-        let mut prefix = String::new();
-        if size > 0 {
-            prefix.push_str(&binary[0..=size]);
-            prefix
-        } else {
-            prefix.push_str(&binary.chars().next().unwrap().to_string());
-            prefix
-        }
+        binary[0..size.min(binary.len())].to_string()
     }
 }
 
@@ -120,7 +113,7 @@ impl Binary for Key {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let val = self.get_key();
         val.iter().for_each(|byte| {
-            write!(f, "{:b}", byte);
+            let _ = write!(f, "{:08b}", byte);
         });
 
         Ok(())
@@ -0,0 +1,63 @@
+use crate::protocol::MessageKey;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
+
+/// The events published by the different layers of the stack so that
+/// apps can react to changes in the network.
+///
+/// * PeerDiscovered - a peer was added to the routing table
+/// * PeerEvicted - a peer was evicted from the routing table after failing to answer a ping
+/// * LookupCompleted - an iterative lookup finished, with its id, target and the closest peers found
+/// * MessageDelivered - every packet of a message sent to a peer was acknowledged
+/// * DeliveryFailed - a peer failed to acknowledge a message after every resend
+/// * ProtocolMismatch - a peer sent a message for a different protocol, with the protocol id it sent
+/// * PeerBanned - a peer's score fell below the ban threshold, so it is ignored for the ban duration
+#[derive(Clone, Debug)]
+pub enum Event {
+    PeerDiscovered(PeerInfo),
+    PeerEvicted(PeerInfo),
+    LookupCompleted(MessageKey, Key, Vec<PeerInfo>),
+    MessageDelivered(SocketAddr),
+    DeliveryFailed(SocketAddr),
+    ProtocolMismatch(SocketAddr, String),
+    PeerBanned(SocketAddr),
+}
+
+/// A channel that events are published on and that any number of receivers
+/// can subscribe to. Cloning the bus shares it, so every layer holding a clone
+/// publishes to the same subscribers. Subscribers that have been dropped are
+/// removed the next time an event is published.
+#[derive(Clone, Debug, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    /// Creates a new event bus with no subscribers
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Returns a receiver that gets every event published after it subscribes
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// Sends an event to every subscriber
+    ///
+    /// # Arguments
+    ///
+    /// * event - the event to publish
+    pub fn publish(&self, event: Event) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
@@ -15,7 +15,6 @@ impl_ByteRep!(for Packet, AckMessage, Message, MessageKey, Header, KadMessage);
 //      REASON:
 //          Vectors take up double their allocates space
 //          wheras arrays do not, this will result in significant memory gains
-
 pub type Peer = Vec<u8>;
 pub type RequestBytes = Vec<u8>;
 pub type ResponseBytes = Vec<u8>;
@@ -98,7 +97,6 @@ macro_rules! packetize {
 /// 
 /// Build a macro for this
 /// 
-
 pub fn packetize(bytes: MessageData, id: InnerKey, ret: ReturnReceipt) -> Packets {
     if bytes.len() < 32500 {
         let hex_string = hex::encode(&bytes);
@@ -112,7 +110,7 @@ pub fn packetize(bytes: MessageData, id: InnerKey, ret: ReturnReceipt) -> Packet
         return vec![packet]
     }
     let mut n_packets = bytes.len() / 32500;
-    if n_packets % 32500 != 0 {
+    if !n_packets.is_multiple_of(32500) {
         n_packets += 1;
     }
     let mut start = 0;
@@ -130,7 +128,7 @@ pub fn packetize(bytes: MessageData, id: InnerKey, ret: ReturnReceipt) -> Packet
     }
     
     let packets: Packets = packets.iter().enumerate().map(|(idx, packet)| {
-        let hex_string = hex::encode(&packet);
+        let hex_string = hex::encode(packet);
         Packet {
             id,
             n: idx + 1,
@@ -214,7 +212,7 @@ impl MessageKey {
         let mut ret = MessageKey::rand();
         let bytes = idx / 8;
         let bit = idx % 8;
-        (0..bytes).into_iter().for_each(|i| {
+        (0..bytes).for_each(|i| {
             ret.0[i] = 0;
         });
         ret.0[bytes] &= 0xFF >>(bit);
@@ -0,0 +1,209 @@
+use crate::memory::{MemoryStore, MemoryStoreConfig};
+use crate::record::{DhtRecord, ProviderRecord};
+use crate::store::Store;
+use crate::COMPACTION_THRESHOLD;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::vec;
+use udp2p_node::peer_id::PeerId;
+use udp2p_node::peer_key::Key;
+
+/// A single change to a FileStore, written to its log as one line of json.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LogEntry {
+    Put(DhtRecord),
+    Remove(Key),
+    AddProvider(ProviderRecord),
+    RemoveProvider(Key, PeerId),
+}
+
+/// A record store that survives restarts. Every change is appended to a log
+/// file and synced to disk before it is applied, and the log is replayed into
+/// an in memory store when the store is opened, so records and provider records
+/// are reloaded with their expiry times intact. A log left with a partially
+/// written entry by a crash is replayed up to that entry. Once the log holds
+/// more than COMPACTION_THRESHOLD entries and more than twice as many entries as
+/// there are live records and providers, it is compacted by rewriting it with
+/// only the live entries.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    log: File,
+    entries: usize,
+    inner: MemoryStore,
+}
+
+impl FileStore {
+    /// Opens the store logged to a file with the default limits, creating the file if it doesn't exist
+    ///
+    /// # Arguments
+    ///
+    /// * path - the log file
+    /// * local_id - the id of the local peer
+    pub fn open(path: &Path, local_id: PeerId) -> io::Result<FileStore> {
+        FileStore::with_config(path, local_id, MemoryStoreConfig::default())
+    }
+
+    /// Opens the store logged to a file with the limits provided, creating the file if it doesn't exist
+    ///
+    /// # Arguments
+    ///
+    /// * path - the log file
+    /// * local_id - the id of the local peer
+    /// * config - the limits to apply to the store
+    pub fn with_config(path: &Path, local_id: PeerId, config: MemoryStoreConfig) -> io::Result<FileStore> {
+        let mut inner = MemoryStore::with_config(local_id, config);
+        let mut entries = 0;
+        let mut complete = true;
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let entry = match line.map(|line| serde_json::from_str::<LogEntry>(&line)) {
+                    Ok(Ok(entry)) => entry,
+                    _ => {
+                        info!("Record log {:?} ends with an incomplete entry", path);
+                        complete = false;
+                        break;
+                    }
+                };
+                FileStore::apply(&mut inner, entry);
+                entries += 1;
+            }
+        }
+        inner.remove_expired();
+
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut store = FileStore {
+            path: path.to_path_buf(),
+            log,
+            entries,
+            inner,
+        };
+        if !complete {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Rewrites the log with only the records and provider records that are
+    /// still live. The new log is written to a temporary file and renamed over
+    /// the old one, so a crash part way through leaves the old log in place.
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.inner.remove_expired();
+        let live: Vec<LogEntry> = self
+            .inner
+            .records()
+            .map(LogEntry::Put)
+            .chain(self.inner.provider_records().into_iter().map(LogEntry::AddProvider))
+            .collect();
+
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp)?;
+        for entry in live.iter() {
+            FileStore::write_entry(&mut file, entry)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.log = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = live.len();
+        Ok(())
+    }
+
+    /// Applies an entry that has been logged, or replayed from the log, to the
+    /// in memory store. Entries the store refuses, e.g. because its limits have been lowered since the
+    /// entry was logged, are skipped.
+    fn apply(inner: &mut MemoryStore, entry: LogEntry) {
+        match entry {
+            LogEntry::Put(record) => {
+                let _ = inner.put(record);
+            }
+            LogEntry::Remove(key) => inner.remove(&key),
+            LogEntry::AddProvider(provider) => {
+                let _ = inner.add_provider(provider);
+            }
+            LogEntry::RemoveProvider(key, peer) => inner.remove_provider(&key, &peer),
+        }
+    }
+
+    /// Writes an entry to a log file as a single line of json
+    fn write_entry(file: &mut File, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+
+    /// Appends an entry to the log and syncs it to disk, then applies it to the
+    /// in memory store, compacting the log if it has grown too large. Nothing is
+    /// applied if the entry can't be logged.
+    fn append(&mut self, entry: LogEntry) -> io::Result<()> {
+        FileStore::write_entry(&mut self.log, &entry)?;
+        self.log.sync_data()?;
+        self.entries += 1;
+        FileStore::apply(&mut self.inner, entry);
+
+        if self.entries > COMPACTION_THRESHOLD && self.entries > self.inner.len() * 2 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    type Record = DhtRecord;
+    type Provision = ProviderRecord;
+    type Key = Key;
+    type RecordIter = vec::IntoIter<DhtRecord>;
+    type ProvisionIter = vec::IntoIter<ProviderRecord>;
+
+    fn get(&self, key: &Key) -> Option<DhtRecord> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, record: DhtRecord) -> Result<(), Box<dyn Error>> {
+        // Log the record as it will be stored, so that the expiry given to it by the store is kept
+        let record = self.inner.prepare_record(record)?;
+        self.append(LogEntry::Put(record))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Err(e) = self.append(LogEntry::Remove(*key)) {
+            info!("Error logging record removal to {:?}: {:?}", self.path, e);
+        }
+    }
+
+    fn records(&self) -> vec::IntoIter<DhtRecord> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, provider: ProviderRecord) -> Result<(), Box<dyn Error>> {
+        let provider = self.inner.prepare_provider(provider)?;
+        self.append(LogEntry::AddProvider(provider))?;
+        Ok(())
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> vec::IntoIter<ProviderRecord> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, key: &Key, peer: &PeerId) {
+        if let Err(e) = self.append(LogEntry::RemoveProvider(*key, peer.clone())) {
+            info!("Error logging provider removal to {:?}: {:?}", self.path, e);
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        // Expired entries are skipped when the log is replayed and dropped
+        // when it is compacted, so they don't need to be logged.
+        self.inner.remove_expired();
+    }
+}
@@ -1,11 +1,167 @@
 pub mod store;
 pub mod record;
+pub mod memory;
+pub mod file;
+pub mod validator;
+
+const MAX_RECORDS: usize = 1024;
+const MAX_VALUE_BYTES: usize = 16_384;
+const MAX_PROVIDED_KEYS: usize = 1024;
+const MAX_PROVIDERS_PER_KEY: usize = 20;
+const RECORD_TTL: u128 = 129_600_000_000_000;
+const PROVIDER_TTL: u128 = 86_400_000_000_000;
+const COMPACTION_THRESHOLD: usize = 1024;
 
 #[cfg(test)]
 mod tests {
+    use crate::file::FileStore;
+    use crate::memory::{MemoryStore, MemoryStoreConfig};
+    use crate::record::{DhtRecord, ProviderRecord};
+    use crate::store::{Store, StoreConfig, StoreError};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use udp2p_node::peer_id::PeerId;
+    use udp2p_node::peer_info::PeerInfo;
+    use udp2p_node::peer_key::Key;
+    use udp2p_utils::utils::timestamp_now;
+
+    fn peer(port: usize) -> PeerInfo {
+        let key = Key::rand();
+        let address = format!("127.0.0.1:{}", port).parse().unwrap();
+        PeerInfo::new(PeerId::from_key(&key), key, address)
+    }
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn memory_store_applies_limits_and_ttl() {
+        let config = MemoryStoreConfig {
+            max_records: 2,
+            max_value_bytes: 4,
+            ..MemoryStoreConfig::default()
+        };
+        let mut store = MemoryStore::with_config(PeerId::rand(), config);
+
+        let record = DhtRecord::new(Key::rand(), vec![1, 2, 3], None);
+        store.put(record.clone()).unwrap();
+        let stored = store.get(&record.key).unwrap();
+        assert_eq!(stored.value, record.value);
+        assert!(stored.expires.is_some());
+
+        // Expiries further away than the ttl are brought forward to it
+        let mut forever = DhtRecord::new(Key::rand(), vec![0], None);
+        forever.expires = Some(u128::MAX);
+        store.put(forever.clone()).unwrap();
+        let latest = timestamp_now() + MemoryStoreConfig::default().record_ttl;
+        assert!(store.get(&forever.key).unwrap().expires.unwrap() <= latest);
+        store.remove(&forever.key);
+
+        let too_large = DhtRecord::new(Key::rand(), vec![0; 5], None);
+        let err = store.put(too_large).unwrap_err();
+        assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::ValueTooLarge));
+
+        // An expired record is never returned and makes room for new records
+        let mut expired = DhtRecord::new(Key::rand(), vec![4], None);
+        expired.expires = Some(timestamp_now() - 1);
+        store.put(expired.clone()).unwrap();
+        assert!(store.get(&expired.key).is_none());
+        assert_eq!(store.records().count(), 1);
+
+        store.put(DhtRecord::new(Key::rand(), vec![5], None)).unwrap();
+        let err = store.put(DhtRecord::new(Key::rand(), vec![6], None)).unwrap_err();
+        assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::MaxRecords));
+
+        // Replacing an existing record doesn't need room
+        store.put(DhtRecord::new(record.key, vec![7], None)).unwrap();
+        assert_eq!(store.get(&record.key).unwrap().value, vec![7]);
+        store.remove(&record.key);
+        assert!(store.get(&record.key).is_none());
+    }
+
+    #[test]
+    fn memory_store_tracks_providers() {
+        let local = peer(9292);
+        let config = MemoryStoreConfig {
+            max_providers_per_key: 2,
+            ..MemoryStoreConfig::default()
+        };
+        let mut store = MemoryStore::with_config(local.id.clone(), config);
+        let (key, other_key) = (Key::rand(), Key::rand());
+        let (first, second) = (peer(9293), peer(9294));
+
+        store.add_provider(ProviderRecord::new(key, local.clone())).unwrap();
+        store.add_provider(ProviderRecord::new(key, first.clone())).unwrap();
+        let mut forever = ProviderRecord::new(key, first.clone());
+        forever.expires = Some(u128::MAX);
+        store.add_provider(forever).unwrap();
+        let latest = timestamp_now() + MemoryStoreConfig::default().provider_ttl;
+        assert_eq!(store.providers(&key).len(), 2);
+        assert_eq!(store.len(), 2);
+        assert!(store.providers(&key).iter().all(|provider| provider.expires.unwrap() <= latest));
+
+        let err = store.add_provider(ProviderRecord::new(key, second.clone())).unwrap_err();
+        assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::MaxProviders));
+
+        store.add_provider(ProviderRecord::new(other_key, second.clone())).unwrap();
+        let provided: Vec<ProviderRecord> = store.provided().collect();
+        assert_eq!(provided.len(), 1);
+        assert_eq!(provided[0].key, key);
+
+        store.remove_provider(&key, &first.id);
+        assert_eq!(store.providers(&key).len(), 1);
+        store.remove_provider(&other_key, &second.id);
+        assert!(store.providers(&other_key).is_empty());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn file_store_reloads_after_restart() {
+        let path = std::env::temp_dir().join(format!("udp2p_records_{}.log", PeerId::rand().get_id()));
+        let local = peer(9292);
+        let (kept, removed) = (
+            DhtRecord::new(Key::rand(), b"kept".to_vec(), Some(local.id.clone())),
+            DhtRecord::new(Key::rand(), b"removed".to_vec(), None),
+        );
+        let provided = ProviderRecord::new(kept.key, local.clone());
+
+        let stored = {
+            let mut store = StoreConfig::File(path.clone(), MemoryStoreConfig::default())
+                .build(local.id.clone())
+                .unwrap();
+            store.put(kept.clone()).unwrap();
+            store.put(removed.clone()).unwrap();
+            store.remove(&removed.key);
+            store.add_provider(provided.clone()).unwrap();
+            store.get(&kept.key).unwrap()
+        };
+
+        // Simulate a crash part way through writing an entry
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"Put\":{\"key\":").unwrap();
+        drop(log);
+
+        let mut store = FileStore::open(&path, local.id.clone()).unwrap();
+        assert_eq!(store.get(&kept.key), Some(stored));
+        assert!(store.get(&removed.key).is_none());
+        let providers: Vec<ProviderRecord> = store.provided().collect();
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, local);
+
+        // Reopening truncated the incomplete entry and compaction keeps only live entries
+        store.compact().unwrap();
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 2);
+
+        // Records the store refuses are never logged
+        assert!(store.put(DhtRecord::new(Key::rand(), vec![0; 16_385], None)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        store.put(DhtRecord::new(Key::rand(), b"new".to_vec(), None)).unwrap();
+        drop(store);
+        assert_eq!(FileStore::open(&path, local.id).unwrap().records().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,250 @@
+use crate::record::{DhtRecord, ProviderRecord};
+use crate::store::{Store, StoreError};
+use crate::{MAX_PROVIDED_KEYS, MAX_PROVIDERS_PER_KEY, MAX_RECORDS, MAX_VALUE_BYTES, PROVIDER_TTL, RECORD_TTL};
+use std::collections::HashMap;
+use std::error::Error;
+use std::vec;
+use udp2p_node::peer_id::PeerId;
+use udp2p_node::peer_key::Key;
+use udp2p_utils::utils::{timestamp_now, Timestamp};
+
+/// The limits applied by a MemoryStore. Contains the maximum number of
+/// records, the maximum size of a record's value in bytes, the maximum number of
+/// keys that providers are held for and of providers held per key, and the
+/// time to live in nanoseconds given to records and provider records. Records
+/// that already have an expiry keep it only if it is sooner than their time
+/// to live, so peers can't make the store hold a record forever.
+#[derive(Clone, Debug)]
+pub struct MemoryStoreConfig {
+    pub max_records: usize,
+    pub max_value_bytes: usize,
+    pub max_provided_keys: usize,
+    pub max_providers_per_key: usize,
+    pub record_ttl: Timestamp,
+    pub provider_ttl: Timestamp,
+}
+
+impl Default for MemoryStoreConfig {
+    fn default() -> Self {
+        MemoryStoreConfig {
+            max_records: MAX_RECORDS,
+            max_value_bytes: MAX_VALUE_BYTES,
+            max_provided_keys: MAX_PROVIDED_KEYS,
+            max_providers_per_key: MAX_PROVIDERS_PER_KEY,
+            record_ttl: RECORD_TTL,
+            provider_ttl: PROVIDER_TTL,
+        }
+    }
+}
+
+/// A record store that holds every record and provider record in memory.
+/// Contains the id of the local peer, used to tell which keys the local
+/// peer provides, the store's limits, the records by key, the provider
+/// records by key and the number of provider records held. Expired records
+/// are never returned and are dropped whenever the store needs room.
+#[derive(Clone, Debug)]
+pub struct MemoryStore {
+    local_id: PeerId,
+    config: MemoryStoreConfig,
+    records: HashMap<Key, DhtRecord>,
+    providers: HashMap<Key, Vec<ProviderRecord>>,
+    provider_count: usize,
+}
+
+impl MemoryStore {
+    /// Creates a new empty store with the default limits
+    ///
+    /// # Arguments
+    ///
+    /// * local_id - the id of the local peer
+    pub fn new(local_id: PeerId) -> MemoryStore {
+        MemoryStore::with_config(local_id, MemoryStoreConfig::default())
+    }
+
+    /// Creates a new empty store with the limits provided
+    ///
+    /// # Arguments
+    ///
+    /// * local_id - the id of the local peer
+    /// * config - the limits to apply to the store
+    pub fn with_config(local_id: PeerId, config: MemoryStoreConfig) -> MemoryStore {
+        MemoryStore {
+            local_id,
+            config,
+            records: HashMap::new(),
+            providers: HashMap::new(),
+            provider_count: 0,
+        }
+    }
+
+    /// Returns the number of records and provider records held, including
+    /// expired ones that haven't been dropped yet
+    pub fn len(&self) -> usize {
+        self.records.len() + self.provider_count
+    }
+
+    /// Checks if the store holds no records or provider records and returns true or false
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks that a record can be put in the store and returns it with the
+    /// expiry it will be stored with, without storing it. Expired records are
+    /// dropped if the store needs room for it.
+    ///
+    /// # Arguments
+    ///
+    /// * record - the record to check
+    pub fn prepare_record(&mut self, mut record: DhtRecord) -> Result<DhtRecord, Box<dyn Error>> {
+        if record.value.len() > self.config.max_value_bytes {
+            return Err(Box::new(StoreError::ValueTooLarge));
+        }
+
+        if !self.records.contains_key(&record.key) && self.records.len() >= self.config.max_records {
+            self.remove_expired();
+            if self.records.len() >= self.config.max_records {
+                return Err(Box::new(StoreError::MaxRecords));
+            }
+        }
+
+        let latest = timestamp_now() + self.config.record_ttl;
+        record.expires = Some(record.expires.map_or(latest, |expires| expires.min(latest)));
+        Ok(record)
+    }
+
+    /// Checks that a provider record can be added to the store and returns it
+    /// with the expiry it will be stored with, without adding it. Expired
+    /// provider records are dropped if the store needs room for it.
+    ///
+    /// # Arguments
+    ///
+    /// * provider - the provider record to check
+    pub fn prepare_provider(&mut self, mut provider: ProviderRecord) -> Result<ProviderRecord, Box<dyn Error>> {
+        if !self.providers.contains_key(&provider.key)
+            && self.providers.len() >= self.config.max_provided_keys
+        {
+            self.remove_expired();
+            if self.providers.len() >= self.config.max_provided_keys {
+                return Err(Box::new(StoreError::MaxProvidedKeys));
+            }
+        }
+
+        let full = self.providers.get(&provider.key).is_some_and(|providers| {
+            let live: Vec<&ProviderRecord> = providers.iter().filter(|existing| !existing.is_expired()).collect();
+            live.len() >= self.config.max_providers_per_key
+                && !live.iter().any(|existing| existing.provider.id == provider.provider.id)
+        });
+        if full {
+            return Err(Box::new(StoreError::MaxProviders));
+        }
+
+        let latest = timestamp_now() + self.config.provider_ttl;
+        provider.expires = Some(provider.expires.map_or(latest, |expires| expires.min(latest)));
+        Ok(provider)
+    }
+
+    /// Returns every provider record that hasn't expired, for any peer
+    pub fn provider_records(&self) -> Vec<ProviderRecord> {
+        self.providers
+            .values()
+            .flatten()
+            .filter(|provider| !provider.is_expired())
+            .cloned()
+            .collect()
+    }
+
+}
+
+impl Store for MemoryStore {
+    type Record = DhtRecord;
+    type Provision = ProviderRecord;
+    type Key = Key;
+    type RecordIter = vec::IntoIter<DhtRecord>;
+    type ProvisionIter = vec::IntoIter<ProviderRecord>;
+
+    fn get(&self, key: &Key) -> Option<DhtRecord> {
+        self.records
+            .get(key)
+            .filter(|record| !record.is_expired())
+            .cloned()
+    }
+
+    fn put(&mut self, record: DhtRecord) -> Result<(), Box<dyn Error>> {
+        let record = self.prepare_record(record)?;
+        self.records.insert(record.key, record);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Key) {
+        self.records.remove(key);
+    }
+
+    fn records(&self) -> vec::IntoIter<DhtRecord> {
+        self.records
+            .values()
+            .filter(|record| !record.is_expired())
+            .cloned()
+            .collect::<Vec<DhtRecord>>()
+            .into_iter()
+    }
+
+    fn add_provider(&mut self, provider: ProviderRecord) -> Result<(), Box<dyn Error>> {
+        let provider = self.prepare_provider(provider)?;
+        let providers = self.providers.entry(provider.key).or_default();
+        let held = providers.len();
+        providers.retain(|existing| !existing.is_expired());
+        if let Some(existing) = providers
+            .iter_mut()
+            .find(|existing| existing.provider.id == provider.provider.id)
+        {
+            *existing = provider;
+        } else {
+            providers.push(provider);
+        }
+        self.provider_count = self.provider_count - held + providers.len();
+        Ok(())
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.providers
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|provider| !provider.is_expired())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn provided(&self) -> vec::IntoIter<ProviderRecord> {
+        self.providers
+            .values()
+            .flatten()
+            .filter(|provider| provider.provider.id == self.local_id && !provider.is_expired())
+            .cloned()
+            .collect::<Vec<ProviderRecord>>()
+            .into_iter()
+    }
+
+    fn remove_provider(&mut self, key: &Key, peer: &PeerId) {
+        if let Some(providers) = self.providers.get_mut(key) {
+            let held = providers.len();
+            providers.retain(|provider| provider.provider.id != *peer);
+            self.provider_count = self.provider_count - held + providers.len();
+            if providers.is_empty() {
+                self.providers.remove(key);
+            }
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        self.records.retain(|_, record| !record.is_expired());
+        self.providers.iter_mut().for_each(|(_, providers)| {
+            providers.retain(|provider| !provider.is_expired());
+        });
+        self.providers.retain(|_, providers| !providers.is_empty());
+        self.provider_count = self.providers.values().map(Vec::len).sum();
+    }
+}
@@ -1,5 +1,110 @@
+use serde::{Deserialize, Serialize};
+use udp2p_node::peer_id::PeerId;
+use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
+use udp2p_utils::impl_ByteRep;
+use udp2p_utils::utils::{timestamp_now, ByteRep, Timestamp};
+
+impl_ByteRep!(for DhtRecord, ProviderRecord);
+
 /// Applied to a type that has a key
 pub trait Record {
     type Key;
     fn get_key(&self) -> Self::Key;
 }
+
+/// A value stored in the DHT under a 256 bit key. Contains the key,
+/// the namespace the record belongs to, which selects the validator
+/// used to check it, the value itself, the id of the peer that originally
+/// published the record (if known) and the unix timestamp in nanoseconds
+/// after which the record is no longer valid (if it ever expires).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtRecord {
+    pub key: Key,
+    #[serde(default)]
+    pub namespace: String,
+    pub value: Vec<u8>,
+    pub publisher: Option<PeerId>,
+    pub expires: Option<Timestamp>,
+}
+
+/// A record announcing that a peer is able to provide the value stored
+/// under a key. Contains the key, the providing peer and the unix timestamp
+/// in nanoseconds after which the provider record is no longer valid.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderRecord {
+    pub key: Key,
+    pub provider: PeerInfo,
+    pub expires: Option<Timestamp>,
+}
+
+impl DhtRecord {
+    /// Creates a new record that never expires, outside of any namespace
+    ///
+    /// # Arguments
+    ///
+    /// * key - the key to store the record under
+    /// * value - the value of the record
+    /// * publisher - the id of the peer publishing the record
+    pub fn new(key: Key, value: Vec<u8>, publisher: Option<PeerId>) -> DhtRecord {
+        DhtRecord {
+            key,
+            namespace: String::new(),
+            value,
+            publisher,
+            expires: None,
+        }
+    }
+
+    /// Moves the record into a namespace
+    ///
+    /// # Arguments
+    ///
+    /// * namespace - the namespace the record belongs to
+    pub fn with_namespace(mut self, namespace: &str) -> DhtRecord {
+        self.namespace = namespace.to_string();
+        self
+    }
+
+    /// Checks if the record has passed its expiry and returns true or false
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= timestamp_now())
+    }
+}
+
+impl ProviderRecord {
+    /// Creates a new provider record that never expires
+    ///
+    /// # Arguments
+    ///
+    /// * key - the key that the peer provides the value of
+    /// * provider - the peer providing the value
+    pub fn new(key: Key, provider: PeerInfo) -> ProviderRecord {
+        ProviderRecord {
+            key,
+            provider,
+            expires: None,
+        }
+    }
+
+    /// Checks if the provider record has passed its expiry and returns true or false
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= timestamp_now())
+    }
+}
+
+impl Record for DhtRecord {
+    type Key = Key;
+
+    fn get_key(&self) -> Key {
+        self.key
+    }
+}
+
+impl Record for ProviderRecord {
+    type Key = Key;
+
+    fn get_key(&self) -> Key {
+        self.key
+    }
+}
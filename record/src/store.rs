@@ -1,9 +1,18 @@
 #![allow(unused_imports)]
+use crate::file::FileStore;
+use crate::memory::{MemoryStore, MemoryStoreConfig};
+use crate::record::{DhtRecord, ProviderRecord};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Debug;
+use std::io;
 use std::mem;
+use std::path::PathBuf;
+use std::vec;
 use udp2p_utils::utils::timestamp_now;
 use udp2p_node::peer_id::PeerId;
 use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
 use std::error::Error;
 
 /// A trait applied to any kind of key value storing
@@ -24,4 +33,88 @@ pub trait Store {
     fn providers(&self, key: &Self::Key) -> Vec<Self::Provision>;
     fn provided(&self) -> Self::ProvisionIter;
     fn remove_provider(&mut self, key: &Self::Key, peer: &PeerId);
-}
\ No newline at end of file
+    fn remove_expired(&mut self);
+}
+
+/// A store of DHT records and provider records that can be used by
+/// a kademlia instance. Implemented for every Store over DhtRecords and
+/// ProviderRecords so that stores can be swapped out behind a trait object.
+pub trait RecordStore:
+    Store<
+        Record = DhtRecord,
+        Provision = ProviderRecord,
+        Key = Key,
+        RecordIter = vec::IntoIter<DhtRecord>,
+        ProvisionIter = vec::IntoIter<ProviderRecord>,
+    > + Debug
+    + Send
+{
+}
+
+impl<T> RecordStore for T where
+    T: Store<
+            Record = DhtRecord,
+            Provision = ProviderRecord,
+            Key = Key,
+            RecordIter = vec::IntoIter<DhtRecord>,
+            ProvisionIter = vec::IntoIter<ProviderRecord>,
+        > + Debug
+        + Send
+{
+}
+
+/// Selects the record store to use and the limits to apply to it, so that
+/// the in memory store and the file backed store can be swapped without
+/// any other changes.
+#[derive(Clone, Debug)]
+pub enum StoreConfig {
+    Memory(MemoryStoreConfig),
+    File(PathBuf, MemoryStoreConfig),
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Memory(MemoryStoreConfig::default())
+    }
+}
+
+impl StoreConfig {
+    /// Creates the configured record store, opening and replaying
+    /// the log file for a file backed store.
+    ///
+    /// # Arguments
+    ///
+    /// * local_id - the id of the local peer
+    pub fn build(&self, local_id: PeerId) -> io::Result<Box<dyn RecordStore>> {
+        match self {
+            StoreConfig::Memory(config) => {
+                Ok(Box::new(MemoryStore::with_config(local_id, config.clone())))
+            }
+            StoreConfig::File(path, config) => {
+                Ok(Box::new(FileStore::with_config(path, local_id, config.clone())?))
+            }
+        }
+    }
+}
+
+/// The reasons a store may refuse a record or provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreError {
+    MaxRecords,
+    ValueTooLarge,
+    MaxProvidedKeys,
+    MaxProviders,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::MaxRecords => write!(f, "the store is full"),
+            StoreError::ValueTooLarge => write!(f, "the record value is too large"),
+            StoreError::MaxProvidedKeys => write!(f, "the store holds providers for too many keys"),
+            StoreError::MaxProviders => write!(f, "the key has too many providers"),
+        }
+    }
+}
+
+impl Error for StoreError {}
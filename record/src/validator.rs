@@ -0,0 +1,77 @@
+use crate::record::DhtRecord;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use udp2p_node::peer_key::Key;
+
+/// A trait applied to the checks for the records in a namespace. Validators
+/// check that a record is well formed (e.g. that it is signed by the key's
+/// owner and matches the namespace's schema) and, when several versions of a
+/// record are found, select the best one (e.g. the highest sequence number).
+pub trait Validator: Debug + Send {
+    fn validate(&self, record: &DhtRecord) -> Result<(), Box<dyn Error>>;
+
+    /// Returns the index of the best of several valid versions of the record
+    /// stored under a key. By default the first version is the best.
+    fn select(&self, _key: &Key, _records: &[DhtRecord]) -> usize {
+        0
+    }
+}
+
+/// The validators registered for each namespace. Records in a namespace
+/// without a validator are always valid, and the first of several versions
+/// of them is the best.
+#[derive(Debug, Default)]
+pub struct Validators {
+    validators: HashMap<String, Box<dyn Validator>>,
+}
+
+impl Validators {
+    /// Creates a new set of validators with none registered
+    pub fn new() -> Validators {
+        Validators::default()
+    }
+
+    /// Registers the validator for a namespace, replacing any validator registered before
+    ///
+    /// # Arguments
+    ///
+    /// * namespace - the namespace to validate
+    /// * validator - the validator for records in the namespace
+    pub fn register(&mut self, namespace: &str, validator: Box<dyn Validator>) {
+        self.validators.insert(namespace.to_string(), validator);
+    }
+
+    /// Checks a record with the validator for its namespace
+    ///
+    /// # Arguments
+    ///
+    /// * record - the record to check
+    pub fn validate(&self, record: &DhtRecord) -> Result<(), Box<dyn Error>> {
+        match self.validators.get(&record.namespace) {
+            Some(validator) => validator.validate(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the best of several versions of the record stored under a key, using
+    /// the validator for their namespace. Versions are expected to share a namespace,
+    /// any that don't match the first version's namespace are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * records - the versions of the record
+    pub fn select(&self, records: &[DhtRecord]) -> Option<DhtRecord> {
+        let first = records.first()?;
+        let versions: Vec<DhtRecord> = records
+            .iter()
+            .filter(|record| record.key == first.key && record.namespace == first.namespace)
+            .cloned()
+            .collect();
+        let best = match self.validators.get(&first.namespace) {
+            Some(validator) => validator.select(&first.key, &versions),
+            None => 0,
+        };
+        versions.get(best).or(Some(first)).cloned()
+    }
+}
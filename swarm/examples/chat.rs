@@ -0,0 +1,58 @@
+use udp2p_swarm::builder::NodeBuilder;
+use rand::{thread_rng, Rng};
+use std::env::args;
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    // Bind to a random port between 9292 and 19292 on the localhost address,
+    // any arguments are the addresses of seed nodes to bootstrap from
+    let port: usize = thread_rng().gen_range(9292..19292);
+    let addr: SocketAddr = format!("127.0.0.1:{}", port)
+        .parse()
+        .expect("Unable to parse address");
+    let seeds: Vec<SocketAddr> = args()
+        .skip(1)
+        .map(|seed| seed.parse().expect("Unable to parse address"))
+        .collect();
+    let node = NodeBuilder::new(addr)
+        .seeds(&seeds)
+        .build()
+        .expect("Unable to start node");
+
+    println!("My Address: {:?}", node.address());
+    println!("My ID: {:?}", node.info().id);
+
+    node.subscribe("chat");
+
+    // Print the node's events
+    let events = node.events();
+    thread::spawn(move || {
+        for event in events {
+            println!("{:?}", event);
+        }
+    });
+
+    // Read lines from stdin on their own thread
+    let (line_tx, line_rx) = channel::<String>();
+    thread::spawn(move || loop {
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line).is_ok() && line_tx.send(line).is_err() {
+            break;
+        }
+    });
+
+    // Gossip each line read and print the messages from other peers
+    loop {
+        while let Ok(line) = line_rx.try_recv() {
+            node.publish("chat", line.trim().as_bytes().to_vec());
+        }
+
+        if let Ok(delivered) = node.messages().recv_timeout(Duration::from_millis(100)) {
+            let message = delivered.message;
+            println!("{}: {}", message.sender, String::from_utf8_lossy(&message.data));
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::node::{NodeHandle, Service};
+use crate::{FLUSH_TIMEOUT, PING_INTERVAL, SOCKET_READ_TIMEOUT};
+use udp2p_discovery::kad::Kademlia;
+use udp2p_discovery::routing::RoutingTable;
+use udp2p_discovery::score::ScoreConfig;
+use udp2p_gossip::gossip::{GossipConfig, GossipService};
+use udp2p_node::peer_id::PeerId;
+use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
+use udp2p_protocol::event::EventBus;
+use udp2p_protocol::protocol::{AckMessage, Message};
+use udp2p_record::store::StoreConfig;
+use udp2p_transport::handler::MessageHandler;
+use udp2p_transport::transport::Transport;
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use udp2p_utils::utils::ByteRep;
+use log::info;
+
+/// A builder for a node. Binds the node's socket, wires its kademlia,
+/// transport, message handler and gossip instances together, and spawns
+/// the threads that run them. Every option has a default, so a node only
+/// needs the address to bind to.
+pub struct NodeBuilder {
+    address: SocketAddr,
+    identity: Option<SigningKey>,
+    seeds: Vec<SocketAddr>,
+    interval: Duration,
+    gossip_config: GossipConfig,
+    score_config: ScoreConfig,
+    store: StoreConfig,
+    snapshot: Option<PathBuf>,
+}
+
+impl NodeBuilder {
+    /// Creates a new NodeBuilder
+    ///
+    /// # Arguments
+    ///
+    /// * address - the socket address to bind to, a port of 0 binds to any free port
+    pub fn new(address: SocketAddr) -> NodeBuilder {
+        NodeBuilder {
+            address,
+            identity: None,
+            seeds: vec![],
+            interval: Duration::from_nanos(PING_INTERVAL),
+            gossip_config: GossipConfig::default(),
+            score_config: ScoreConfig::default(),
+            store: StoreConfig::default(),
+            snapshot: None,
+        }
+    }
+
+    /// Sets the identity key that the node's gossip messages are signed with, a
+    /// random key is used if none is set. The local peer's key is the identity's
+    /// public key, so a node keeps its id across restarts by keeping its identity.
+    ///
+    /// # Arguments
+    ///
+    /// * identity - the node's identity key
+    pub fn identity(mut self, identity: SigningKey) -> NodeBuilder {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Sets the seed nodes to bootstrap from once the node is running
+    ///
+    /// # Arguments
+    ///
+    /// * seeds - the socket addresses of the seed nodes
+    pub fn seeds(mut self, seeds: &[SocketAddr]) -> NodeBuilder {
+        self.seeds = seeds.to_vec();
+        self
+    }
+
+    /// Sets the interval between pinging peers and refreshing buckets
+    ///
+    /// # Arguments
+    ///
+    /// * interval - the interval between ping-pong events
+    pub fn interval(mut self, interval: Duration) -> NodeBuilder {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the configuration of the gossip service
+    ///
+    /// # Arguments
+    ///
+    /// * config - the gossip configuration
+    pub fn gossip_config(mut self, config: GossipConfig) -> NodeBuilder {
+        self.gossip_config = config;
+        self
+    }
+
+    /// Sets the weights and thresholds peers are scored with
+    ///
+    /// # Arguments
+    ///
+    /// * config - the score configuration
+    pub fn score_config(mut self, config: ScoreConfig) -> NodeBuilder {
+        self.score_config = config;
+        self
+    }
+
+    /// Sets the record store used to hold records stored in the DHT
+    ///
+    /// # Arguments
+    ///
+    /// * store - the configuration of the record store
+    pub fn store(mut self, store: StoreConfig) -> NodeBuilder {
+        self.store = store;
+        self
+    }
+
+    /// Sets the file that the routing table is snapshotted to. If the file already
+    /// exists the node warm starts from it, pinging the peers it holds, so a node
+    /// restarted with the same path rejoins the network without a seed.
+    ///
+    /// # Arguments
+    ///
+    /// * path - the file to write routing table snapshots to
+    pub fn snapshot_path(mut self, path: PathBuf) -> NodeBuilder {
+        self.snapshot = Some(path);
+        self
+    }
+
+    /// Binds the socket, wires the node's components together and spawns the
+    /// transport, message handler and service threads, then bootstraps from
+    /// the seed nodes if any were set. Returns a handle to the running node.
+    #[allow(clippy::type_complexity)]
+    pub fn build(self) -> io::Result<NodeHandle> {
+        let sock = UdpSocket::bind(self.address)?;
+        sock.set_read_timeout(Some(Duration::from_nanos(SOCKET_READ_TIMEOUT)))?;
+        let addr = sock.local_addr()?;
+
+        // Initiate channels for communication between different threads
+        let (to_transport_tx, to_transport_rx): (
+            Sender<(SocketAddr, Message)>,
+            Receiver<(SocketAddr, Message)>,
+        ) = channel();
+        let (to_gossip_tx, to_gossip_rx) = channel();
+        let (to_kad_tx, to_kad_rx) = channel();
+        let (incoming_ack_tx, incoming_ack_rx): (Sender<AckMessage>, Receiver<AckMessage>) = channel();
+        let (to_app_tx, to_app_rx) = channel();
+        let (command_tx, command_rx) = channel();
+
+        // Initialize local peer information
+        let identity = self.identity.unwrap_or_else(|| SigningKey::generate(&mut OsRng));
+        let key = Key::new(identity.verifying_key().to_bytes());
+        let id = PeerId::from_key(&key);
+        let info = PeerInfo::new(id.clone(), key, addr);
+
+        // Initialize the kademlia, transport and message handler instances
+        // sharing one event bus
+        let events = EventBus::new();
+        let routing_table = RoutingTable::new(info.clone());
+        let mut kad = Kademlia::new(
+            routing_table,
+            to_transport_tx.clone(),
+            to_kad_rx,
+            HashMap::new(),
+            self.interval,
+            Instant::now(),
+        );
+        kad.set_store(self.store.build(id)?);
+        kad.set_event_bus(events.clone());
+        kad.set_score_config(self.score_config);
+        kad.set_protocol_id(self.gossip_config.id());
+        if let Some(bytes) = info.as_bytes() {
+            kad.add_peer(bytes);
+        }
+        if let Some(path) = self.snapshot {
+            if path.exists() {
+                match kad.warm_start(&path) {
+                    Ok(count) => info!("Warm starting from {} peers in {:?}", count, path),
+                    Err(e) => info!("Unable to warm start from {:?}: {:?}", path, e),
+                }
+            }
+            kad.set_snapshot_path(path);
+        }
+        if !self.seeds.is_empty() {
+            kad.bootstrap(&self.seeds);
+        }
+
+        let mut transport = Transport::new(addr, incoming_ack_rx, to_transport_rx);
+        transport.set_event_bus(events.clone());
+        let mut message_handler = MessageHandler::new(
+            to_transport_tx.clone(),
+            incoming_ack_tx,
+            HashMap::new(),
+            to_kad_tx.clone(),
+            to_gossip_tx,
+        );
+        let mut gossip = GossipService::new(
+            addr,
+            to_gossip_rx,
+            to_transport_tx,
+            to_app_tx,
+            kad,
+            self.gossip_config,
+            Instant::now(),
+            Instant::now(),
+        );
+        gossip.set_identity(identity);
+
+        let service = Service {
+            gossip,
+            commands: command_rx,
+            events: events.subscribe(),
+            gets: HashMap::new(),
+        };
+
+        // The transport flushes its outbox once it stops sending
+        let transport_sock = sock.try_clone()?;
+        let sending = Arc::new(AtomicBool::new(true));
+        let transport_sending = sending.clone();
+        let transport_thread = thread::spawn(move || {
+            while transport_sending.load(Ordering::Relaxed) {
+                transport.incoming_ack();
+                transport.outgoing_msg(&transport_sock);
+                transport.check_time_elapsed(&transport_sock);
+            }
+            transport.flush(&transport_sock, Duration::from_nanos(FLUSH_TIMEOUT));
+        });
+
+        let receiving = Arc::new(AtomicBool::new(true));
+        let handler_receiving = receiving.clone();
+        let handler_thread = thread::spawn(move || {
+            let mut buf = [0u8; 65536];
+            while handler_receiving.load(Ordering::Relaxed) {
+                message_handler.recv_msg(&sock, &mut buf, addr);
+            }
+        });
+
+        let service_thread = thread::spawn(move || service.run());
+
+        Ok(NodeHandle {
+            info,
+            to_kad_tx,
+            commands: command_tx,
+            messages: to_app_rx,
+            events,
+            sending,
+            receiving,
+            service: service_thread,
+            transport: transport_thread,
+            handler: handler_thread,
+        })
+    }
+}
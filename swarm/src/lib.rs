@@ -0,0 +1,124 @@
+pub mod builder;
+pub mod node;
+
+const PING_INTERVAL: u64 = 20_000_000_000;
+const SOCKET_READ_TIMEOUT: u64 = 100_000_000;
+const FLUSH_TIMEOUT: u64 = 2_000_000_000;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::builder::NodeBuilder;
+    use crate::node::NodeHandle;
+    use udp2p_node::peer_key::Key;
+    use udp2p_protocol::event::Event;
+    use udp2p_record::memory::MemoryStoreConfig;
+    use udp2p_record::record::DhtRecord;
+    use udp2p_record::store::StoreConfig;
+    use std::net::{SocketAddr, UdpSocket};
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:0".parse().expect("Unable to parse address")
+    }
+
+    fn get_eventually(node: &NodeHandle, key: Key) -> DhtRecord {
+        let start = Instant::now();
+        loop {
+            if let Some(record) = node.get(key) {
+                return record;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "Record was never found");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn nodes_gossip_and_share_records() {
+        let seed = NodeBuilder::new(local()).build().unwrap();
+        let node = NodeBuilder::new(local()).seeds(&[seed.address()]).build().unwrap();
+
+        let key = Key::rand();
+        seed.put(key, b"hello".to_vec()).unwrap();
+        assert_eq!(get_eventually(&node, key).value, b"hello".to_vec());
+
+        // The seed only sends to the node once it has heard about its subscription
+        node.subscribe("news");
+        let start = Instant::now();
+        let message = loop {
+            seed.publish("sport", b"ignored".to_vec()).unwrap();
+            seed.publish("news", b"gossip".to_vec()).unwrap();
+            if let Ok(message) = node.messages().recv_timeout(Duration::from_millis(500)) {
+                break message;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "Message was never delivered");
+        };
+        assert_eq!(message.message.topic, "news");
+        assert_eq!(message.message.data, b"gossip".to_vec());
+        assert_eq!(message.message.sender, seed.address());
+        assert_eq!(message.origin, seed.info().id);
+        assert_eq!(message.relayed_by, seed.address());
+        assert_eq!(message.hops, 1);
+
+        // Leaving removes the node from the seed's routing table straight away
+        let events = seed.events();
+        let address = node.address();
+        node.shutdown();
+        let start = Instant::now();
+        let left = std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(1)).ok())
+            .take_while(|_| start.elapsed() < Duration::from_secs(10))
+            .any(|event| matches!(event, Event::PeerEvicted(peer) if peer.address == address));
+        assert!(left);
+        seed.shutdown();
+    }
+
+    #[test]
+    fn nodes_restart_after_shutdown() {
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("udp2p-swarm-{}.log", rand::random::<u64>()));
+        let snapshot = dir.join(format!("udp2p-swarm-{}.json", rand::random::<u64>()));
+        let store = StoreConfig::File(log.clone(), MemoryStoreConfig::default());
+        let identity = SigningKey::generate(&mut OsRng);
+
+        let seed = NodeBuilder::new(local()).build().unwrap();
+        let joined = Key::rand();
+        seed.put(joined, b"joined".to_vec()).unwrap();
+        let node = NodeBuilder::new(local())
+            .identity(identity.clone())
+            .seeds(&[seed.address()])
+            .store(store.clone())
+            .snapshot_path(snapshot.clone())
+            .build()
+            .unwrap();
+        let address = node.address();
+        get_eventually(&node, joined);
+        let record = Key::rand();
+        node.put(record, b"persisted".to_vec()).unwrap();
+        node.shutdown();
+        assert!(snapshot.exists());
+        drop(UdpSocket::bind(address).expect("The socket was never released"));
+
+        // The record store is reloaded on restart. The node comes back on a new
+        // address the seed has never seen, so only the node can reconnect.
+        let shared = Key::rand();
+        seed.put(shared, b"shared".to_vec()).unwrap();
+        let node = NodeBuilder::new(local())
+            .identity(identity)
+            .store(store)
+            .snapshot_path(snapshot.clone())
+            .build()
+            .unwrap();
+        assert_eq!(node.get(record).unwrap().value, b"persisted".to_vec());
+
+        // The node rejoins through the peers in its snapshot, without a seed
+        assert_eq!(get_eventually(&node, shared).value, b"shared".to_vec());
+        node.shutdown();
+        seed.shutdown();
+
+        std::fs::remove_file(&log).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use udp2p_gossip::gossip::GossipService;
+use udp2p_gossip::protocol::{DeliveredMessage, Topic};
+use udp2p_gossip::validator::{MessageValidator, Validation};
+use udp2p_node::peer_info::PeerInfo;
+use udp2p_node::peer_key::Key;
+use udp2p_protocol::event::{Event, EventBus};
+use udp2p_protocol::protocol::{KadMessage, MessageData, MessageKey, Value};
+use udp2p_record::record::DhtRecord;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use log::info;
+
+/// A request from a node handle to the service loop, which owns the
+/// kademlia instance. Requests that return a result carry a sender
+/// for the service loop to reply on.
+pub(crate) enum Command {
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    Publish(Topic, MessageData, Sender<MessageKey>),
+    AddValidator(Topic, Box<dyn MessageValidator>),
+    AddAsyncValidator(Topic),
+    ReportValidation(MessageKey, Validation),
+    Bootstrap(Vec<SocketAddr>),
+    Put(Key, Value, Sender<Result<MessageKey, String>>),
+    Get(Key, Sender<Option<DhtRecord>>),
+}
+
+/// The service loop of a node. Contains the gossip service, and the kademlia
+/// instance it owns, a receiver for commands from the node's handle, a subscription
+/// to the node's events used to tell when value lookups complete, and the senders
+/// waiting on the result of each value lookup.
+pub(crate) struct Service {
+    pub(crate) gossip: GossipService,
+    pub(crate) commands: Receiver<Command>,
+    pub(crate) events: Receiver<Event>,
+    pub(crate) gets: HashMap<MessageKey, Sender<Option<DhtRecord>>>,
+}
+
+impl Service {
+    /// Runs the kademlia and gossip instances and handles commands until the
+    /// kademlia instance is killed. Gets still waiting on a lookup are dropped,
+    /// which answers them with None.
+    pub(crate) fn run(mut self) {
+        while !self.gossip.kad.is_stopped() {
+            self.gossip.kad.recv();
+            self.gossip.recv();
+            self.gossip.gossip();
+            self.handle_commands();
+            self.handle_events();
+        }
+    }
+
+    /// Handles every command waiting in the command receiver
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Subscribe(topic) => self.gossip.subscribe(&topic),
+                Command::Unsubscribe(topic) => self.gossip.unsubscribe(&topic),
+                Command::Publish(topic, data, reply) => {
+                    let id = self.gossip.publish(&topic, data);
+                    if reply.send(id).is_err() {
+                        info!("Error replying to publish to {:?}", topic);
+                    }
+                }
+                Command::AddValidator(topic, validator) => self.gossip.add_validator(&topic, validator),
+                Command::AddAsyncValidator(topic) => self.gossip.add_async_validator(&topic),
+                Command::ReportValidation(id, validation) => self.gossip.report_validation(id, validation),
+                Command::Bootstrap(seeds) => self.gossip.kad.bootstrap(&seeds),
+                Command::Put(key, value, reply) => {
+                    let res = self.gossip.kad.put_record(key, value).map_err(|e| e.to_string());
+                    if reply.send(res).is_err() {
+                        info!("Error replying to put of {:?}", key);
+                    }
+                }
+                Command::Get(key, reply) => {
+                    let id = self.gossip.kad.lookup_value(key);
+                    self.gets.insert(id, reply);
+                }
+            }
+        }
+    }
+
+    /// Replies to each get whose value lookup has completed with the best
+    /// version of the record found, which the lookup adds to the local store.
+    fn handle_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            if let Event::LookupCompleted(id, key, _) = event {
+                if let Some(reply) = self.gets.remove(&id) {
+                    if reply.send(self.gossip.kad.get_record(&key)).is_err() {
+                        info!("Error replying to get of {:?}", key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A handle to a running node, returned by a NodeBuilder. Contains the local
+/// peer's information, a sender to the kademlia instance used to kill it, a sender for commands to
+/// the service loop, a receiver for the gossip messages delivered to the
+/// application and the node's event bus. Lastly, the flags that keep the
+/// transport and message handler loops running, and the handles of the
+/// service, transport and message handler threads.
+pub struct NodeHandle {
+    pub(crate) info: PeerInfo,
+    pub(crate) to_kad_tx: Sender<(SocketAddr, KadMessage)>,
+    pub(crate) commands: Sender<Command>,
+    pub(crate) messages: Receiver<DeliveredMessage>,
+    pub(crate) events: EventBus,
+    pub(crate) sending: Arc<AtomicBool>,
+    pub(crate) receiving: Arc<AtomicBool>,
+    pub(crate) service: JoinHandle<()>,
+    pub(crate) transport: JoinHandle<()>,
+    pub(crate) handler: JoinHandle<()>,
+}
+
+impl NodeHandle {
+    /// Returns the local peer's information
+    pub fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
+    /// Returns the socket address the node is bound to
+    pub fn address(&self) -> SocketAddr {
+        self.info.address
+    }
+
+    /// Subscribes to a topic, so messages published to it are delivered to messages()
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to subscribe to
+    pub fn subscribe(&self, topic: &str) {
+        if self.commands.send(Command::Subscribe(topic.to_string())).is_err() {
+            info!("Error sending subscribe to the service loop");
+        }
+    }
+
+    /// Unsubscribes from a topic
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to unsubscribe from
+    pub fn unsubscribe(&self, topic: &str) {
+        if self.commands.send(Command::Unsubscribe(topic.to_string())).is_err() {
+            info!("Error sending unsubscribe to the service loop");
+        }
+    }
+
+    /// Gossips a message to the peers subscribed to a topic. Returns the id of
+    /// the message, or None if the node has shut down.
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to publish to
+    /// * data - the message to gossip
+    pub fn publish(&self, topic: &str, data: MessageData) -> Option<MessageKey> {
+        let (reply_tx, reply_rx) = channel();
+        self.commands.send(Command::Publish(topic.to_string(), data, reply_tx)).ok()?;
+        reply_rx.recv().ok()
+    }
+
+    /// Registers a validator that messages from other peers published to a topic
+    /// must be accepted by before they are delivered or forwarded
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to validate
+    /// * validator - the validator for messages on the topic
+    pub fn add_validator(&self, topic: &str, validator: impl MessageValidator + 'static) {
+        let command = Command::AddValidator(topic.to_string(), Box::new(validator));
+        if self.commands.send(command).is_err() {
+            info!("Error sending validator to the service loop");
+        }
+    }
+
+    /// Validates the messages published to a topic in the application. Each
+    /// message is delivered to messages() and is only forwarded once it is
+    /// accepted with report_validation.
+    ///
+    /// # Arguments
+    ///
+    /// * topic - the topic to validate
+    pub fn add_async_validator(&self, topic: &str) {
+        if self.commands.send(Command::AddAsyncValidator(topic.to_string())).is_err() {
+            info!("Error sending async validator to the service loop");
+        }
+    }
+
+    /// Reports the result of validating a message on an asynchronously validated topic
+    ///
+    /// # Arguments
+    ///
+    /// * id - the id of the message
+    /// * validation - the result of validating the message
+    pub fn report_validation(&self, id: MessageKey, validation: Validation) {
+        if self.commands.send(Command::ReportValidation(id, validation)).is_err() {
+            info!("Error sending validation report to the service loop");
+        }
+    }
+
+    /// Bootstraps the node from a list of seed nodes
+    ///
+    /// # Arguments
+    ///
+    /// * seeds - the socket addresses of the seed nodes
+    pub fn bootstrap(&self, seeds: &[SocketAddr]) {
+        if self.commands.send(Command::Bootstrap(seeds.to_vec())).is_err() {
+            info!("Error sending bootstrap to the service loop");
+        }
+    }
+
+    /// Stores a record in the DHT, returning the id of the lookup that sends it
+    /// to the closest peers to its key.
+    ///
+    /// # Arguments
+    ///
+    /// * key - the key to store the record under
+    /// * value - the value of the record
+    pub fn put(&self, key: Key, value: Value) -> Result<MessageKey, Box<dyn Error>> {
+        let (reply_tx, reply_rx) = channel();
+        self.commands.send(Command::Put(key, value, reply_tx))?;
+        Ok(reply_rx.recv()??)
+    }
+
+    /// Looks up the record stored under a key in the DHT, blocking until the
+    /// lookup completes. Returns the best version of the record found, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * key - the key of the record
+    pub fn get(&self, key: Key) -> Option<DhtRecord> {
+        let (reply_tx, reply_rx) = channel();
+        self.commands.send(Command::Get(key, reply_tx)).ok()?;
+        reply_rx.recv().ok()?
+    }
+
+    /// Returns the receiver that gossip messages from other peers are delivered to,
+    /// along with the peer that relayed each one and how it was validated
+    pub fn messages(&self) -> &Receiver<DeliveredMessage> {
+        &self.messages
+    }
+
+    /// Subscribes to the node's discovery and transport events
+    pub fn events(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Shuts the node down and waits for each of its threads to finish. The
+    /// kademlia instance is killed first, which stops the service loop and
+    /// persists the routing table. The transport then sends the messages already
+    /// queued and waits for them to be acknowledged, up to FLUSH_TIMEOUT, while
+    /// the message handler keeps receiving acknowledgements. Lastly the message
+    /// handler is stopped, which closes the socket.
+    pub fn shutdown(self) {
+        if self.to_kad_tx.send((self.address(), KadMessage::Kill)).is_err() {
+            info!("Error sending kill to kademlia");
+        }
+        join(self.service, "service");
+
+        self.sending.store(false, Ordering::Relaxed);
+        join(self.transport, "transport");
+
+        self.receiving.store(false, Ordering::Relaxed);
+        join(self.handler, "message handler");
+    }
+}
+
+/// Waits for one of a node's threads to finish
+///
+/// # Arguments
+///
+/// * thread - the handle of the thread
+/// * name - the name of the thread, used when logging a thread that panicked
+fn join(thread: JoinHandle<()>, name: &str) {
+    if thread.join().is_err() {
+        info!("The {} thread panicked", name);
+    }
+}
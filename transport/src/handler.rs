@@ -60,14 +60,11 @@ impl MessageHandler {
     /// * local - the local socket address.
     pub fn recv_msg(&mut self, sock: &UdpSocket, buf: &mut [u8], local: SocketAddr) {
         let res = sock.recv_from(buf);
-        match res {
-            Ok((amt, src)) => {
-                info!("Received {:?} bytes from {:?}", amt, src);
-                if let Some(packet) = self.process_packet(local, buf.to_vec(), amt, src) {
-                    self.insert_packet(packet, src)
-                }
+        if let Ok((amt, src)) = res {
+            info!("Received {:?} bytes from {:?}", amt, src);
+            if let Some(packet) = self.process_packet(local, buf.to_vec(), amt, src) {
+                self.insert_packet(packet, src)
             }
-            Err(_) => {}
         }
     }
 
@@ -94,7 +91,7 @@ impl MessageHandler {
                     msg: ack.as_bytes().unwrap()
                 };
 
-                if let Err(_) = self.om_tx.clone().send((src, message)) {
+                if self.om_tx.clone().send((src, message)).is_err() {
                     println!("Error sending ack message to transport thread");
                 }
             }
@@ -144,15 +141,15 @@ impl MessageHandler {
     fn assemble_packets(&self, packet: Packet, map: HashMap<usize, Packet>) -> Option<Message> {
         let mut bytes = vec![];
         (1..=packet.total_n)
-            .into_iter()
             .for_each(|n| {
-                let converted = hex::decode(&map[&n].bytes.clone()).unwrap();
+                let converted = hex::decode(map[&n].bytes.clone()).unwrap();
                 bytes.extend(converted)
             });
         Message::from_bytes(&bytes)
     }
     
-    /// Handles and routes a message to the proper component
+    /// Handles and routes a message to the proper component. Kill messages
+    /// can only come from the local node, so any received from a peer are dropped.
     /// 
     /// # Arguments
     /// 
@@ -163,20 +160,24 @@ impl MessageHandler {
         match message.head {
             Header::Request | Header::Response => {
                 if let Some(msg) = KadMessage::from_bytes(&message.msg) {
-                    if let Err(_) = self.kad_tx.send((src, msg)) {
+                    if let KadMessage::Kill = msg {
+                        info!("Dropped kill message from {:?}", src);
+                        return;
+                    }
+                    if self.kad_tx.send((src, msg)).is_err() {
                         println!("Error sending to kad");
                     }
                 }
             }
             Header::Ack => {
                 if let Some(ack) = AckMessage::from_bytes(&message.msg) {
-                    if let Err(_) = self.ia_tx.send(ack) {
+                    if self.ia_tx.send(ack).is_err() {
                         println!("Error sending ack message")
                     }
                 }                
             }
             Header::Gossip => {
-                if let Err(_) = self.gossip_tx.send((src, message)) {
+                if self.gossip_tx.send((src, message)).is_err() {
                     println!("Error sending to gossip");
                 }
             }
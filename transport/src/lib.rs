@@ -1,11 +1,85 @@
 pub mod transport;
 pub mod handler;
 
+const FLUSH_POLL_INTERVAL: u64 = 10_000_000;
+
 #[cfg(test)]
 mod tests {
+    use crate::transport::Transport;
+    use udp2p_gd_udp::gd_udp::GDUdp;
+    use udp2p_protocol::event::{Event, EventBus};
+    use udp2p_protocol::protocol::{AckMessage, Header, Message, Packet};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use udp2p_utils::utils::ByteRep;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    fn socket() -> UdpSocket {
+        let sock = UdpSocket::bind("127.0.0.1:0").expect("Unable to bind socket");
+        sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        sock
+    }
+
+    fn recv_packet(sock: &UdpSocket) -> Option<Packet> {
+        let mut buf = [0u8; 65_536];
+        let (amt, _) = sock.recv_from(&mut buf).ok()?;
+        Packet::from_bytes(&buf[..amt])
+    }
+
+    #[test]
+    fn transport_reports_deliveries_and_failures() {
+        let sock = socket();
+        let acking = socket();
+        let silent = socket();
+        let (ia_tx, ia_rx) = channel();
+        let (om_tx, om_rx) = channel();
+        let mut transport = Transport::new(sock.local_addr().unwrap(), ia_rx, om_rx);
+        let events = EventBus::new();
+        let delivered = events.subscribe();
+        transport.set_event_bus(events);
+
+        let message = Message {
+            head: Header::Gossip,
+            msg: b"hello".to_vec(),
+        };
+        let peers: Vec<SocketAddr> = vec![acking.local_addr().unwrap(), silent.local_addr().unwrap()];
+        peers.iter().for_each(|peer| {
+            om_tx.send((*peer, message.clone())).unwrap();
+            transport.outgoing_msg(&sock);
+        });
+
+        // The acknowledged message is delivered at the next maintenance
+        let packet = recv_packet(&acking).unwrap();
+        assert_eq!(packet.ret, GDUdp::RETURN_RECEIPT);
+        ia_tx
+            .send(AckMessage {
+                packet_id: packet.id,
+                packet_number: packet.n,
+                src: peers[0].to_string().as_bytes().to_vec(),
+            })
+            .unwrap();
+        transport.incoming_ack();
+        std::thread::sleep(GDUdp::MAINTENANCE);
+        transport.check_time_elapsed(&sock);
+        assert!(matches!(delivered.try_recv(), Ok(Event::MessageDelivered(peer)) if peer == peers[0]));
+        assert!(delivered.try_recv().is_err());
+
+        // The unacknowledged message is resent until it runs out of attempts
+        let mut attempts = 0;
+        while recv_packet(&silent).is_some() {
+            attempts += 1;
+            std::thread::sleep(GDUdp::MAINTENANCE);
+            transport.check_time_elapsed(&sock);
+        }
+        assert_eq!(attempts, 5);
+        assert!(matches!(delivered.try_recv(), Ok(Event::DeliveryFailed(peer)) if peer == peers[1]));
+        assert!(delivered.try_recv().is_err());
+        assert!(recv_packet(&acking).is_none());
+    }
 }
@@ -1,18 +1,23 @@
+use crate::FLUSH_POLL_INTERVAL;
 use udp2p_gd_udp::gd_udp::GDUdp;
+use udp2p_protocol::event::EventBus;
 use udp2p_protocol::protocol::{packetize, AckMessage, Header, Message, MessageKey};
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 use udp2p_utils::utils::ByteRep;
 
 /// A struct for managing the transport layer in a p2p network
 /// contains a GDUdp struct for sending reliable messages over UDP
 /// an incoming acknowledgement receiver to receiving return receipts from peers
-/// and an outgoing message receive to get messages to send from other threads
+/// and an outgoing message receive to get messages to send from other threads.
+/// Once the transport is closed only acknowledgements are sent.
 #[derive(Debug)]
 pub struct Transport {
     gd_udp: GDUdp,
     ia_rx: Receiver<AckMessage>,
     om_rx: Receiver<(SocketAddr, Message)>,
+    closed: bool,
 }
 
 impl Transport {
@@ -33,24 +38,40 @@ impl Transport {
             gd_udp: GDUdp::new(addr),
             ia_rx,
             om_rx,
+            closed: false,
         }
     }
 
+    /// Sets the event bus that message deliveries and delivery failures are published on
+    /// 
+    /// # Arguments
+    /// 
+    /// * events - the event bus to publish to
+    pub fn set_event_bus(&mut self, events: EventBus) {
+        self.gd_udp.events = events;
+    }
+
     /// Handles incomingi acknowledgements
     pub fn incoming_ack(&mut self) {
         let res = self.ia_rx.try_recv();
-        match res {
-            Ok(ack) => {
-                let exists = self.gd_udp.outbox.contains_key(&ack.packet_id);
-                if exists {
-                    self.gd_udp
-                        .process_ack(ack.packet_id, ack.packet_number, ack.src);
-                };
-            }
-            Err(_) => {}
+        if let Ok(ack) = res {
+            self.process_ack(ack);
         }
     }
 
+    /// Marks the packet an acknowledgement is for as received, if it is still in the outbox
+    ///
+    /// # Arguments
+    ///
+    /// * ack - the acknowledgement
+    fn process_ack(&mut self, ack: AckMessage) {
+        let exists = self.gd_udp.outbox.contains_key(&ack.packet_id);
+        if exists {
+            self.gd_udp
+                .process_ack(ack.packet_id, ack.packet_number, ack.src);
+        };
+    }
+
     /// Handles and sends outgoing messages
     /// 
     /// # Arguments
@@ -59,24 +80,70 @@ impl Transport {
     /// 
     pub fn outgoing_msg(&mut self, sock: &UdpSocket) {
         let res = self.om_rx.try_recv();
-        match res {
-            Ok((src, msg)) => match msg.head {
-                Header::Ack => {
-                    let packets_id = MessageKey::rand().inner();
-                    let packets = packetize(msg.as_bytes().unwrap().clone(), packets_id, 0u8);
-                    packets.iter().for_each(|packet| {
-                        if let Err(_) = sock.send_to(&packet.as_bytes().unwrap(), src) {}
-                    });
-                }
-                _ => {
-                    let packets_id = MessageKey::rand().inner();
-                    let packets = packetize(msg.as_bytes().unwrap().clone(), packets_id, 1u8);
-                    packets.iter().for_each(|packet| {
-                        self.gd_udp.send_reliable(&src, packet, &sock);
-                    });
-                }
-            },
-            Err(_) => {}
+        if let Ok((src, msg)) = res {
+            self.send_msg(src, msg, sock);
+        }
+    }
+
+    /// Packetizes and sends a message, acknowledgements are sent once and every
+    /// other message is sent reliably. Only acknowledgements are sent once the
+    /// transport is closed.
+    /// 
+    /// # Arguments
+    /// 
+    /// * src - the destination of the message
+    /// * msg - the message to send
+    /// * sock - The UDP socket for the message to be sent out on.
+    fn send_msg(&mut self, src: SocketAddr, msg: Message, sock: &UdpSocket) {
+        if self.closed && !matches!(msg.head, Header::Ack) {
+            return;
+        }
+
+        match msg.head {
+            Header::Ack => {
+                let packets_id = MessageKey::rand().inner();
+                let packets = packetize(msg.as_bytes().unwrap().clone(), packets_id, 0u8);
+                packets.iter().for_each(|packet| {
+                    if sock.send_to(&packet.as_bytes().unwrap(), src).is_err() {}
+                });
+            }
+            _ => {
+                let packets_id = MessageKey::rand().inner();
+                let packets = packetize(msg.as_bytes().unwrap().clone(), packets_id, 1u8);
+                packets.iter().for_each(|packet| {
+                    self.gd_udp.send_reliable(&src, packet, sock);
+                });
+            }
+        }
+    }
+
+    /// Sends every message already waiting to be sent, then closes the transport
+    /// and resends unacknowledged packets until they are all acknowledged, run
+    /// out of attempts, or the timeout passes. Rather than spinning, each pass
+    /// blocks for a short while waiting on the next acknowledgement.
+    /// 
+    /// # Arguments
+    /// 
+    /// * sock - The UDP socket for messages to be sent out on.
+    /// * timeout - the longest to wait for the outbox to empty
+    pub fn flush(&mut self, sock: &UdpSocket, timeout: Duration) {
+        while let Ok((src, msg)) = self.om_rx.try_recv() {
+            self.send_msg(src, msg, sock);
+        }
+        self.closed = true;
+
+        let start = Instant::now();
+        while !self.gd_udp.outbox.is_empty() && start.elapsed() < timeout {
+            let wait = timeout
+                .saturating_sub(start.elapsed())
+                .min(Duration::from_nanos(FLUSH_POLL_INTERVAL));
+            if let Ok(ack) = self.ia_rx.recv_timeout(wait) {
+                self.process_ack(ack);
+            }
+            while let Ok((src, msg)) = self.om_rx.try_recv() {
+                self.send_msg(src, msg, sock);
+            }
+            self.check_time_elapsed(sock);
         }
     }
 